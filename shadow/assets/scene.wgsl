@@ -1,47 +1,551 @@
 struct Camera {
     view: mat4x4f,
     proj: mat4x4f,
+    inv_view_proj: mat4x4f,
+    // Unjittered, of the previous frame.
+    prev_view_proj: mat4x4f,
+    jitter: vec2f,
 }
 
 struct DirectionalLight {
     pos: vec3f,
+    first_cascade: u32,
     dir: vec3f,
+    cascade_count: u32,
     col: vec3f,
+    filter_mode: u32,
+    filter_samples: u32,
+    filter_param: f32,
+    depth_bias: f32,
+    normal_bias: f32,
+}
+
+struct PointLight {
+    pos: vec3f,
+    radius: f32,
+    col: vec3f,
+}
+
+struct Material {
+    base_color: vec4f,
+    metallic: f32,
+    roughness: f32,
+    // Zero unless the material is masked.
+    alpha_cutoff: f32,
+    emissive: vec3f,
+}
+
+struct Environment {
+    intensity: f32,
+    // Mip of the prefiltered radiance for a roughness of 1.
+    max_level: f32,
+    enabled: u32,
+}
+
+struct Atmosphere {
+    // Perez coefficients A to E of the luminance and both chromaticities.
+    perez: array<vec4f, 5>,
+    // Luminance and chromaticity of the zenith.
+    zenith: vec3f,
+    sky_enabled: u32,
+    to_sun: vec3f,
+    sky_intensity: f32,
+    fog_color: vec3f,
+    fog_density: f32,
+    fog_height_falloff: f32,
+    fog_base_height: f32,
+    fog_enabled: u32,
+}
+
+struct ShadowCascade {
+    view_proj: mat4x4f,
+    split_far: f32,
+    texel_size: f32,
+    depth_range: f32,
+}
+
+// A position in the shadow map, `depth` being the depth of the receiver.
+struct ShadowCoords {
+    uv: vec2f,
+    depth: f32,
+    layer: u32,
 }
 
 struct VertexInput {
     @location(0) position: vec3f,
     @location(1) normal: vec3f,
+    @location(2) joints: vec4u,
+    @location(3) weights: vec4f,
+    @location(4) uv: vec2f,
 }
 
 struct VertexOutput {
     @builtin(position) position_cs: vec4f,
     @location(0) position_ws: vec3f,
     @location(1) normal_ws: vec3f,
+    @location(2) uv: vec2f,
+}
+
+struct GBufferOutput {
+    @location(0) albedo: vec4f,
+    @location(1) normal: vec4f,
+    @location(2) material: vec4f,
+    @location(3) emissive: vec4f,
+}
+
+// With TAA, the scene pass also writes motion vectors.
+struct TaaOutput {
+    @location(0) color: vec4f,
+    @location(1) motion: vec4f,
+}
+
+// Accumulated by the weighted blended transparency, the revealage is multiplied.
+struct OitOutput {
+    @location(0) accum: vec4f,
+    @location(1) revealage: f32,
 }
 
 @group(0) @binding(0) var<uniform> camera: Camera;
 @group(0) @binding(1) var<storage> dir_lights: array<DirectionalLight>;
+@group(0) @binding(2) var<storage> shadow_cascades: array<ShadowCascade>;
+@group(0) @binding(3) var shadow_maps: texture_depth_2d_array;
+@group(0) @binding(4) var shadow_sampler: sampler_comparison;
+@group(0) @binding(5) var shadow_moments: texture_2d_array<f32>;
+// White when ambient occlusion is disabled.
+@group(0) @binding(6) var ambient_occlusion: texture_2d<f32>;
+// Holds a single light with a radius of zero when the scene has none.
+@group(0) @binding(8) var<storage> point_lights: array<PointLight>;
+// Black with `enabled` unset when there's no environment, the constant ambient light is
+// used instead.
+@group(0) @binding(9) var<uniform> environment: Environment;
+@group(0) @binding(10) var irradiance_map: texture_cube<f32>;
+@group(0) @binding(11) var prefiltered_map: texture_cube<f32>;
+@group(0) @binding(12) var brdf_lut: texture_2d<f32>;
+@group(0) @binding(13) var environment_sampler: sampler;
+@group(0) @binding(15) var<uniform> atmosphere: Atmosphere;
+
+// Set by the renderer for the filters used by any light, the others are compiled out.
+override SHADOW_FILTER_POISSON: bool = false;
+override SHADOW_FILTER_PCSS: bool = false;
+override SHADOW_FILTER_VARIANCE: bool = false;
+
+const FILTER_POISSON: u32 = 1u;
+const FILTER_PCSS: u32 = 2u;
+const FILTER_VARIANCE: u32 = 3u;
+
+const AMBIENT: f32 = 0.1;
+// Reflectance of dielectrics at normal incidence.
+const DIELECTRIC_F0: f32 = 0.04;
+// Fraction of a cascade over which it fades into the next one.
+const CASCADE_BLEND: f32 = 0.1;
+// Bounds the blocker search and penumbra of PCSS, in texels.
+const PCSS_MAX_RADIUS: f32 = 24.;
+// Keeps the variance of flat receivers from causing acne.
+const MIN_VARIANCE: f32 = 0.00001;
+
+var<private> POISSON_DISK: array<vec2f, 16> = array(
+    vec2f(-0.94201624, -0.39906216),
+    vec2f(0.94558609, -0.76890725),
+    vec2f(-0.09418410, -0.92938870),
+    vec2f(0.34495938, 0.29387760),
+    vec2f(-0.91588581, 0.45771432),
+    vec2f(-0.81544232, -0.87912464),
+    vec2f(-0.38277543, 0.27676845),
+    vec2f(0.97484398, 0.75648379),
+    vec2f(0.44323325, -0.97511554),
+    vec2f(0.53742981, -0.47373420),
+    vec2f(-0.26496911, -0.41893023),
+    vec2f(0.79197514, 0.19090188),
+    vec2f(-0.24188840, 0.99706507),
+    vec2f(-0.81409955, 0.91437590),
+    vec2f(0.19984126, 0.78641367),
+    vec2f(0.14383161, -0.14100790),
+);
+
+// White where the material has no texture.
+@group(2) @binding(0) var base_color_map: texture_2d<f32>;
+@group(2) @binding(1) var metallic_roughness_map: texture_2d<f32>;
+@group(2) @binding(2) var emissive_map: texture_2d<f32>;
+@group(2) @binding(3) var material_sampler: sampler;
+@group(2) @binding(4) var<uniform> material_params: Material;
+
+@group(3) @binding(0) var gbuffer_albedo: texture_2d<f32>;
+@group(3) @binding(1) var gbuffer_normal: texture_2d<f32>;
+@group(3) @binding(2) var gbuffer_material: texture_2d<f32>;
+@group(3) @binding(3) var gbuffer_depth: texture_depth_2d;
+@group(3) @binding(4) var gbuffer_emissive: texture_2d<f32>;
 
 @vertex
 fn vertex(input: VertexInput) -> VertexOutput {
+    let skin = skin_matrix(input.joints, input.weights);
+    let position_ws = (skin * vec4f(input.position, 1.)).xyz;
+
     var output: VertexOutput;
-    output.position_ws = input.position;
-    output.position_cs = camera.proj * camera.view * vec4f(input.position, 1.);
-    output.normal_ws = input.normal;
+    output.position_ws = position_ws;
+    output.position_cs = camera.proj * camera.view * vec4f(position_ws, 1.);
+    output.normal_ws = normalize((skin * vec4f(input.normal, 0.)).xyz);
+    output.uv = input.uv;
     return output;
 }
 
+// A triangle covering the whole target.
+@vertex
+fn vertex_fullscreen(@builtin(vertex_index) vertex_index: u32) -> @builtin(position) vec4f {
+    let uv = vec2f(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    return vec4f(uv * 2. - 1., 0., 1.);
+}
+
+// Rotates the Poisson disk differently for every pixel, trading banding for noise.
+fn disk_rotation(pixel: vec2f) -> mat2x2f {
+    let noise = fract(52.9829189 * fract(dot(pixel, vec2f(0.06711056, 0.00583715))));
+    let angle = 6.2831853 * noise;
+    return mat2x2f(cos(angle), sin(angle), -sin(angle), cos(angle));
+}
+
+fn filter_poisson(coords: ShadowCoords, samples: u32, radius: f32, rotation: mat2x2f) -> f32 {
+    let texel_uv = 1. / f32(textureDimensions(shadow_maps).x);
+    var visibility = 0.;
+    for (var i = 0u; i < samples; i += 1u) {
+        let uv = coords.uv + rotation * POISSON_DISK[i] * radius * texel_uv;
+        visibility += textureSampleCompareLevel(shadow_maps, shadow_sampler, uv, coords.layer, coords.depth);
+    }
+    return visibility / f32(samples);
+}
+
+// Estimates the penumbra from the average depth of the blockers around the receiver,
+// light_size being the tangent of the angular diameter of the light.
+fn filter_pcss(
+    coords: ShadowCoords,
+    cascade: ShadowCascade,
+    samples: u32,
+    light_size: f32,
+    rotation: mat2x2f,
+) -> f32 {
+    let size = vec2i(textureDimensions(shadow_maps));
+    let receiver_distance = coords.depth * cascade.depth_range;
+    let search_radius = min(light_size * receiver_distance / cascade.texel_size, PCSS_MAX_RADIUS);
+
+    var blocker_depth = 0.;
+    var blockers = 0u;
+    for (var i = 0u; i < samples; i += 1u) {
+        let uv = coords.uv + rotation * POISSON_DISK[i] * search_radius / f32(size.x);
+        let texel = clamp(vec2i(uv * vec2f(size)), vec2i(0), size - 1);
+        let depth = textureLoad(shadow_maps, texel, coords.layer, 0);
+        if depth < coords.depth {
+            blocker_depth += depth;
+            blockers += 1u;
+        }
+    }
+    if blockers == 0u {
+        return 1.;
+    }
+
+    let blocker_distance = (coords.depth - blocker_depth / f32(blockers)) * cascade.depth_range;
+    let penumbra = clamp(light_size * blocker_distance / cascade.texel_size, 1., PCSS_MAX_RADIUS);
+    return filter_poisson(coords, samples, penumbra, rotation);
+}
+
+fn load_moments(texel: vec2i, layer: u32) -> vec2f {
+    let size = vec2i(textureDimensions(shadow_moments));
+    return textureLoad(shadow_moments, clamp(texel, vec2i(0), size - 1), layer, 0).xy;
+}
+
+// Chebyshev's upper bound on the fraction of the blurred depths behind the receiver.
+fn filter_variance(coords: ShadowCoords, light_bleeding: f32) -> f32 {
+    // Filtered by hand, as 32 bit float textures aren't filterable everywhere.
+    let texel = coords.uv * vec2f(textureDimensions(shadow_moments)) - 0.5;
+    let base = vec2i(floor(texel));
+    let t = fract(texel);
+    let moments = mix(
+        mix(load_moments(base, coords.layer), load_moments(base + vec2i(1, 0), coords.layer), t.x),
+        mix(load_moments(base + vec2i(0, 1), coords.layer), load_moments(base + vec2i(1, 1), coords.layer), t.x),
+        t.y,
+    );
+
+    if coords.depth <= moments.x {
+        return 1.;
+    }
+    let variance = max(moments.y - moments.x * moments.x, MIN_VARIANCE);
+    let distance = coords.depth - moments.x;
+    let visibility = variance / (variance + distance * distance);
+    return saturate((visibility - light_bleeding) / (1. - light_bleeding));
+}
+
+fn sample_cascade(
+    light: DirectionalLight,
+    layer: u32,
+    position_ws: vec3f,
+    normal_ws: vec3f,
+    rotation: mat2x2f,
+) -> f32 {
+    let cascade = shadow_cascades[layer];
+    let offset = normal_ws * light.normal_bias * cascade.texel_size - light.dir * light.depth_bias;
+    let position_ls = cascade.view_proj * vec4f(position_ws + offset, 1.);
+    let ndc = position_ls.xyz / position_ls.w;
+    let uv = ndc.xy * vec2f(0.5, -0.5) + 0.5;
+    if any(uv < vec2f(0.)) || any(uv > vec2f(1.)) || ndc.z > 1. {
+        return 1.;
+    }
+
+    let coords = ShadowCoords(uv, ndc.z, layer);
+    if SHADOW_FILTER_POISSON && light.filter_mode == FILTER_POISSON {
+        return filter_poisson(coords, light.filter_samples, light.filter_param, rotation);
+    }
+    if SHADOW_FILTER_PCSS && light.filter_mode == FILTER_PCSS {
+        return filter_pcss(coords, cascade, light.filter_samples, light.filter_param, rotation);
+    }
+    if SHADOW_FILTER_VARIANCE && light.filter_mode == FILTER_VARIANCE {
+        return filter_variance(coords, light.filter_param);
+    }
+    return textureSampleCompareLevel(shadow_maps, shadow_sampler, uv, layer, ndc.z);
+}
+
+// Picks the first cascade reaching past `depth` and blends into the next one near its
+// far end, so the seam between resolutions isn't visible.
+fn shadow(
+    light: DirectionalLight,
+    position_ws: vec3f,
+    normal_ws: vec3f,
+    depth: f32,
+    rotation: mat2x2f,
+) -> f32 {
+    for (var i = 0u; i < light.cascade_count; i += 1u) {
+        let layer = light.first_cascade + i;
+        let split_far = shadow_cascades[layer].split_far;
+        if depth > split_far {
+            continue;
+        }
+
+        let visibility = sample_cascade(light, layer, position_ws, normal_ws, rotation);
+        let split_near = select(0., shadow_cascades[max(layer, 1u) - 1u].split_far, i > 0u);
+        let blend = (depth - split_far) / ((split_far - split_near) * CASCADE_BLEND) + 1.;
+        if blend > 0. && i + 1u < light.cascade_count {
+            let next = sample_cascade(light, layer + 1u, position_ws, normal_ws, rotation);
+            return mix(visibility, next, blend);
+        }
+        return visibility;
+    }
+    return 1.;
+}
+
+// Lambert diffuse for a light arriving from `to_light`.
+fn diffuse(albedo: vec3f, normal: vec3f, to_light: vec3f) -> vec3f {
+    return albedo * max(dot(normal, to_light), 0.);
+}
+
+fn camera_position() -> vec3f {
+    let view_inverse = transpose(mat3x3f(camera.view[0].xyz, camera.view[1].xyz, camera.view[2].xyz));
+    return -(view_inverse * camera.view[3].xyz);
+}
+
+// Exponential height fog, integrating the density along the view ray in closed form.
+fn apply_fog(color: vec3f, position_ws: vec3f) -> vec3f {
+    if atmosphere.fog_enabled == 0u {
+        return color;
+    }
+
+    let eye = camera_position();
+    let ray = position_ws - eye;
+    let distance = length(ray);
+    let falloff = atmosphere.fog_height_falloff;
+    let eye_density = atmosphere.fog_density * exp(-falloff * (eye.y - atmosphere.fog_base_height));
+    let height_change = falloff * ray.y;
+    var optical_depth = eye_density * distance;
+    if abs(height_change) > 0.0001 {
+        optical_depth *= (1. - exp(-height_change)) / height_change;
+    }
+    return mix(color, atmosphere.fog_color, 1. - exp(-optical_depth));
+}
+
+// Diffuse irradiance and prefiltered specular radiance of the environment, split sum
+// approximated with the BRDF lookup table.
+fn environment_light(
+    albedo: vec3f,
+    metallic: f32,
+    roughness: f32,
+    normal: vec3f,
+    to_view: vec3f,
+) -> vec3f {
+    let n_dot_v = max(dot(normal, to_view), 0.0001);
+    let f0 = mix(vec3f(DIELECTRIC_F0), albedo, metallic);
+    let fresnel = f0 + (max(vec3f(1. - roughness), f0) - f0) * pow(1. - n_dot_v, 5.);
+
+    let irradiance = textureSampleLevel(irradiance_map, environment_sampler, normal, 0.).rgb;
+    let diffuse = (1. - fresnel) * (1. - metallic) * albedo * irradiance;
+    let level = roughness * environment.max_level;
+    let reflected = reflect(-to_view, normal);
+    let radiance = textureSampleLevel(prefiltered_map, environment_sampler, reflected, level).rgb;
+    let scale_bias = textureSampleLevel(brdf_lut, environment_sampler, vec2f(n_dot_v, roughness), 0.).rg;
+    let specular = radiance * (f0 * scale_bias.x + scale_bias.y);
+    return (diffuse + specular) * environment.intensity;
+}
+
+// Lighting shared by the forward and deferred paths, `pixel` being the framebuffer
+// position of the fragment.
+fn shade(
+    albedo: vec3f,
+    metallic: f32,
+    roughness: f32,
+    position_ws: vec3f,
+    normal_ws: vec3f,
+    pixel: vec2f,
+) -> vec3f {
+    let to_view = normalize(camera_position() - position_ws);
+    let depth = -(camera.view * vec4f(position_ws, 1.)).z;
+    let rotation = disk_rotation(pixel);
+
+    let occlusion_size = vec2i(textureDimensions(ambient_occlusion));
+    let occlusion_texel = min(vec2i(pixel), occlusion_size - 1);
+    let occlusion = textureLoad(ambient_occlusion, occlusion_texel, 0).r;
+
+    var ambient = AMBIENT * albedo;
+    if environment.enabled != 0u {
+        ambient = environment_light(albedo, metallic, roughness, normal_ws, to_view);
+    }
+    var color = ambient * occlusion;
+    for (var i_light = 0u; i_light < arrayLength(&dir_lights); i_light += 1u) {
+        let light = dir_lights[i_light];
+        let visibility = shadow(light, position_ws, normal_ws, depth, rotation);
+        color += diffuse(albedo, normal_ws, -light.dir) * visibility * light.col;
+    }
+    for (var i_light = 0u; i_light < arrayLength(&point_lights); i_light += 1u) {
+        let light = point_lights[i_light];
+        let offset = light.pos - position_ws;
+        let distance = length(offset);
+        if light.radius <= 0. || distance >= light.radius {
+            continue;
+        }
+
+        // Inverse square falloff, windowed to reach zero at the radius.
+        let window = saturate(1. - pow(distance / light.radius, 4.));
+        let attenuation = window * window / max(distance * distance, 0.0001);
+        color += diffuse(albedo, normal_ws, offset / distance) * attenuation * light.col;
+    }
+    return color;
+}
+
+// Offset from the previous frame to this one in UV units, with alpha set as the
+// background has no motion vectors.
+fn motion_vector(position_ws: vec3f) -> vec4f {
+    let current = camera.proj * camera.view * vec4f(position_ws, 1.);
+    let previous = camera.prev_view_proj * vec4f(position_ws, 1.);
+    let offset = current.xy / current.w - camera.jitter - previous.xy / previous.w;
+    return vec4f(offset * vec2f(0.5, -0.5), 0., 1.);
+}
+
+// The parameters of the material multiplied with its textures, roughness being in the
+// green channel and metallic in the blue one like glTF.
+// Discards the fragments of masked materials below the alpha cutoff.
+fn textured_material(uv: vec2f) -> Material {
+    var material = material_params;
+    let metallic_roughness = textureSample(metallic_roughness_map, material_sampler, uv);
+    material.base_color *= textureSample(base_color_map, material_sampler, uv);
+    if material.base_color.a < material.alpha_cutoff {
+        discard;
+    }
+    material.metallic *= metallic_roughness.b;
+    material.roughness *= metallic_roughness.g;
+    material.emissive *= textureSample(emissive_map, material_sampler, uv).rgb;
+    return material;
+}
+
+fn shade_forward(input: VertexOutput) -> vec4f {
+    let material = textured_material(input.uv);
+    let color = shade(
+        material.base_color.rgb,
+        material.metallic,
+        material.roughness,
+        input.position_ws,
+        normalize(input.normal_ws),
+        input.position_cs.xy,
+    ) + material.emissive;
+    return vec4f(apply_fog(color, input.position_ws), material.base_color.a);
+}
+
 @fragment
 fn fragment(input: VertexOutput) -> @location(0) vec4f {
-    var color = vec3f(0.);
+    return vec4f(shade_forward(input).rgb, 1.);
+}
 
-    for (var i_light = 0u; i_light < arrayLength(&dir_lights); i_light += 1u) {
-        let light = &dir_lights[i_light];
-        // color += dot(normalize((*light).pos - input.position_ws), input.normal_ws) * (*light).col;
-        color = (*light).col;
+@fragment
+fn fragment_taa(input: VertexOutput) -> TaaOutput {
+    var output: TaaOutput;
+    output.color = vec4f(shade_forward(input).rgb, 1.);
+    output.motion = motion_vector(input.position_ws);
+    return output;
+}
+
+// Transparent meshes sorted back to front, with straight alpha.
+@fragment
+fn fragment_blend(input: VertexOutput) -> @location(0) vec4f {
+    return shade_forward(input);
+}
+
+// Transparent meshes in any order, weighted to favor the nearest fragments.
+@fragment
+fn fragment_oit(input: VertexOutput) -> OitOutput {
+    let color = shade_forward(input);
+    let depth = -(camera.view * vec4f(input.position_ws, 1.)).z;
+    let weight = color.a * clamp(
+        10. / (1e-5 + pow(depth / 5., 2.) + pow(depth / 200., 6.)),
+        1e-2,
+        3e3,
+    );
+
+    var output: OitOutput;
+    output.accum = vec4f(color.rgb * color.a, color.a) * weight;
+    output.revealage = color.a;
+    return output;
+}
+
+@fragment
+fn fragment_gbuffer(input: VertexOutput) -> GBufferOutput {
+    let material = textured_material(input.uv);
+
+    var output: GBufferOutput;
+    output.albedo = vec4f(material.base_color.rgb, 1.);
+    output.normal = vec4f(normalize(input.normal_ws), 0.);
+    output.material = vec4f(material.metallic, material.roughness, 0., 0.);
+    output.emissive = vec4f(material.emissive, 0.);
+    return output;
+}
+
+// Reconstructs the position of the G-buffer surface from the depth of the G-buffer
+// pass, discarding the background.
+fn gbuffer_position(position_fs: vec4f) -> vec3f {
+    let depth = textureLoad(gbuffer_depth, vec2i(position_fs.xy), 0);
+    if depth >= 1. {
+        discard;
     }
 
-    return vec4f(color, 1.);
-    // return vec4f(input.normal_ws, 1.);
+    let size = vec2f(textureDimensions(gbuffer_depth));
+    let uv = position_fs.xy / size;
+    let ndc = vec4f(uv.x * 2. - 1., 1. - uv.y * 2., depth, 1.);
+    let position = camera.inv_view_proj * ndc;
+    return position.xyz / position.w;
+}
+
+fn shade_gbuffer(position_fs: vec4f, position_ws: vec3f) -> vec4f {
+    let texel = vec2i(position_fs.xy);
+    let albedo = textureLoad(gbuffer_albedo, texel, 0).rgb;
+    let normal = normalize(textureLoad(gbuffer_normal, texel, 0).xyz);
+    let material = textureLoad(gbuffer_material, texel, 0).xy;
+    let emissive = textureLoad(gbuffer_emissive, texel, 0).rgb;
+    let color = shade(albedo, material.x, material.y, position_ws, normal, position_fs.xy) + emissive;
+    return vec4f(apply_fog(color, position_ws), 1.);
+}
+
+@fragment
+fn fragment_lighting(@builtin(position) position_fs: vec4f) -> @location(0) vec4f {
+    return shade_gbuffer(position_fs, gbuffer_position(position_fs));
+}
+
+@fragment
+fn fragment_lighting_taa(@builtin(position) position_fs: vec4f) -> TaaOutput {
+    let position_ws = gbuffer_position(position_fs);
+
+    var output: TaaOutput;
+    output.color = shade_gbuffer(position_fs, position_ws);
+    output.motion = motion_vector(position_ws);
+    return output;
 }
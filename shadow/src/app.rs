@@ -1,33 +1,117 @@
 use std::{
     borrow::Cow,
     f32::consts::FRAC_PI_4,
-    sync::{Arc, Mutex},
-    thread,
-    time::Instant,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use glam::{UVec2, Vec2, Vec3};
+use gilrs::{EventType, Gilrs};
+use glam::{Mat4, UVec2, Vec2, Vec3};
 use wgpu::{ShaderSource, TextureFormat};
 use wgpu_renderer::{
+    render::TransparencyMode,
     scene::{Camera, DirectionalLight, Transform},
-    RendererConfig, WgpuSurfaceRenderer,
+    shadow::ShadowFilter,
+    ssao::SsaoConfig,
+    RendererConfig, WgpuRenderer, WgpuSurfaceRenderer,
 };
 use winit::{
     application::ApplicationHandler,
     dpi::{PhysicalSize, Size},
-    event::{DeviceEvent, DeviceId, WindowEvent},
-    event_loop::{ActiveEventLoop, EventLoop},
+    event::{DeviceEvent, DeviceId, ElementState, MouseScrollDelta, WindowEvent},
+    event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
     keyboard::PhysicalKey,
     window::{Window, WindowAttributes, WindowId},
 };
 
-use crate::scene::{CameraConfig, ControllableCamera};
+use crate::{
+    camera_path::CameraPath,
+    input::{Action, Binding, InputMap, InputState},
+    scene::{CameraConfig, ControllableCamera},
+};
+
+/// Cursor movement in pixels up to which a left click selects instead of rotating.
+const CLICK_THRESHOLD: f32 = 4.;
+/// Step of the simulation, independent of the frame rate.
+const FIXED_TIMESTEP: Duration = Duration::from_nanos(1_000_000_000 / 120);
+/// Longer frames are clamped, so a stall doesn't cause a burst of simulation steps.
+const MAX_FRAME_DELTA: Duration = Duration::from_millis(250);
+const CASCADE_COLORS: [Vec3; 4] = [
+    Vec3::new(1., 0., 0.),
+    Vec3::new(0., 1., 0.),
+    Vec3::new(0., 0., 1.),
+    Vec3::new(1., 1., 0.),
+];
+pub const CAMERA_PATH_FILE: &str = "camera_path.txt";
+
+pub fn create_camera(dim: UVec2) -> Camera {
+    Camera {
+        transform: Transform::default(),
+        aspect_ratio: dim.x as f32 / dim.y as f32,
+        fov: FRAC_PI_4,
+        near: 0.1,
+        far: 1000.,
+    }
+}
+
+/// Loads the scene shown by the viewer, shared with offline rendering.
+pub fn load_scene(renderer: &mut WgpuRenderer) {
+    renderer.dir_lights.push(DirectionalLight {
+        translation: Vec3::new(10., 20., 0.),
+        direction: Vec3::new(-1., -1.2, 1.).normalize(),
+        color: Vec3::ONE,
+        cascade_count: 4,
+        split_lambda: 0.75,
+        shadow_distance: 100.,
+        shadow_filter: ShadowFilter::Pcss {
+            samples: 16,
+            light_size: 0.05,
+        },
+        depth_bias: 0.05,
+        normal_bias: 1.5,
+    });
+    renderer.load_obj("assets/scene.obj");
+    if let Err(err) = renderer.load_gltf("assets/hung.gltf") {
+        log::error!("Failed to load animated model: {}", err);
+    }
+    renderer.write_scene();
+}
+
+/// Rewinds every animation, so recording and replaying a camera path start from the
+/// same pose.
+fn restart_animations(renderer: &mut WgpuRenderer) {
+    for model in &mut renderer.skinned_models {
+        model.player.time = 0.;
+    }
+    renderer.update_animations(0.);
+}
 
 pub struct Application<'w> {
     pub renderer: WgpuSurfaceRenderer<'w>,
     window: Arc<Window>,
 
-    main_camera: Arc<Mutex<ControllableCamera>>,
+    main_camera: ControllableCamera,
+    input: InputState,
+    // None if gamepads aren't supported on this platform.
+    gilrs: Option<Gilrs>,
+    cursor_position: Vec2,
+    press_position: Option<Vec2>,
+    show_gizmos: bool,
+    /// Main camera when the gizmos were shown, whose frustum stays in place to be seen
+    /// from outside.
+    gizmo_camera: Camera,
+
+    /// Minimum time between frames, `None` to render as fast as the present mode allows.
+    frame_cap: Option<Duration>,
+    last_frame: Instant,
+    accumulator: Duration,
+    /// Seconds simulated in fixed steps.
+    sim_time: f32,
+
+    /// Simulation time at the start of the recording and the recorded path.
+    recording: Option<(f32, CameraPath)>,
+    /// Remaining frames of the replayed path, with the animation delta of each.
+    replay: Option<std::vec::IntoIter<(f32, Transform)>>,
 }
 
 impl<'w> Application<'w> {
@@ -48,99 +132,347 @@ impl<'w> Application<'w> {
             ShaderSource::Wgsl(Cow::Borrowed(include_str!("../assets/scene.wgsl"))),
             Some(RendererConfig {
                 primary_target_format: TextureFormat::Bgra8UnormSrgb,
+                ssao: Some(SsaoConfig::default()),
+                transparency: Some(TransparencyMode::Sorted),
                 ..Default::default()
             }),
         )
         .await;
 
-        let main_camera = ControllableCamera::new(
-            Camera {
-                transform: Transform::default(),
-                aspect_ratio: dim.x as f32 / dim.y as f32,
-                fov: FRAC_PI_4,
-                near: 0.1,
-                far: 1000.,
-            },
-            CameraConfig::default(),
-        );
-
+        let main_camera = ControllableCamera::new(create_camera(dim), CameraConfig::default());
         renderer.renderer_mut().set_camera(&main_camera.camera);
+        load_scene(renderer.renderer_mut());
 
-        renderer.renderer_mut().dir_lights.push(DirectionalLight {
-            translation: Vec3::new(10., 20., 0.),
-            direction: Vec3::new(-1., -1.2, 1.).normalize(),
-            color: Vec3::ONE,
+        let input_map = InputMap::load("assets/input.cfg").unwrap_or_else(|err| {
+            log::error!("Failed to load input config, using defaults: {}", err);
+            InputMap::default()
         });
-        renderer.renderer_mut().load_obj("assets/icosphere.obj");
-        renderer.renderer_mut().write_scene();
 
         Self {
             renderer,
             window,
 
-            main_camera: Arc::new(Mutex::new(main_camera)),
+            main_camera,
+            input: InputState::new(input_map),
+            gilrs: Gilrs::new()
+                .inspect_err(|err| log::warn!("Gamepads unavailable: {}", err))
+                .ok(),
+            cursor_position: Vec2::ZERO,
+            press_position: None,
+            show_gizmos: false,
+            gizmo_camera: create_camera(dim),
+
+            frame_cap: None,
+            last_frame: Instant::now(),
+            accumulator: Duration::ZERO,
+            sim_time: 0.,
+
+            recording: None,
+            replay: None,
         }
     }
 
-    pub fn run(&self) {
-        let window = self.window.clone();
-        let main_camera = self.main_camera.clone();
-        let mut delta = 0.;
+    /// Caps the frame rate at `fps`, or removes the cap for `None`.
+    pub fn set_max_fps(&mut self, fps: Option<f32>) {
+        self.frame_cap = fps.map(|fps| Duration::from_secs_f32(1. / fps));
+    }
+
+    /// Advances everything that has to behave the same regardless of the frame rate.
+    fn fixed_update(&mut self, delta: f32) {
+        self.renderer.renderer_mut().update_animations(delta);
+    }
 
-        thread::spawn(move || loop {
-            let start = std::time::Instant::now();
+    fn redraw(&mut self, event_loop: &ActiveEventLoop) {
+        let now = Instant::now();
+        let frame_delta = (now - self.last_frame).min(MAX_FRAME_DELTA);
+        self.last_frame = now;
 
-            window.request_redraw();
-            main_camera.lock().unwrap().update(delta);
+        match self.replay.as_mut().map(Iterator::next) {
+            Some(Some((animation_delta, transform))) => {
+                self.renderer
+                    .renderer_mut()
+                    .update_animations(animation_delta);
+                self.main_camera.set_transform(transform);
+            }
+            replay => {
+                if replay.is_some() {
+                    log::info!("Camera path replay finished.");
+                    self.replay = None;
+                }
 
-            delta = start.elapsed().as_secs_f32();
-        });
+                self.accumulator += frame_delta;
+                while self.accumulator >= FIXED_TIMESTEP {
+                    self.fixed_update(FIXED_TIMESTEP.as_secs_f32());
+                    self.accumulator -= FIXED_TIMESTEP;
+                    self.sim_time += FIXED_TIMESTEP.as_secs_f32();
+                }
+                self.poll_gamepads();
+                self.main_camera
+                    .update(frame_delta.as_secs_f32(), &self.input);
+
+                if let Some((start, path)) = &mut self.recording {
+                    path.push(self.sim_time - *start, self.main_camera.camera.transform);
+                }
+            }
+        }
+
+        if self.show_gizmos {
+            self.draw_gizmos();
+        }
+
+        if self.renderer.renderer().is_device_lost() {
+            log::warn!("Device lost, recreating the renderer.");
+            pollster::block_on(self.renderer.recover_device());
+        }
+
+        let renderer = self.renderer.renderer_mut();
+        renderer.set_camera(&self.main_camera.camera);
+        renderer.write_scene();
+        if self.renderer.draw().is_err() {
+            event_loop.exit();
+        }
+    }
+
+    /// Feeds gamepad events into the same input state as the keyboard and mouse.
+    fn poll_gamepads(&mut self) {
+        let Some(gilrs) = &mut self.gilrs else {
+            return;
+        };
+
+        let events = std::iter::from_fn(|| gilrs.next_event()).collect::<Vec<_>>();
+        for event in events {
+            match event.event {
+                EventType::ButtonPressed(button, _) => {
+                    self.handle_input(Binding::GamepadButton(button), ElementState::Pressed, false)
+                }
+                EventType::ButtonReleased(button, _) => self.handle_input(
+                    Binding::GamepadButton(button),
+                    ElementState::Released,
+                    false,
+                ),
+                EventType::AxisChanged(axis, value, _) => self.input.handle_axis(axis, value),
+                EventType::Disconnected => self.input.clear_gamepad(),
+                _ => {}
+            }
+        }
+    }
+
+    fn handle_input(&mut self, binding: Binding, state: ElementState, repeat: bool) {
+        let actions = self.input.handle(binding, state).to_vec();
+
+        for action in actions {
+            match (action, state) {
+                (Action::Select, ElementState::Pressed) => {
+                    self.press_position = Some(self.cursor_position)
+                }
+                (Action::Select, ElementState::Released) => {
+                    let clicked = self
+                        .press_position
+                        .take()
+                        .is_some_and(|p| p.distance(self.cursor_position) <= CLICK_THRESHOLD);
+                    if clicked {
+                        self.select_at_cursor();
+                    }
+                }
+                // Everything else is either held or triggered once per press.
+                (_, ElementState::Released) => {}
+                _ if repeat => {}
+                (Action::ToggleCameraMode, _) => {
+                    self.main_camera.toggle_mode();
+                    log::info!("Camera mode: {:?}", self.main_camera.mode());
+                }
+                (Action::FrameSelected, _) => {
+                    let renderer = self.renderer.renderer();
+                    if let Some(bounds) = renderer.selected.and_then(|h| renderer.mesh_bounds(h)) {
+                        self.main_camera.frame(bounds);
+                    }
+                }
+                (Action::RecordCameraPath, _) => self.toggle_recording(),
+                (Action::CycleViewMode, _) => {
+                    let renderer = self.renderer.renderer_mut();
+                    let view_mode = renderer.config().view_mode.next();
+                    renderer.set_view_mode(view_mode);
+                    log::info!("View mode: {}", view_mode.name());
+                }
+                (Action::ToggleGizmos, _) => {
+                    self.show_gizmos = !self.show_gizmos;
+                    self.gizmo_camera = self.main_camera.camera;
+                }
+                (Action::ToggleGizmoDepthTest, _) => {
+                    let lines = &mut self.renderer.renderer_mut().debug_lines;
+                    lines.depth_test = !lines.depth_test;
+                }
+                (Action::ReplayCameraPath, _) => self.start_replay(),
+                (Action::FrameAll, _) => {
+                    if let Some(bounds) = self.renderer.renderer().bounds() {
+                        self.main_camera.frame(bounds);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn draw_gizmos(&mut self) {
+        let renderer = self.renderer.renderer_mut();
+        let selected_bounds = renderer.selected.and_then(|h| renderer.mesh_bounds(h));
+        let cascades = renderer.shadow_cascades().to_vec();
+        let lines = &mut renderer.debug_lines;
+
+        lines.axes(Mat4::IDENTITY, 1.);
+        for light in &renderer.dir_lights {
+            lines.sphere(light.translation, 0.25, light.color);
+            lines.arrow(
+                light.translation,
+                light.translation + light.direction * 2.,
+                light.color,
+            );
+        }
+        for (index, cascade) in cascades.iter().enumerate() {
+            lines.frustum(
+                cascade.view_proj,
+                CASCADE_COLORS[index % CASCADE_COLORS.len()],
+            );
+        }
+        if let Some(bounds) = selected_bounds {
+            lines.aabb(&bounds, Vec3::new(1., 0.5, 0.));
+        }
+
+        // Cut at the end of the shadows rather than at the far plane, to stay in view.
+        let camera = Camera {
+            far: cascades
+                .last()
+                .map_or(self.gizmo_camera.far, |cascade| cascade.split_far),
+            ..self.gizmo_camera
+        };
+        lines.frustum(camera.projection_matrix() * camera.view_matrix(), Vec3::ONE);
+    }
+
+    fn toggle_recording(&mut self) {
+        match self.recording.take() {
+            Some((_, path)) => match path.save(CAMERA_PATH_FILE) {
+                Ok(()) => log::info!(
+                    "Saved {} camera keyframes to {}.",
+                    path.keyframes.len(),
+                    CAMERA_PATH_FILE
+                ),
+                Err(err) => log::error!("Failed to save camera path: {}", err),
+            },
+            None => {
+                restart_animations(self.renderer.renderer_mut());
+                self.recording = Some((self.sim_time, CameraPath::default()));
+                log::info!("Recording camera path.");
+            }
+        }
+    }
+
+    fn start_replay(&mut self) {
+        if self.recording.is_some() {
+            log::warn!("Stop recording before replaying the camera path.");
+            return;
+        }
+
+        match CameraPath::load(CAMERA_PATH_FILE) {
+            Ok(path) => {
+                restart_animations(self.renderer.renderer_mut());
+                self.replay = Some(path.frames().collect::<Vec<_>>().into_iter());
+                log::info!("Replaying {} camera keyframes.", path.keyframes.len());
+            }
+            Err(err) => log::error!("Failed to load camera path: {}", err),
+        }
+    }
+
+    fn select_at_cursor(&mut self) {
+        let size = self.window.inner_size();
+        let ray = self.main_camera.camera.viewport_ray(
+            self.cursor_position,
+            Vec2::new(size.width as f32, size.height as f32),
+        );
+
+        let renderer = self.renderer.renderer_mut();
+        renderer.selected = renderer.pick(&ray);
+        match renderer.selected {
+            Some(handle) => {
+                let assets = renderer.context().assets();
+                let name = assets.mesh(handle).and_then(|m| m.name.as_deref());
+                log::info!("Selected {}", name.unwrap_or("unnamed mesh"));
+            }
+            None => log::info!("Selection cleared"),
+        }
     }
 }
 
 impl<'w> ApplicationHandler for Application<'w> {
     fn resumed(&mut self, _event_loop: &ActiveEventLoop) {}
 
+    fn about_to_wait(&mut self, event_loop: &ActiveEventLoop) {
+        let config = self.renderer.surface_config();
+        // Minimized windows are redrawn once they are resized again.
+        if config.width == 0 || config.height == 0 {
+            event_loop.set_control_flow(ControlFlow::Wait);
+            return;
+        }
+
+        match self.frame_cap {
+            Some(frame_cap) if self.last_frame.elapsed() < frame_cap => {
+                event_loop.set_control_flow(ControlFlow::WaitUntil(self.last_frame + frame_cap));
+            }
+            _ => {
+                event_loop.set_control_flow(ControlFlow::Wait);
+                self.window.request_redraw();
+            }
+        }
+    }
+
     fn window_event(
         &mut self,
-        _event_loop: &ActiveEventLoop,
+        event_loop: &ActiveEventLoop,
         _window_id: WindowId,
         event: WindowEvent,
     ) {
         match event {
-            WindowEvent::RedrawRequested => {
-                let Ok(main_camera) = self.main_camera.lock() else {
-                    return;
-                };
-                self.renderer.renderer_mut().set_camera(&main_camera.camera);
-                self.renderer.renderer_mut().write_scene();
-                self.renderer.draw();
+            WindowEvent::RedrawRequested => self.redraw(event_loop),
+            WindowEvent::Resized(size) => {
+                self.renderer.resize(UVec2::new(size.width, size.height));
+                // Minimized windows report a zero size.
+                if size.width > 0 && size.height > 0 {
+                    self.main_camera
+                        .set_aspect_ratio(size.width as f32 / size.height as f32);
+                }
+                self.window.request_redraw();
             }
-            WindowEvent::Resized(size) => self.renderer.resize(UVec2::new(size.width, size.height)),
             WindowEvent::CloseRequested => std::process::exit(0),
             WindowEvent::KeyboardInput {
                 device_id: _,
                 event,
                 is_synthetic: _,
             } => {
-                let Ok(mut main_camera) = self.main_camera.lock() else {
-                    return;
-                };
-                match event.physical_key {
-                    PhysicalKey::Code(key) => main_camera.keyboard_control(key, &event.state),
-                    PhysicalKey::Unidentified(_) => {}
+                if let PhysicalKey::Code(key) = event.physical_key {
+                    self.handle_input(Binding::Key(key), event.state, event.repeat);
                 }
             }
-            WindowEvent::MouseInput {
+            WindowEvent::MouseWheel {
                 device_id: _,
-                state,
-                button,
+                delta,
+                phase: _,
             } => {
-                let Ok(mut main_camera) = self.main_camera.lock() else {
-                    return;
+                let lines = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => y,
+                    // Roughly the height of a line in most browsers and toolkits.
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / 20.,
                 };
-                main_camera.mouse_control(button, &state);
+                self.main_camera.mouse_wheel(lines);
             }
+            WindowEvent::MouseInput {
+                device_id: _,
+                state,
+                button,
+            } => self.handle_input(Binding::Mouse(button), state, false),
+            WindowEvent::CursorMoved {
+                device_id: _,
+                position,
+            } => self.cursor_position = Vec2::new(position.x as f32, position.y as f32),
+            WindowEvent::Focused(false) => self.input.clear(),
             _ => {}
         }
     }
@@ -153,10 +485,8 @@ impl<'w> ApplicationHandler for Application<'w> {
     ) {
         match event {
             DeviceEvent::MouseMotion { delta } => {
-                let Ok(mut main_camera) = self.main_camera.lock() else {
-                    return;
-                };
-                main_camera.mouse_move(Vec2::new(delta.0 as f32, delta.1 as f32));
+                self.main_camera
+                    .mouse_move(Vec2::new(delta.0 as f32, delta.1 as f32));
             }
             _ => {}
         }
@@ -0,0 +1,172 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use glam::{Quat, Vec3};
+use wgpu_renderer::scene::Transform;
+
+/// `time` is the simulation time since the start of the recording, so replaying
+/// advances animations by exactly the same steps.
+#[derive(Debug, Clone, Copy)]
+pub struct CameraKeyframe {
+    pub time: f32,
+    pub transform: Transform,
+}
+
+/// Camera transforms of consecutive frames, stored one per line as
+/// `time tx ty tz rx ry rz rw`.
+#[derive(Debug, Clone, Default)]
+pub struct CameraPath {
+    pub keyframes: Vec<CameraKeyframe>,
+}
+
+impl CameraPath {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let invalid = |line: usize| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid camera keyframe on line {}", line + 1),
+            )
+        };
+
+        let mut keyframes = Vec::new();
+        for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let values = line
+                .split_whitespace()
+                .map(str::parse::<f32>)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid(index))?;
+            let [time, tx, ty, tz, rx, ry, rz, rw] = values[..] else {
+                return Err(invalid(index));
+            };
+
+            keyframes.push(CameraKeyframe {
+                time,
+                transform: Transform {
+                    translation: Vec3::new(tx, ty, tz),
+                    rotation: Quat::from_xyzw(rx, ry, rz, rw),
+                },
+            });
+        }
+
+        Ok(Self { keyframes })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for CameraKeyframe { time, transform } in &self.keyframes {
+            let t = transform.translation;
+            let r = transform.rotation;
+            writeln!(
+                writer,
+                "{} {} {} {} {} {} {} {}",
+                time, t.x, t.y, t.z, r.x, r.y, r.z, r.w
+            )?;
+        }
+        writer.flush()
+    }
+
+    #[inline]
+    pub fn push(&mut self, time: f32, transform: Transform) {
+        self.keyframes.push(CameraKeyframe { time, transform });
+    }
+
+    /// Yields every keyframe with the time elapsed since the previous one, which is
+    /// how far animations have to be advanced for that frame.
+    pub fn frames(&self) -> impl Iterator<Item = (f32, Transform)> + '_ {
+        let mut last_time = 0.;
+        self.keyframes.iter().map(move |keyframe| {
+            let delta = keyframe.time - last_time;
+            last_time = keyframe.time;
+            (delta, keyframe.transform)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("camera_path_{}_{}", std::process::id(), name))
+    }
+
+    fn load_source(name: &str, source: &str) -> io::Result<CameraPath> {
+        let path = temp_path(name);
+        std::fs::write(&path, source)?;
+        let result = CameraPath::load(&path);
+        std::fs::remove_file(path)?;
+        result
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let mut camera_path = CameraPath::default();
+        camera_path.push(0.1, Transform::default());
+        camera_path.push(
+            0.35,
+            Transform {
+                translation: Vec3::new(1.5, -2., 1e-3),
+                rotation: Quat::from_rotation_y(0.7),
+            },
+        );
+
+        let path = temp_path("round_trip");
+        camera_path.save(&path).unwrap();
+        let loaded = CameraPath::load(&path);
+        std::fs::remove_file(path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.keyframes.len(), camera_path.keyframes.len());
+        for (loaded, saved) in loaded.keyframes.iter().zip(&camera_path.keyframes) {
+            assert_eq!(loaded.time, saved.time);
+            assert_eq!(loaded.transform.translation, saved.transform.translation);
+            assert_eq!(loaded.transform.rotation, saved.transform.rotation);
+        }
+    }
+
+    #[test]
+    fn load_skips_blank_lines() {
+        let camera_path = load_source("blank", "\n0.5 1 2 3 0 0 0 1\n  \n").unwrap();
+        assert_eq!(camera_path.keyframes.len(), 1);
+        assert_eq!(
+            camera_path.keyframes[0].transform.translation,
+            Vec3::new(1., 2., 3.)
+        );
+    }
+
+    #[test]
+    fn load_rejects_invalid_lines() {
+        for (name, source) in [
+            ("text", "0 0 0 0 0 0 0 1\n0 0 0 x 0 0 0 1\n"),
+            ("short", "0 0 0 0 0 0 0 1\n0 0 0 0 0 0 1\n"),
+            ("long", "0 0 0 0 0 0 0 1\n0 0 0 0 0 0 0 1 0\n"),
+        ] {
+            let error = load_source(name, source).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().ends_with("line 2"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn frames_yield_time_deltas() {
+        let mut camera_path = CameraPath::default();
+        for time in [0.25, 0.5, 1.] {
+            camera_path.push(time, Transform::default());
+        }
+        let deltas = camera_path
+            .frames()
+            .map(|(delta, _)| delta)
+            .collect::<Vec<_>>();
+        assert_eq!(deltas, [0.25, 0.25, 0.5]);
+    }
+}
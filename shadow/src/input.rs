@@ -0,0 +1,470 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::Path,
+};
+
+use gilrs::{Axis, Button};
+use winit::{
+    event::{ElementState, MouseButton},
+    keyboard::KeyCode,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    /// Multiplies the movement speed while held.
+    Boost,
+    /// Turn the camera at a fixed speed, for keys and gamepad sticks.
+    LookLeft,
+    LookRight,
+    LookUp,
+    LookDown,
+    Rotate,
+    Pan,
+    Select,
+    ToggleCameraMode,
+    FrameSelected,
+    FrameAll,
+    /// Starts recording the camera path, or stops and saves it.
+    RecordCameraPath,
+    ReplayCameraPath,
+    ToggleGizmos,
+    /// Switches between hiding gizmos behind geometry and drawing them on top.
+    ToggleGizmoDepthTest,
+    CycleViewMode,
+}
+
+impl Action {
+    const ALL: [(&'static str, Action); 22] = [
+        ("move_forward", Action::MoveForward),
+        ("move_backward", Action::MoveBackward),
+        ("move_left", Action::MoveLeft),
+        ("move_right", Action::MoveRight),
+        ("move_up", Action::MoveUp),
+        ("move_down", Action::MoveDown),
+        ("boost", Action::Boost),
+        ("look_left", Action::LookLeft),
+        ("look_right", Action::LookRight),
+        ("look_up", Action::LookUp),
+        ("look_down", Action::LookDown),
+        ("rotate", Action::Rotate),
+        ("pan", Action::Pan),
+        ("select", Action::Select),
+        ("toggle_camera_mode", Action::ToggleCameraMode),
+        ("frame_selected", Action::FrameSelected),
+        ("frame_all", Action::FrameAll),
+        ("record_camera_path", Action::RecordCameraPath),
+        ("replay_camera_path", Action::ReplayCameraPath),
+        ("toggle_gizmos", Action::ToggleGizmos),
+        ("toggle_gizmo_depth_test", Action::ToggleGizmoDepthTest),
+        ("cycle_view_mode", Action::CycleViewMode),
+    ];
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|(n, _)| *n == name).map(|(_, a)| *a)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    GamepadButton(Button),
+    /// One direction of a gamepad axis, active with the magnitude of the axis in that
+    /// direction.
+    GamepadAxis {
+        axis: Axis,
+        positive: bool,
+    },
+}
+
+/// Keys that can be bound, named like their [`KeyCode`] variant in the config file.
+const BINDABLE_KEYS: [KeyCode; 70] = [
+    KeyCode::KeyA,
+    KeyCode::KeyB,
+    KeyCode::KeyC,
+    KeyCode::KeyD,
+    KeyCode::KeyE,
+    KeyCode::KeyF,
+    KeyCode::KeyG,
+    KeyCode::KeyH,
+    KeyCode::KeyI,
+    KeyCode::KeyJ,
+    KeyCode::KeyK,
+    KeyCode::KeyL,
+    KeyCode::KeyM,
+    KeyCode::KeyN,
+    KeyCode::KeyO,
+    KeyCode::KeyP,
+    KeyCode::KeyQ,
+    KeyCode::KeyR,
+    KeyCode::KeyS,
+    KeyCode::KeyT,
+    KeyCode::KeyU,
+    KeyCode::KeyV,
+    KeyCode::KeyW,
+    KeyCode::KeyX,
+    KeyCode::KeyY,
+    KeyCode::KeyZ,
+    KeyCode::Digit0,
+    KeyCode::Digit1,
+    KeyCode::Digit2,
+    KeyCode::Digit3,
+    KeyCode::Digit4,
+    KeyCode::Digit5,
+    KeyCode::Digit6,
+    KeyCode::Digit7,
+    KeyCode::Digit8,
+    KeyCode::Digit9,
+    KeyCode::F1,
+    KeyCode::F2,
+    KeyCode::F3,
+    KeyCode::F4,
+    KeyCode::F5,
+    KeyCode::F6,
+    KeyCode::F7,
+    KeyCode::F8,
+    KeyCode::F9,
+    KeyCode::F10,
+    KeyCode::F11,
+    KeyCode::F12,
+    KeyCode::ArrowUp,
+    KeyCode::ArrowDown,
+    KeyCode::ArrowLeft,
+    KeyCode::ArrowRight,
+    KeyCode::ShiftLeft,
+    KeyCode::ShiftRight,
+    KeyCode::ControlLeft,
+    KeyCode::ControlRight,
+    KeyCode::AltLeft,
+    KeyCode::AltRight,
+    KeyCode::Space,
+    KeyCode::Tab,
+    KeyCode::Enter,
+    KeyCode::Escape,
+    KeyCode::Backspace,
+    KeyCode::Delete,
+    KeyCode::Insert,
+    KeyCode::Home,
+    KeyCode::End,
+    KeyCode::PageUp,
+    KeyCode::PageDown,
+    KeyCode::Backquote,
+];
+
+/// Gamepad buttons that can be bound, named like their [`Button`] variant prefixed
+/// with `Gamepad` in the config file.
+const BINDABLE_BUTTONS: [Button; 19] = [
+    Button::South,
+    Button::East,
+    Button::North,
+    Button::West,
+    Button::C,
+    Button::Z,
+    Button::LeftTrigger,
+    Button::LeftTrigger2,
+    Button::RightTrigger,
+    Button::RightTrigger2,
+    Button::Select,
+    Button::Start,
+    Button::Mode,
+    Button::LeftThumb,
+    Button::RightThumb,
+    Button::DPadUp,
+    Button::DPadDown,
+    Button::DPadLeft,
+    Button::DPadRight,
+];
+
+/// Gamepad axes that can be bound, named like their [`Axis`] variant prefixed with
+/// `Gamepad` and followed by `+` or `-` for their direction in the config file.
+const BINDABLE_AXES: [Axis; 8] = [
+    Axis::LeftStickX,
+    Axis::LeftStickY,
+    Axis::LeftZ,
+    Axis::RightStickX,
+    Axis::RightStickY,
+    Axis::RightZ,
+    Axis::DPadX,
+    Axis::DPadY,
+];
+
+impl Binding {
+    fn from_name(name: &str) -> Option<Self> {
+        if let Some(name) = name.strip_prefix("Gamepad") {
+            return Self::from_gamepad_name(name);
+        }
+
+        match name {
+            "MouseLeft" => Some(Binding::Mouse(MouseButton::Left)),
+            "MouseRight" => Some(Binding::Mouse(MouseButton::Right)),
+            "MouseMiddle" => Some(Binding::Mouse(MouseButton::Middle)),
+            "MouseBack" => Some(Binding::Mouse(MouseButton::Back)),
+            "MouseForward" => Some(Binding::Mouse(MouseButton::Forward)),
+            _ => BINDABLE_KEYS
+                .iter()
+                .find(|key| format!("{:?}", key) == name)
+                .map(|key| Binding::Key(*key)),
+        }
+    }
+
+    fn from_gamepad_name(name: &str) -> Option<Self> {
+        let axis = |name: &str, positive| {
+            BINDABLE_AXES
+                .iter()
+                .find(|axis| format!("{:?}", axis) == name)
+                .map(|&axis| Binding::GamepadAxis { axis, positive })
+        };
+
+        if let Some(name) = name.strip_suffix('+') {
+            axis(name, true)
+        } else if let Some(name) = name.strip_suffix('-') {
+            axis(name, false)
+        } else {
+            BINDABLE_BUTTONS
+                .iter()
+                .find(|button| format!("{:?}", button) == name)
+                .map(|button| Binding::GamepadButton(*button))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum InputConfigError {
+    Io(std::io::Error),
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for InputConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputConfigError::Io(err) => write!(f, "{}", err),
+            InputConfigError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for InputConfigError {}
+
+/// Maps bindings to actions. A binding can trigger several actions, like the left mouse
+/// button both rotating and selecting.
+#[derive(Debug, Clone)]
+pub struct InputMap {
+    bindings: HashMap<Binding, Vec<Action>>,
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        Self::parse(include_str!("../assets/input.cfg")).unwrap()
+    }
+}
+
+impl InputMap {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, InputConfigError> {
+        Self::parse(&std::fs::read_to_string(path).map_err(InputConfigError::Io)?)
+    }
+
+    /// Every non-empty line not starting with `#` has the form
+    /// `action = binding, binding, ...`.
+    pub fn parse(source: &str) -> Result<Self, InputConfigError> {
+        let mut bindings = HashMap::<Binding, Vec<Action>>::new();
+
+        for (index, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |message: String| InputConfigError::Parse {
+                line: index + 1,
+                message,
+            };
+            let Some((action, names)) = line.split_once('=') else {
+                return Err(error("expected `action = binding, ...`".to_owned()));
+            };
+            let action = Action::from_name(action.trim())
+                .ok_or_else(|| error(format!("unknown action `{}`", action.trim())))?;
+
+            for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                let binding = Binding::from_name(name)
+                    .ok_or_else(|| error(format!("unknown binding `{}`", name)))?;
+                bindings.entry(binding).or_default().push(action);
+            }
+        }
+
+        Ok(Self { bindings })
+    }
+
+    #[inline]
+    pub fn actions(&self, binding: Binding) -> &[Action] {
+        self.bindings
+            .get(&binding)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+}
+
+/// Tracks which bindings are held, so releasing one of two keys bound to opposite
+/// actions doesn't cancel the other.
+pub struct InputState {
+    map: InputMap,
+    pressed: HashSet<Binding>,
+    // Magnitudes of the gamepad axis directions away from their rest position.
+    axes: HashMap<Binding, f32>,
+}
+
+impl InputState {
+    pub fn new(map: InputMap) -> Self {
+        Self {
+            map,
+            pressed: HashSet::new(),
+            axes: HashMap::new(),
+        }
+    }
+
+    /// Updates the state of `binding` and returns the actions it's bound to.
+    pub fn handle(&mut self, binding: Binding, state: ElementState) -> &[Action] {
+        match state {
+            ElementState::Pressed => self.pressed.insert(binding),
+            ElementState::Released => self.pressed.remove(&binding),
+        };
+        self.map.actions(binding)
+    }
+
+    /// Updates both directions of `axis` from its value in `[-1, 1]`.
+    pub fn handle_axis(&mut self, axis: Axis, value: f32) {
+        for (positive, magnitude) in [(true, value), (false, -value)] {
+            let binding = Binding::GamepadAxis { axis, positive };
+            if magnitude > 0. {
+                self.axes.insert(binding, magnitude.min(1.));
+            } else {
+                self.axes.remove(&binding);
+            }
+        }
+    }
+
+    /// Releases everything, for when the window loses focus and release events are
+    /// missed.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.pressed.clear();
+        self.axes.clear();
+    }
+
+    /// Releases the gamepad buttons and axes, for when the gamepad is disconnected.
+    pub fn clear_gamepad(&mut self) {
+        self.pressed
+            .retain(|binding| matches!(binding, Binding::Key(_) | Binding::Mouse(_)));
+        self.axes.clear();
+    }
+
+    /// 1 if a key or button bound to `action` is held, otherwise the largest magnitude
+    /// of the axes bound to it.
+    pub fn value(&self, action: Action) -> f32 {
+        let is_bound = |binding: &Binding| self.map.actions(*binding).contains(&action);
+        if self.pressed.iter().any(is_bound) {
+            return 1.;
+        }
+
+        self.axes
+            .iter()
+            .filter(|(binding, _)| is_bound(binding))
+            .map(|(_, &magnitude)| magnitude)
+            .fold(0., f32::max)
+    }
+
+    /// Axes pushed past half way count as held.
+    #[inline]
+    pub fn is_active(&self, action: Action) -> bool {
+        self.value(action) >= 0.5
+    }
+
+    /// In `[-1, 1]` depending on the values of the two actions.
+    pub fn axis(&self, negative: Action, positive: Action) -> f32 {
+        self.value(positive) - self.value(negative)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error_line(source: &str) -> usize {
+        match InputMap::parse(source) {
+            Err(InputConfigError::Parse { line, .. }) => line,
+            result => panic!("expected a parse error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parse_bindings() {
+        let map = InputMap::parse(
+            "# Comment\n\nrotate = MouseLeft\nselect = MouseLeft,\n\
+             move_up = KeyE, GamepadSouth\nlook_up = GamepadRightStickY+\n",
+        )
+        .unwrap();
+        assert_eq!(
+            map.actions(Binding::Mouse(MouseButton::Left)),
+            [Action::Rotate, Action::Select]
+        );
+        assert_eq!(map.actions(Binding::Key(KeyCode::KeyE)), [Action::MoveUp]);
+        assert_eq!(
+            map.actions(Binding::GamepadButton(Button::South)),
+            [Action::MoveUp]
+        );
+        let axis = Binding::GamepadAxis {
+            axis: Axis::RightStickY,
+            positive: true,
+        };
+        assert_eq!(map.actions(axis), [Action::LookUp]);
+        assert_eq!(map.actions(Binding::Key(KeyCode::KeyQ)), []);
+    }
+
+    #[test]
+    fn parse_default_config() {
+        let map = InputMap::default();
+        assert_eq!(
+            map.actions(Binding::Key(KeyCode::KeyW)),
+            [Action::MoveForward]
+        );
+        let axis = Binding::GamepadAxis {
+            axis: Axis::LeftStickY,
+            positive: false,
+        };
+        assert_eq!(map.actions(axis), [Action::MoveBackward]);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_lines() {
+        assert_eq!(parse_error_line("rotate = MouseLeft\nrotate MouseLeft"), 2);
+        assert_eq!(parse_error_line("# Comment\n\nspin = MouseLeft"), 3);
+        assert_eq!(parse_error_line("rotate = MouseLeft, KeyWW"), 1);
+        assert_eq!(parse_error_line("look_up = GamepadRightStickY"), 1);
+        assert_eq!(parse_error_line("look_up = GamepadRightStick+"), 1);
+    }
+
+    #[test]
+    fn state_combines_bindings() {
+        let map =
+            InputMap::parse("move_left = KeyA, GamepadLeftStickX-\nmove_right = KeyD").unwrap();
+        let mut state = InputState::new(map);
+        state.handle_axis(Axis::LeftStickX, -0.25);
+        assert_eq!(state.value(Action::MoveLeft), 0.25);
+        assert!(!state.is_active(Action::MoveLeft));
+
+        state.handle(Binding::Key(KeyCode::KeyA), ElementState::Pressed);
+        state.handle(Binding::Key(KeyCode::KeyD), ElementState::Pressed);
+        assert_eq!(state.axis(Action::MoveLeft, Action::MoveRight), 0.);
+        state.handle(Binding::Key(KeyCode::KeyA), ElementState::Released);
+        assert_eq!(state.axis(Action::MoveLeft, Action::MoveRight), 0.75);
+
+        state.clear_gamepad();
+        assert_eq!(state.axis(Action::MoveLeft, Action::MoveRight), 1.);
+    }
+}
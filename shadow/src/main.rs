@@ -1,21 +1,32 @@
-use std::{borrow::Cow, f32::consts::FRAC_PI_4};
+use std::{borrow::Cow, f32::consts::FRAC_PI_4, path::Path};
 
-use app::Application;
+use app::{create_camera, load_scene, Application, CAMERA_PATH_FILE};
+use camera_path::CameraPath;
 use glam::{UVec2, Vec3};
 
 use wgpu_renderer::{
+    atmosphere::{FogConfig, SkyConfig},
+    bloom::BloomConfig,
+    render::{RenderPath, TransparencyMode, ViewMode},
     scene::{Camera, DirectionalLight, Transform},
-    WgpuImageRenderer,
+    shadow::ShadowFilter,
+    ssao::SsaoConfig,
+    taa::TaaConfig,
+    RendererConfig, WgpuImageRenderer, WgpuRenderer,
 };
 
 use wgpu::*;
 use winit::event_loop::EventLoop;
 
 mod app;
+mod camera_path;
+mod input;
 mod scene;
 
 const TEXTURE_DIM: UVec2 = UVec2::splat(512);
 const WINDOW_DIM: UVec2 = UVec2::new(1920, 1080);
+/// `None` renders as fast as the present mode allows.
+const MAX_FPS: Option<f32> = None;
 
 async fn render_to_image(dim: UVec2) {
     let mut renderer = WgpuImageRenderer::new(
@@ -36,6 +47,15 @@ async fn render_to_image(dim: UVec2) {
         translation: Vec3::new(10., 20., 0.),
         direction: Vec3::new(-1., -1.2, 1.).normalize(),
         color: Vec3::ONE,
+        cascade_count: 4,
+        split_lambda: 0.75,
+        shadow_distance: 100.,
+        shadow_filter: ShadowFilter::Pcss {
+            samples: 16,
+            light_size: 0.05,
+        },
+        depth_bias: 0.05,
+        normal_bias: 1.5,
     });
     renderer.renderer_mut().write_scene();
     renderer.renderer_mut().load_obj("assets/hung_mesh.obj");
@@ -44,13 +64,138 @@ async fn render_to_image(dim: UVec2) {
     renderer.save_result("render_output.png").await;
 }
 
-async fn realtime_render(dim: UVec2) {
+/// Renderer settings of the viewer and replays, from the command line.
+struct Options {
+    view_mode: ViewMode,
+    render_path: RenderPath,
+    /// Path of an equirectangular `.hdr` panorama lighting the scene.
+    environment: Option<String>,
+    sky: bool,
+    fog: bool,
+    bloom: bool,
+    taa: bool,
+}
+
+impl Options {
+    fn apply(&self, renderer: &mut WgpuRenderer) {
+        renderer.set_view_mode(self.view_mode);
+        renderer.set_render_path(self.render_path);
+        renderer.set_sky(self.sky.then(SkyConfig::default));
+        renderer.set_fog(self.fog.then(FogConfig::default));
+        renderer.set_bloom(self.bloom.then(BloomConfig::default));
+        renderer.set_taa(self.taa.then(TaaConfig::default));
+        if let Some(path) = &self.environment {
+            if let Err(err) = renderer.load_environment(path) {
+                log::error!("Failed to load environment {}: {}", path, err);
+            }
+        }
+    }
+}
+
+/// Renders every frame of a recorded camera path to `output_dir`, matching the frames
+/// shown when replaying it in the viewer.
+async fn replay_to_images(dim: UVec2, options: &Options, camera_path: &str, output_dir: &str) {
+    let path = match CameraPath::load(camera_path) {
+        Ok(path) => path,
+        Err(err) => {
+            log::error!("Failed to load camera path {}: {}", camera_path, err);
+            return;
+        }
+    };
+
+    let mut renderer = WgpuImageRenderer::new(
+        dim,
+        ShaderSource::Wgsl(Cow::Borrowed(include_str!("../assets/scene.wgsl"))),
+        // Same encoding as the viewer's surface, with the channel order of PNGs.
+        Some(RendererConfig {
+            primary_target_format: TextureFormat::Rgba8UnormSrgb,
+            ssao: Some(SsaoConfig::default()),
+            transparency: Some(TransparencyMode::Sorted),
+            ..Default::default()
+        }),
+    )
+    .await;
+    options.apply(renderer.renderer_mut());
+    let mut camera = create_camera(dim);
+    renderer.renderer_mut().set_camera(&camera);
+    load_scene(renderer.renderer_mut());
+    std::fs::create_dir_all(output_dir).unwrap();
+
+    for (index, (animation_delta, transform)) in path.frames().enumerate() {
+        camera.transform = transform;
+        renderer.renderer_mut().set_camera(&camera);
+        renderer.renderer_mut().update_animations(animation_delta);
+        renderer.renderer_mut().write_scene();
+
+        renderer.draw().await;
+        renderer
+            .save_result(Path::new(output_dir).join(format!("frame_{:05}.png", index)))
+            .await;
+    }
+}
+
+async fn realtime_render(dim: UVec2, options: &Options) {
     let event_loop = EventLoop::new().unwrap();
     let mut app = Application::new(&event_loop, dim).await;
-    app.run();
+    app.set_max_fps(MAX_FPS);
+    options.apply(app.renderer.renderer_mut());
     event_loop.run_app(&mut app).unwrap();
 }
 
 fn main() {
-    pollster::block_on(realtime_render(WINDOW_DIM));
+    let _ = env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .try_init();
+
+    // `shadow --replay [camera path] [output dir]` renders a recorded camera path
+    // offline instead of opening the viewer, `--view-mode <mode>`, `--deferred`,
+    // `--environment <hdr file>`, `--sky`, `--fog`, `--bloom` and `--taa` work for both.
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let view_mode = match args.iter().position(|a| a == "--view-mode") {
+        Some(index) if index + 1 < args.len() => {
+            let name = args.drain(index..index + 2).next_back().unwrap();
+            name.parse().unwrap_or_else(|err| {
+                log::error!("{}, using the shaded view.", err);
+                ViewMode::Shaded
+            })
+        }
+        _ => ViewMode::Shaded,
+    };
+    let render_path = match args.iter().position(|a| a == "--deferred") {
+        Some(index) => {
+            args.remove(index);
+            RenderPath::Deferred
+        }
+        None => RenderPath::Forward,
+    };
+    let environment = match args.iter().position(|a| a == "--environment") {
+        Some(index) if index + 1 < args.len() => args.drain(index..index + 2).next_back(),
+        _ => None,
+    };
+    let mut flag = |name| match args.iter().position(|a| a == name) {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    };
+    let options = Options {
+        view_mode,
+        render_path,
+        environment,
+        sky: flag("--sky"),
+        fog: flag("--fog"),
+        bloom: flag("--bloom"),
+        taa: flag("--taa"),
+    };
+
+    match args.first().map(String::as_str) {
+        Some("--replay") => pollster::block_on(replay_to_images(
+            WINDOW_DIM,
+            &options,
+            args.get(1).map(String::as_str).unwrap_or(CAMERA_PATH_FILE),
+            args.get(2).map(String::as_str).unwrap_or("replay"),
+        )),
+        _ => pollster::block_on(realtime_render(WINDOW_DIM, &options)),
+    }
 }
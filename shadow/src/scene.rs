@@ -1,14 +1,46 @@
-use glam::{Vec2, Vec3};
-use wgpu_renderer::scene::Camera;
-use winit::{
-    event::{ElementState, MouseButton},
-    keyboard::KeyCode,
-};
+use std::f32::consts::FRAC_PI_2;
+
+use glam::{EulerRot, Quat, Vec2, Vec3};
+use wgpu_renderer::scene::{Aabb, Camera, Transform};
+
+use crate::input::{Action, InputState};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CameraMode {
+    /// WASD movement, rotation while holding the left mouse button.
+    Fly,
+    /// Rotation around a target point with the left mouse button, panning with the
+    /// middle mouse button and zoom with the mouse wheel.
+    Orbit,
+    /// Orbit mode slowly spinning around the target on its own.
+    Turntable,
+}
+
+impl CameraMode {
+    #[inline]
+    fn is_orbiting(self) -> bool {
+        matches!(self, CameraMode::Orbit | CameraMode::Turntable)
+    }
+}
 
 pub struct CameraConfig {
     pub tranl_sensi: f32,
     pub rot_sensi: Vec2,
     pub smoothness: f32,
+    /// Speed multiplier while [`Action::Boost`] is held.
+    pub boost: f32,
+    /// Radians per second at full [`Action::LookLeft`] and its siblings.
+    pub look_speed: f32,
+    /// Radians per pixel.
+    pub orbit_sensi: f32,
+    /// Fraction of the orbit distance per pixel.
+    pub pan_sensi: f32,
+    /// Fraction of the orbit distance per mouse wheel line.
+    pub zoom_sensi: f32,
+    /// Maximum pitch in radians, below a right angle to avoid flipping over.
+    pub pitch_limit: f32,
+    /// Radians per second in turntable mode.
+    pub turntable_speed: f32,
 }
 
 impl Default for CameraConfig {
@@ -17,6 +49,48 @@ impl Default for CameraConfig {
             tranl_sensi: 2.,
             rot_sensi: Vec2::ONE,
             smoothness: 20.,
+            boost: 4.,
+            look_speed: 2.,
+            orbit_sensi: 0.005,
+            pan_sensi: 0.001,
+            zoom_sensi: 0.1,
+            pitch_limit: FRAC_PI_2 - 0.01,
+            turntable_speed: 0.5,
+        }
+    }
+}
+
+/// Yaw and pitch of a camera looking along `forward`, the inverse of
+/// [`Quat::from_euler`] with [`EulerRot::YXZ`] and no roll.
+fn yaw_pitch(forward: Vec3, pitch_limit: f32) -> (f32, f32) {
+    let yaw = (-forward.x).atan2(-forward.z);
+    let pitch = forward.y.asin().clamp(-pitch_limit, pitch_limit);
+    (yaw, pitch)
+}
+
+struct Orbit {
+    target: Vec3,
+    distance: f32,
+    yaw: f32,
+    pitch: f32,
+}
+
+impl Orbit {
+    #[inline]
+    fn orientation(&self) -> Quat {
+        Quat::from_euler(EulerRot::YXZ, self.yaw, self.pitch, 0.)
+    }
+
+    /// The camera transform holds the view matrix, so it's the inverse of the camera's
+    /// placement in the world.
+    fn view_transform(&self) -> Transform {
+        let orientation = self.orientation();
+        let eye = self.target + orientation * Vec3::Z * self.distance;
+        let rotation = orientation.inverse();
+
+        Transform {
+            translation: -(rotation * eye),
+            rotation,
         }
     }
 }
@@ -27,60 +101,181 @@ pub struct ControllableCamera {
     current_vel: Vec3,
     on_rotate: bool,
     mouse_delta: Vec2,
+    // Yaw and pitch in radians to add this frame.
+    look: Vec2,
+    mode: CameraMode,
+    // Rebuilt into the rotation every frame in fly mode rather than composing
+    // rotations, which would accumulate roll.
+    yaw: f32,
+    pitch: f32,
+    orbit: Orbit,
+    on_pan: bool,
+    orbit_delta: Vec2,
+    pan_delta: Vec2,
     pub config: CameraConfig,
 }
 
 impl ControllableCamera {
     pub fn new(camera: Camera, config: CameraConfig) -> Self {
+        let (yaw, pitch) = yaw_pitch(Self::forward(&camera), config.pitch_limit);
         Self {
             camera,
             target_camera: camera,
             current_vel: Vec3::ZERO,
             on_rotate: false,
             mouse_delta: Vec2::ZERO,
+            look: Vec2::ZERO,
+            mode: CameraMode::Fly,
+            yaw,
+            pitch,
+            orbit: Orbit {
+                target: Vec3::ZERO,
+                distance: 10.,
+                yaw: 0.,
+                pitch: 0.,
+            },
+            on_pan: false,
+            orbit_delta: Vec2::ZERO,
+            pan_delta: Vec2::ZERO,
             config,
         }
     }
 
-    pub fn keyboard_control(&mut self, key: KeyCode, state: &ElementState) {
-        let t = match state {
-            ElementState::Pressed => 1.,
-            ElementState::Released => 0.,
-        };
+    #[inline]
+    pub fn mode(&self) -> CameraMode {
+        self.mode
+    }
+
+    /// Direction `camera` looks at in world space.
+    #[inline]
+    fn forward(camera: &Camera) -> Vec3 {
+        camera.transform.rotation.inverse() * Vec3::NEG_Z
+    }
+
+    /// Switching to orbit keeps the current view, orbiting around the point at the
+    /// current orbit distance in front of the camera.
+    pub fn set_mode(&mut self, mode: CameraMode) {
+        if mode == self.mode {
+            return;
+        }
 
-        match key {
-            KeyCode::KeyW => self.current_vel.z = self.config.tranl_sensi * t,
-            KeyCode::KeyS => self.current_vel.z = self.config.tranl_sensi * -t,
-            KeyCode::KeyA => self.current_vel.x = self.config.tranl_sensi * t,
-            KeyCode::KeyD => self.current_vel.x = self.config.tranl_sensi * -t,
-            KeyCode::KeyQ => self.current_vel.y = self.config.tranl_sensi * -t,
-            KeyCode::KeyE => self.current_vel.y = self.config.tranl_sensi * t,
-            _ => {}
+        let forward = Self::forward(&self.camera);
+        if mode.is_orbiting() && !self.mode.is_orbiting() {
+            let orientation = self.camera.transform.rotation.inverse();
+            let eye = -(orientation * self.camera.transform.translation);
+
+            self.orbit.target = eye + forward * self.orbit.distance;
+            (self.orbit.yaw, self.orbit.pitch) = yaw_pitch(forward, self.config.pitch_limit);
+        } else if mode == CameraMode::Fly {
+            (self.yaw, self.pitch) = yaw_pitch(forward, self.config.pitch_limit);
         }
+        self.mode = mode;
     }
 
-    pub fn mouse_control(&mut self, button: MouseButton, state: &ElementState) {
-        let t = match state {
-            ElementState::Pressed => true,
-            ElementState::Released => false,
-        };
+    #[inline]
+    pub fn set_aspect_ratio(&mut self, aspect_ratio: f32) {
+        self.camera.aspect_ratio = aspect_ratio;
+        self.target_camera.aspect_ratio = aspect_ratio;
+    }
+
+    /// Places the camera at `transform` in fly mode, for replaying a recorded path.
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.mode = CameraMode::Fly;
+        self.camera.transform = transform;
+        self.target_camera.transform = transform;
+        (self.yaw, self.pitch) = yaw_pitch(Self::forward(&self.camera), self.config.pitch_limit);
+    }
+
+    /// Cycles through fly, orbit and turntable.
+    pub fn toggle_mode(&mut self) {
+        self.set_mode(match self.mode {
+            CameraMode::Fly => CameraMode::Orbit,
+            CameraMode::Orbit => CameraMode::Turntable,
+            CameraMode::Turntable => CameraMode::Fly,
+        });
+    }
+
+    /// Orbits around the center of `bounds` at a distance that fits them into the view,
+    /// switching to orbit mode from fly mode.
+    pub fn frame(&mut self, bounds: Aabb) {
+        let radius = bounds.half_extents().length().max(0.01);
+        let half_fov_y = self.camera.fov * 0.5;
+        let half_fov_x = (half_fov_y.tan() * self.camera.aspect_ratio).atan();
 
-        match button {
-            MouseButton::Left => {
-                self.mouse_delta = Vec2::ZERO;
-                self.on_rotate = t;
-            }
-            _ => {}
+        if !self.mode.is_orbiting() {
+            self.set_mode(CameraMode::Orbit);
         }
+        self.orbit.target = bounds.center();
+        self.orbit.distance = radius / half_fov_y.min(half_fov_x).sin();
     }
 
     pub fn mouse_move(&mut self, delta: Vec2) {
         if self.on_rotate {
             self.mouse_delta = delta;
+            self.orbit_delta += delta;
+        }
+        if self.on_pan {
+            self.pan_delta += delta;
+        }
+    }
+
+    /// `lines` is positive when scrolling away from the user, which zooms in.
+    pub fn mouse_wheel(&mut self, lines: f32) {
+        if self.mode.is_orbiting() {
+            self.orbit.distance =
+                (self.orbit.distance * (1. - self.config.zoom_sensi).powf(lines)).max(0.01);
         }
     }
 
-    pub fn update(&mut self, delta: f32) {
+    pub fn update(&mut self, delta: f32, input: &InputState) {
+        let boost = match input.is_active(Action::Boost) {
+            true => self.config.boost,
+            false => 1.,
+        };
+        self.current_vel = Vec3::new(
+            input.axis(Action::MoveRight, Action::MoveLeft),
+            input.axis(Action::MoveDown, Action::MoveUp),
+            input.axis(Action::MoveBackward, Action::MoveForward),
+        ) * self.config.tranl_sensi
+            * boost;
+
+        self.look = Vec2::new(
+            input.axis(Action::LookRight, Action::LookLeft),
+            input.axis(Action::LookDown, Action::LookUp),
+        ) * self.config.look_speed
+            * delta;
+
+        self.on_rotate = input.is_active(Action::Rotate);
+        self.on_pan = input.is_active(Action::Pan);
+        if !self.on_rotate {
+            self.mouse_delta = Vec2::ZERO;
+        }
+
+        match self.mode {
+            CameraMode::Fly => self.update_fly(delta),
+            CameraMode::Orbit => self.update_orbit(0.),
+            CameraMode::Turntable => self.update_orbit(self.config.turntable_speed * delta),
+        }
+        self.orbit_delta = Vec2::ZERO;
+        self.pan_delta = Vec2::ZERO;
+    }
+
+    /// `spin` is added to the yaw on top of the mouse rotation.
+    fn update_orbit(&mut self, spin: f32) {
+        let orbit = &mut self.orbit;
+        orbit.yaw += spin + self.look.x - self.orbit_delta.x * self.config.orbit_sensi;
+        orbit.pitch = (orbit.pitch + self.look.y - self.orbit_delta.y * self.config.orbit_sensi)
+            .clamp(-self.config.pitch_limit, self.config.pitch_limit);
+
+        let pan = Vec3::new(-self.pan_delta.x, self.pan_delta.y, 0.);
+        orbit.target += orbit.orientation() * pan * self.config.pan_sensi * orbit.distance;
+
+        // The fly camera continues from here when switching back.
+        self.camera.transform = orbit.view_transform();
+        self.target_camera.transform = self.camera.transform;
+    }
+
+    fn update_fly(&mut self, delta: f32) {
         self.target_camera
             .transform
             .local_move(self.current_vel * self.config.tranl_sensi * delta);
@@ -89,15 +284,14 @@ impl ControllableCamera {
             self.config.smoothness * delta,
         );
 
+        let mut rotation = self.look;
         if self.on_rotate {
-            self.camera.transform.rotate(
-                Vec3::Y,
-                self.mouse_delta.x * delta * self.config.rot_sensi.x,
-            );
-            self.camera.transform.rotate(
-                Vec3::X,
-                self.mouse_delta.y * delta * self.config.rot_sensi.y,
-            );
+            rotation -= self.mouse_delta * delta * self.config.rot_sensi;
         }
+        self.yaw += rotation.x;
+        self.pitch =
+            (self.pitch + rotation.y).clamp(-self.config.pitch_limit, self.config.pitch_limit);
+        self.camera.transform.rotation =
+            Quat::from_euler(EulerRot::YXZ, self.yaw, self.pitch, 0.).inverse();
     }
 }
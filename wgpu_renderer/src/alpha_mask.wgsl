@@ -0,0 +1,23 @@
+// The material bound by `Materials::bind` as group 2, for the passes discarding the
+// fragments of masked meshes.
+
+struct Material {
+    base_color: vec4f,
+    metallic: f32,
+    roughness: f32,
+    // Zero unless the material is masked.
+    alpha_cutoff: f32,
+    emissive: vec3f,
+}
+
+@group(2) @binding(0) var base_color_map: texture_2d<f32>;
+@group(2) @binding(3) var material_sampler: sampler;
+@group(2) @binding(4) var<uniform> material_params: Material;
+
+// Discards the fragment if its alpha is below the cutoff, like the scene shader.
+fn alpha_mask(uv: vec2f) {
+    let alpha = material_params.base_color.a * textureSample(base_color_map, material_sampler, uv).a;
+    if alpha < material_params.alpha_cutoff {
+        discard;
+    }
+}
@@ -0,0 +1,357 @@
+use glam::{Mat4, Quat, Vec3};
+use wgpu::BindGroup;
+
+use crate::{assets::MeshHandle, buffer::StorageBuffer};
+
+#[derive(Debug, Clone, Copy)]
+pub struct JointTransform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl JointTransform {
+    #[inline]
+    pub fn compute_matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Joint {
+    pub name: Option<String>,
+    pub parent: Option<usize>,
+    /// Transform of the non-joint ancestors, for root joints.
+    pub root: Mat4,
+    pub rest: JointTransform,
+    pub inverse_bind: Mat4,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Skeleton {
+    pub joints: Vec<Joint>,
+}
+
+impl Skeleton {
+    pub fn rest_pose(&self) -> Vec<JointTransform> {
+        self.joints.iter().map(|j| j.rest).collect()
+    }
+
+    pub fn joint_matrices(&self, pose: &[JointTransform]) -> Vec<Mat4> {
+        let mut globals = vec![None; self.joints.len()];
+        (0..self.joints.len())
+            .map(|i| self.global_transform(i, pose, &mut globals) * self.joints[i].inverse_bind)
+            .collect()
+    }
+
+    fn global_transform(
+        &self,
+        joint: usize,
+        pose: &[JointTransform],
+        globals: &mut [Option<Mat4>],
+    ) -> Mat4 {
+        if let Some(global) = globals[joint] {
+            return global;
+        }
+
+        let parent = match self.joints[joint].parent {
+            Some(parent) => self.global_transform(parent, pose, globals),
+            None => self.joints[joint].root,
+        };
+        let global = parent * pose[joint].compute_matrix();
+        globals[joint] = Some(global);
+        global
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    CubicSpline,
+}
+
+#[derive(Debug, Clone)]
+pub enum Keyframes {
+    Translation(Vec<Vec3>),
+    Rotation(Vec<Quat>),
+    Scale(Vec<Vec3>),
+}
+
+/// For cubic spline interpolation, every keyframe stores an in-tangent, the value and
+/// an out-tangent, in this order.
+#[derive(Debug, Clone)]
+pub struct AnimationChannel {
+    pub joint: usize,
+    pub interpolation: Interpolation,
+    pub times: Vec<f32>,
+    pub keyframes: Keyframes,
+}
+
+impl AnimationChannel {
+    pub fn sample(&self, time: f32, transform: &mut JointTransform) {
+        let Some(&last) = self.times.last() else {
+            return;
+        };
+
+        let (i, t, dt) = if time <= self.times[0] {
+            (0, 0., 0.)
+        } else if time >= last {
+            (self.times.len() - 1, 0., 0.)
+        } else {
+            let next = self.times.partition_point(|&k| k <= time);
+            let dt = self.times[next] - self.times[next - 1];
+            (next - 1, (time - self.times[next - 1]) / dt, dt)
+        };
+
+        match &self.keyframes {
+            Keyframes::Translation(values) => {
+                transform.translation = self.interpolate(values, i, t, dt, Vec3::lerp)
+            }
+            Keyframes::Scale(values) => {
+                transform.scale = self.interpolate(values, i, t, dt, Vec3::lerp)
+            }
+            Keyframes::Rotation(values) => {
+                transform.rotation = self.interpolate(values, i, t, dt, Quat::slerp).normalize()
+            }
+        }
+    }
+
+    fn interpolate<T>(&self, values: &[T], i: usize, t: f32, dt: f32, lerp: fn(T, T, f32) -> T) -> T
+    where
+        T: Copy + std::ops::Add<Output = T> + std::ops::Mul<f32, Output = T>,
+    {
+        let last = self.times.len() - 1;
+        match self.interpolation {
+            Interpolation::Step => values[i],
+            Interpolation::Linear if i == last => values[i],
+            Interpolation::Linear => lerp(values[i], values[i + 1], t),
+            Interpolation::CubicSpline if i == last => values[i * 3 + 1],
+            Interpolation::CubicSpline => {
+                let (p0, m0) = (values[i * 3 + 1], values[i * 3 + 2] * dt);
+                let (p1, m1) = (values[i * 3 + 4], values[i * 3 + 3] * dt);
+                let (t2, t3) = (t * t, t * t * t);
+                p0 * (2. * t3 - 3. * t2 + 1.)
+                    + m0 * (t3 - 2. * t2 + t)
+                    + p1 * (-2. * t3 + 3. * t2)
+                    + m1 * (t3 - t2)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    pub name: Option<String>,
+    pub duration: f32,
+    pub channels: Vec<AnimationChannel>,
+}
+
+impl AnimationClip {
+    pub fn sample(&self, time: f32, pose: &mut [JointTransform]) {
+        for channel in &self.channels {
+            channel.sample(time, &mut pose[channel.joint]);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationPlayer {
+    pub clip: Option<usize>,
+    pub time: f32,
+    pub speed: f32,
+    pub looping: bool,
+    pub paused: bool,
+}
+
+impl Default for AnimationPlayer {
+    fn default() -> Self {
+        Self {
+            clip: None,
+            time: 0.,
+            speed: 1.,
+            looping: true,
+            paused: false,
+        }
+    }
+}
+
+impl AnimationPlayer {
+    pub fn play(&mut self, clip: usize) {
+        self.clip = Some(clip);
+        self.time = 0.;
+        self.paused = false;
+    }
+
+    pub fn advance(&mut self, delta: f32, duration: f32) {
+        if self.paused {
+            return;
+        }
+
+        self.time += delta * self.speed;
+        if self.looping && duration > 0. {
+            self.time = self.time.rem_euclid(duration);
+        } else {
+            self.time = self.time.clamp(0., duration);
+        }
+    }
+}
+
+pub struct SkinnedModel {
+    pub meshes: Vec<MeshHandle>,
+    pub skeleton: Skeleton,
+    pub animations: Vec<AnimationClip>,
+    pub player: AnimationPlayer,
+    /// Joint matrices of the last update, for picking on the CPU.
+    pub(crate) joint_matrices: Vec<Mat4>,
+    pub(crate) joints: StorageBuffer<Mat4>,
+    pub(crate) bind_group: Option<BindGroup>,
+}
+
+impl SkinnedModel {
+    pub fn new(
+        meshes: Vec<MeshHandle>,
+        skeleton: Skeleton,
+        animations: Vec<AnimationClip>,
+    ) -> Self {
+        let mut player = AnimationPlayer::default();
+        if !animations.is_empty() {
+            player.play(0);
+        }
+
+        Self {
+            meshes,
+            skeleton,
+            animations,
+            player,
+            joint_matrices: Vec::new(),
+            joints: StorageBuffer::default(),
+            bind_group: None,
+        }
+    }
+
+    pub fn find_animation(&self, name: &str) -> Option<usize> {
+        self.animations
+            .iter()
+            .position(|a| a.name.as_deref() == Some(name))
+    }
+
+    /// Advances the animation and computes the current joint matrices.
+    pub fn update(&mut self, delta: f32) -> Vec<Mat4> {
+        let mut pose = self.skeleton.rest_pose();
+        if let Some(clip) = self.player.clip.and_then(|c| self.animations.get(c)) {
+            self.player.advance(delta, clip.duration);
+            clip.sample(self.player.time, &mut pose);
+        }
+
+        self.skeleton.joint_matrices(&pose)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    fn channel(
+        interpolation: Interpolation,
+        times: &[f32],
+        keyframes: Keyframes,
+    ) -> AnimationChannel {
+        AnimationChannel {
+            joint: 0,
+            interpolation,
+            times: times.to_vec(),
+            keyframes,
+        }
+    }
+
+    fn sample(channel: &AnimationChannel, time: f32) -> JointTransform {
+        let mut transform = JointTransform {
+            translation: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+        };
+        channel.sample(time, &mut transform);
+        transform
+    }
+
+    fn translations(channel: &AnimationChannel, times: &[f32]) -> Vec<f32> {
+        times
+            .iter()
+            .map(|&time| sample(channel, time).translation.x)
+            .collect()
+    }
+
+    #[test]
+    fn sample_step() {
+        let values = vec![Vec3::X, Vec3::X * 2., Vec3::X * 3.];
+        let channel = channel(
+            Interpolation::Step,
+            &[0., 1., 2.],
+            Keyframes::Translation(values),
+        );
+        assert_eq!(
+            translations(&channel, &[-1., 0., 0.5, 1., 1.5, 2., 3.]),
+            [1., 1., 1., 2., 2., 3., 3.]
+        );
+    }
+
+    #[test]
+    fn sample_linear() {
+        let values = vec![Vec3::ONE, Vec3::ONE * 3.];
+        let channel = channel(Interpolation::Linear, &[1., 3.], Keyframes::Scale(values));
+        let scales = [0., 1., 1.5, 2., 3., 4.].map(|time| sample(&channel, time).scale);
+        assert_eq!(scales, [1., 1., 1.5, 2., 3., 3.].map(Vec3::splat));
+    }
+
+    #[test]
+    fn sample_linear_rotation() {
+        let end = Quat::from_rotation_z(FRAC_PI_2);
+        let values = vec![Quat::IDENTITY, end];
+        let channel = channel(
+            Interpolation::Linear,
+            &[0., 1.],
+            Keyframes::Rotation(values),
+        );
+        let rotation = sample(&channel, 0.5).rotation;
+        assert!(rotation.angle_between(Quat::from_rotation_z(FRAC_PI_2 / 2.)) < 1e-5);
+        assert!(sample(&channel, 2.).rotation.angle_between(end) < 1e-5);
+    }
+
+    #[test]
+    fn sample_cubic_spline() {
+        // In-tangent, value and out-tangent of two keyframes 2 seconds apart, leaving the
+        // first at 1 unit per second and arriving at the second at rest.
+        let values = vec![
+            Vec3::ZERO,
+            Vec3::ZERO,
+            Vec3::X,
+            Vec3::ZERO,
+            Vec3::X * 2.,
+            Vec3::ZERO,
+        ];
+        let channel = channel(
+            Interpolation::CubicSpline,
+            &[1., 3.],
+            Keyframes::Translation(values),
+        );
+        // At the midpoint, the out-tangent scaled by the 2 seconds adds 2 / 8 to the
+        // average of the values.
+        assert_eq!(
+            translations(&channel, &[0., 1., 2., 3., 4.]),
+            [0., 0., 1.25, 2., 2.]
+        );
+    }
+
+    #[test]
+    fn sample_empty_channel() {
+        let channel = channel(
+            Interpolation::Linear,
+            &[],
+            Keyframes::Translation(Vec::new()),
+        );
+        assert_eq!(sample(&channel, 1.).translation, Vec3::ZERO);
+    }
+}
@@ -0,0 +1,647 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, ErrorKind, Read},
+    path::Path,
+    sync::Arc,
+};
+
+use glam::{Mat3, Mat4, Quat, UVec2, Vec2, Vec3, Vec4};
+use wgpu::{util::*, *};
+
+use crate::{
+    animation::{
+        AnimationChannel, AnimationClip, Interpolation, Joint, JointTransform, Keyframes, Skeleton,
+        SkinnedModel,
+    },
+    render::{AlphaMode, GpuMesh, Material, MaterialTextures, Vertex},
+    texture::{
+        expand_to_rgba, ColorSpace, MipmapGenerator, SamplerCache, SamplerOptions, TextureImage,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MeshHandle(u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureHandle(u32);
+
+pub struct GpuTexture {
+    pub texture: Texture,
+    pub view: TextureView,
+    desc: TextureDescriptor<'static>,
+    // Only the first mip if the others are generated.
+    data: Vec<u8>,
+    generated_mips: bool,
+}
+
+impl GpuTexture {
+    /// `data` holds every mip of `desc`.
+    pub fn new(
+        device: &Device,
+        queue: &Queue,
+        desc: TextureDescriptor<'static>,
+        data: Vec<u8>,
+    ) -> Self {
+        let texture =
+            device.create_texture_with_data(queue, &desc, TextureDataOrder::LayerMajor, &data);
+        let view = texture.create_view(&TextureViewDescriptor::default());
+
+        Self {
+            texture,
+            view,
+            desc,
+            data,
+            generated_mips: false,
+        }
+    }
+
+    /// `data` holds the first mip, the others are rendered from it.
+    fn with_generated_mips(
+        device: &Device,
+        queue: &Queue,
+        mipmap_generator: &mut MipmapGenerator,
+        desc: TextureDescriptor<'static>,
+        data: Vec<u8>,
+    ) -> Self {
+        let texture = device.create_texture(&desc);
+        let texel_size = desc.format.block_copy_size(None).unwrap_or(4);
+        queue.write_texture(
+            ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            &data,
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(desc.size.width * texel_size),
+                rows_per_image: None,
+            },
+            desc.size,
+        );
+        mipmap_generator.generate(device, queue, &texture);
+        let view = texture.create_view(&TextureViewDescriptor::default());
+
+        Self {
+            texture,
+            view,
+            desc,
+            data,
+            generated_mips: true,
+        }
+    }
+}
+
+/// Converts the decoded image of a glTF texture to 8 bit RGBA, float images aren't
+/// supported.
+fn gltf_image(data: &gltf::image::Data, color_space: ColorSpace) -> Option<TextureImage> {
+    use gltf::image::Format;
+
+    let (channels, wide) = match data.format {
+        Format::R8 => (1, false),
+        Format::R8G8 => (2, false),
+        Format::R8G8B8 => (3, false),
+        Format::R8G8B8A8 => (4, false),
+        Format::R16 => (1, true),
+        Format::R16G16 => (2, true),
+        Format::R16G16B16 => (3, true),
+        Format::R16G16B16A16 => (4, true),
+        Format::R32G32B32FLOAT | Format::R32G32B32A32FLOAT => return None,
+    };
+    let pixels = match wide {
+        true => data
+            .pixels
+            .chunks_exact(2)
+            .map(|c| (u16::from_ne_bytes([c[0], c[1]]) >> 8) as u8)
+            .collect(),
+        false => data.pixels.clone(),
+    };
+    Some(TextureImage::from_rgba8(
+        UVec2::new(data.width, data.height),
+        expand_to_rgba(&pixels, channels),
+        color_space,
+    ))
+}
+
+fn gltf_sampler(sampler: gltf::texture::Sampler) -> SamplerOptions {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+
+    let address_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => AddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => AddressMode::MirrorRepeat,
+        WrappingMode::Repeat => AddressMode::Repeat,
+    };
+    let (min_filter, mipmap_filter) = match sampler.min_filter() {
+        Some(MinFilter::Nearest | MinFilter::NearestMipmapNearest) => {
+            (FilterMode::Nearest, FilterMode::Nearest)
+        }
+        Some(MinFilter::NearestMipmapLinear) => (FilterMode::Nearest, FilterMode::Linear),
+        Some(MinFilter::LinearMipmapNearest) => (FilterMode::Linear, FilterMode::Nearest),
+        Some(MinFilter::Linear | MinFilter::LinearMipmapLinear) | None => {
+            (FilterMode::Linear, FilterMode::Linear)
+        }
+    };
+
+    SamplerOptions {
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+        mag_filter: match sampler.mag_filter() {
+            Some(MagFilter::Nearest) => FilterMode::Nearest,
+            Some(MagFilter::Linear) | None => FilterMode::Linear,
+        },
+        min_filter,
+        mipmap_filter,
+        anisotropy_clamp: 1,
+    }
+}
+
+/// The images of a glTF file, uploaded as materials use them.
+struct GltfImages {
+    images: Vec<gltf::image::Data>,
+    // Once per color space, an image could be read as color and as data.
+    uploaded: HashMap<(usize, ColorSpace), TextureHandle>,
+}
+
+impl GltfImages {
+    fn texture(
+        &mut self,
+        assets: &mut AssetRegistry,
+        device: &Device,
+        queue: &Queue,
+        texture: gltf::Texture,
+        color_space: ColorSpace,
+    ) -> Option<TextureHandle> {
+        let index = texture.source().index();
+        if let Some(&handle) = self.uploaded.get(&(index, color_space)) {
+            return Some(handle);
+        }
+
+        let Some(image) = gltf_image(&self.images[index], color_space) else {
+            log::warn!("Skipping float glTF image {}.", index);
+            return None;
+        };
+        let handle = match assets.add_image(device, queue, image) {
+            Ok(handle) => handle,
+            Err(err) => {
+                log::warn!("Skipping glTF image {}: {}", index, err);
+                return None;
+            }
+        };
+        self.uploaded.insert((index, color_space), handle);
+        Some(handle)
+    }
+}
+
+/// Meshes and textures uploaded to a [`crate::context::GpuContext`], shared by every
+/// renderer using that context.
+#[derive(Default)]
+pub struct AssetRegistry {
+    meshes: HashMap<MeshHandle, GpuMesh>,
+    textures: HashMap<TextureHandle, GpuTexture>,
+    samplers: SamplerCache,
+    // Created with the first texture needing it.
+    mipmap_generator: Option<MipmapGenerator>,
+    next_id: u32,
+}
+
+impl AssetRegistry {
+    pub fn add_mesh(
+        &mut self,
+        device: &Device,
+        name: Option<String>,
+        material: Material,
+        vertices: Vec<Vertex>,
+    ) -> MeshHandle {
+        let handle = MeshHandle(self.next_id());
+        self.meshes
+            .insert(handle, GpuMesh::new(device, name, material, vertices));
+        handle
+    }
+
+    #[inline]
+    pub fn mesh(&self, handle: MeshHandle) -> Option<&GpuMesh> {
+        self.meshes.get(&handle)
+    }
+
+    #[inline]
+    pub fn mesh_mut(&mut self, handle: MeshHandle) -> Option<&mut GpuMesh> {
+        self.meshes.get_mut(&handle)
+    }
+
+    #[inline]
+    pub fn remove_mesh(&mut self, handle: MeshHandle) -> Option<GpuMesh> {
+        self.meshes.remove(&handle)
+    }
+
+    pub fn add_texture(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        desc: TextureDescriptor<'static>,
+        data: Vec<u8>,
+    ) -> TextureHandle {
+        let handle = TextureHandle(self.next_id());
+        self.textures
+            .insert(handle, GpuTexture::new(device, queue, desc, data));
+        handle
+    }
+
+    /// Uploads `image`, generating its mips on the GPU unless it has them already or
+    /// its format can't be rendered to. Fails if it's larger than the maximum 2D texture
+    /// size of the device.
+    pub fn add_image(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        image: TextureImage,
+    ) -> io::Result<TextureHandle> {
+        let max_dim = device.limits().max_texture_dimension_2d;
+        if image.dim.max_element() > max_dim {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Texture of {}x{} exceeds the maximum texture size of {}",
+                    image.dim.x, image.dim.y, max_dim
+                ),
+            ));
+        }
+
+        let desc = image.descriptor();
+        let texture = match desc.mip_level_count > image.mip_level_count {
+            true => GpuTexture::with_generated_mips(
+                device,
+                queue,
+                self.mipmap_generator
+                    .get_or_insert_with(|| MipmapGenerator::new(device)),
+                desc,
+                image.data,
+            ),
+            false => GpuTexture::new(device, queue, desc, image.data),
+        };
+        let handle = TextureHandle(self.next_id());
+        self.textures.insert(handle, texture);
+        Ok(handle)
+    }
+
+    /// Loads a PNG, JPEG or KTX2 file with [`AssetRegistry::add_image`].
+    pub fn load_texture(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        path: impl AsRef<Path>,
+        color_space: ColorSpace,
+    ) -> io::Result<TextureHandle> {
+        let image = TextureImage::load(path, color_space)?;
+        self.add_image(device, queue, image)
+    }
+
+    #[inline]
+    pub fn texture(&self, handle: TextureHandle) -> Option<&GpuTexture> {
+        self.textures.get(&handle)
+    }
+
+    #[inline]
+    pub fn remove_texture(&mut self, handle: TextureHandle) -> Option<GpuTexture> {
+        self.textures.remove(&handle)
+    }
+
+    /// Shared by every texture sampled with the same `options`, for materials and
+    /// custom shaders.
+    #[inline]
+    pub fn sampler(&mut self, device: &Device, options: SamplerOptions) -> Arc<Sampler> {
+        self.samplers.get(device, options)
+    }
+
+    pub fn load_obj(&mut self, device: &Device, path: impl AsRef<Path>) -> Vec<MeshHandle> {
+        let mut source = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut source).unwrap();
+        let obj = obj::ObjData::load_buf(&source[..]).unwrap();
+
+        let mut handles = Vec::new();
+        let mut vertices = Vec::new();
+        for object in obj.objects {
+            for group in object.groups {
+                for poly in group.polys {
+                    for end_index in 2..poly.0.len() {
+                        for &index in &[0, end_index - 1, end_index] {
+                            let obj::IndexTuple(position_id, Some(texture_id), Some(normal_id)) =
+                                poly.0[index]
+                            else {
+                                unreachable!()
+                            };
+
+                            vertices.push(Vertex {
+                                position: obj.position[position_id].into(),
+                                normal: obj.normal[normal_id].into(),
+                                joints: [0; 4],
+                                weights: [0.; 4],
+                                uv: obj.texture[texture_id].into(),
+                            });
+                        }
+                    }
+                }
+
+                let name = format!("{}/{}", object.name, group.name);
+                handles.push(self.add_mesh(
+                    device,
+                    Some(name),
+                    Material::default(),
+                    std::mem::take(&mut vertices),
+                ));
+            }
+        }
+
+        handles
+    }
+
+    /// Meshes of skinned nodes are returned as part of a [`SkinnedModel`] per skin, and
+    /// the transforms of all other meshes are baked into their vertices. Textures used
+    /// by materials are uploaded too, reading the first UV set.
+    pub fn load_gltf(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        path: impl AsRef<Path>,
+    ) -> Result<(Vec<MeshHandle>, Vec<SkinnedModel>), gltf::Error> {
+        let (document, buffers, images) = gltf::import(path)?;
+        let mut images = GltfImages {
+            images,
+            uploaded: HashMap::new(),
+        };
+        let get_buffer = |buffer: gltf::Buffer| Some(&*buffers[buffer.index()]);
+
+        let mut parents = vec![None; document.nodes().len()];
+        for node in document.nodes() {
+            for child in node.children() {
+                parents[child.index()] = Some(node.index());
+            }
+        }
+        let locals = document
+            .nodes()
+            .map(|n| Mat4::from_cols_array_2d(&n.transform().matrix()))
+            .collect::<Vec<_>>();
+        let global_transform = |mut node: usize| {
+            let mut transform = locals[node];
+            while let Some(parent) = parents[node] {
+                transform = locals[parent] * transform;
+                node = parent;
+            }
+            transform
+        };
+
+        let mut skinned_models = document
+            .skins()
+            .map(|skin| {
+                let joint_nodes = skin.joints().map(|j| j.index()).collect::<Vec<_>>();
+                let inverse_binds = skin
+                    .reader(get_buffer)
+                    .read_inverse_bind_matrices()
+                    .map(|m| m.map(|m| Mat4::from_cols_array_2d(&m)).collect::<Vec<_>>())
+                    .unwrap_or_else(|| vec![Mat4::IDENTITY; joint_nodes.len()]);
+
+                let joints = skin
+                    .joints()
+                    .zip(inverse_binds)
+                    .map(|(node, inverse_bind)| {
+                        let parent_node = parents[node.index()];
+                        let parent =
+                            parent_node.and_then(|p| joint_nodes.iter().position(|&j| j == p));
+                        let (translation, rotation, scale) = node.transform().decomposed();
+
+                        Joint {
+                            name: node.name().map(str::to_owned),
+                            parent,
+                            root: match (parent, parent_node) {
+                                (None, Some(p)) => global_transform(p),
+                                _ => Mat4::IDENTITY,
+                            },
+                            rest: JointTransform {
+                                translation: translation.into(),
+                                rotation: Quat::from_array(rotation),
+                                scale: scale.into(),
+                            },
+                            inverse_bind,
+                        }
+                    })
+                    .collect();
+
+                SkinnedModel::new(Vec::new(), Skeleton { joints }, Vec::new())
+            })
+            .collect::<Vec<_>>();
+
+        let mut static_meshes = Vec::new();
+        for node in document.nodes() {
+            let Some(mesh) = node.mesh() else {
+                continue;
+            };
+
+            // Skinned vertices are transformed by their joints only.
+            let transform = match node.skin() {
+                Some(_) => Mat4::IDENTITY,
+                None => global_transform(node.index()),
+            };
+            let normal_transform = Mat3::from_mat4(transform).inverse().transpose();
+
+            for primitive in mesh.primitives() {
+                let reader = primitive.reader(get_buffer);
+                let Some(positions) = reader.read_positions() else {
+                    continue;
+                };
+                let positions = positions.collect::<Vec<_>>();
+                let normals = reader
+                    .read_normals()
+                    .map(|n| n.collect::<Vec<_>>())
+                    .unwrap_or_else(|| vec![[0.; 3]; positions.len()]);
+                let joints = reader
+                    .read_joints(0)
+                    .map(|j| j.into_u16().collect::<Vec<_>>())
+                    .unwrap_or_else(|| vec![[0; 4]; positions.len()]);
+                let weights = reader
+                    .read_weights(0)
+                    .map(|w| w.into_f32().collect::<Vec<_>>())
+                    .unwrap_or_else(|| vec![[0.; 4]; positions.len()]);
+                let uvs = reader
+                    .read_tex_coords(0)
+                    .map(|t| t.into_f32().collect::<Vec<_>>())
+                    .unwrap_or_else(|| vec![[0.; 2]; positions.len()]);
+                let indices = reader
+                    .read_indices()
+                    .map(|i| i.into_u32().collect::<Vec<_>>())
+                    .unwrap_or_else(|| (0..positions.len() as u32).collect());
+
+                let vertices = indices
+                    .into_iter()
+                    .map(|i| i as usize)
+                    .map(|i| Vertex {
+                        position: transform.transform_point3(positions[i].into()),
+                        normal: (normal_transform * Vec3::from(normals[i])).normalize_or_zero(),
+                        joints: joints[i],
+                        weights: weights[i],
+                        uv: Vec2::from(uvs[i]),
+                    })
+                    .collect();
+
+                let gltf_material = primitive.material();
+                let pbr = gltf_material.pbr_metallic_roughness();
+                let base_color_texture = pbr.base_color_texture().map(|t| t.texture());
+                let metallic_roughness_texture =
+                    pbr.metallic_roughness_texture().map(|t| t.texture());
+                let emissive_texture = gltf_material.emissive_texture().map(|t| t.texture());
+                // Materials have a single sampler, the one of their first texture.
+                let sampler = [
+                    &base_color_texture,
+                    &metallic_roughness_texture,
+                    &emissive_texture,
+                ]
+                .into_iter()
+                .flatten()
+                .next()
+                .map(|t| gltf_sampler(t.sampler()))
+                .unwrap_or_default();
+                let mut texture = |texture: Option<gltf::Texture>, color_space| {
+                    images.texture(self, device, queue, texture?, color_space)
+                };
+
+                let material = Material {
+                    base_color: Vec4::from(pbr.base_color_factor()),
+                    metallic: pbr.metallic_factor(),
+                    roughness: pbr.roughness_factor(),
+                    emissive: Vec3::from(gltf_material.emissive_factor()),
+                    textures: MaterialTextures {
+                        base_color: texture(base_color_texture, ColorSpace::Srgb),
+                        metallic_roughness: texture(metallic_roughness_texture, ColorSpace::Linear),
+                        emissive: texture(emissive_texture, ColorSpace::Srgb),
+                        sampler,
+                    },
+                    alpha_mode: match gltf_material.alpha_mode() {
+                        gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+                        gltf::material::AlphaMode::Mask => AlphaMode::Mask {
+                            cutoff: gltf_material.alpha_cutoff().unwrap_or(0.5),
+                        },
+                        gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+                    },
+                };
+                let handle =
+                    self.add_mesh(device, mesh.name().map(str::to_owned), material, vertices);
+                match node.skin() {
+                    Some(skin) => skinned_models[skin.index()].meshes.push(handle),
+                    None => static_meshes.push(handle),
+                }
+            }
+        }
+
+        for animation in document.animations() {
+            let mut clips = vec![
+                AnimationClip {
+                    name: animation.name().map(str::to_owned),
+                    duration: 0.,
+                    channels: Vec::new(),
+                };
+                skinned_models.len()
+            ];
+
+            for channel in animation.channels() {
+                let reader = channel.reader(get_buffer);
+                let (Some(inputs), Some(outputs)) = (reader.read_inputs(), reader.read_outputs())
+                else {
+                    continue;
+                };
+                let keyframes = match outputs {
+                    gltf::animation::util::ReadOutputs::Translations(t) => {
+                        Keyframes::Translation(t.map(Vec3::from).collect())
+                    }
+                    gltf::animation::util::ReadOutputs::Rotations(r) => {
+                        Keyframes::Rotation(r.into_f32().map(Quat::from_array).collect())
+                    }
+                    gltf::animation::util::ReadOutputs::Scales(s) => {
+                        Keyframes::Scale(s.map(Vec3::from).collect())
+                    }
+                    gltf::animation::util::ReadOutputs::MorphTargetWeights(_) => continue,
+                };
+                let interpolation = match channel.sampler().interpolation() {
+                    gltf::animation::Interpolation::Step => Interpolation::Step,
+                    gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                    gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+                };
+                let times = inputs.collect::<Vec<_>>();
+                let target = channel.target().node().index();
+
+                for (skin, clip) in document.skins().zip(&mut clips) {
+                    let Some(joint) = skin.joints().position(|j| j.index() == target) else {
+                        continue;
+                    };
+
+                    clip.duration = clip.duration.max(times.last().copied().unwrap_or(0.));
+                    clip.channels.push(AnimationChannel {
+                        joint,
+                        interpolation,
+                        times: times.clone(),
+                        keyframes: keyframes.clone(),
+                    });
+                }
+            }
+
+            for (model, clip) in skinned_models.iter_mut().zip(clips) {
+                if !clip.channels.is_empty() {
+                    model.animations.push(clip);
+                }
+            }
+        }
+
+        for model in &mut skinned_models {
+            if !model.animations.is_empty() {
+                model.player.play(0);
+            }
+        }
+
+        Ok((static_meshes, skinned_models))
+    }
+
+    /// Uploads every asset of `other` again using `device`, keeping the handles valid.
+    pub fn reupload(&mut self, device: &Device, queue: &Queue, other: &mut AssetRegistry) {
+        self.next_id = other.next_id;
+        self.meshes = other
+            .meshes
+            .drain()
+            .map(|(handle, mesh)| {
+                let mut new_mesh = GpuMesh::new(device, mesh.name, mesh.material, mesh.vertices);
+                new_mesh.set_attributes(device, mesh.attributes);
+                (handle, new_mesh)
+            })
+            .collect();
+        self.textures = other
+            .textures
+            .drain()
+            .map(|(handle, texture)| {
+                let texture = match texture.generated_mips {
+                    true => GpuTexture::with_generated_mips(
+                        device,
+                        queue,
+                        self.mipmap_generator
+                            .get_or_insert_with(|| MipmapGenerator::new(device)),
+                        texture.desc,
+                        texture.data,
+                    ),
+                    false => GpuTexture::new(device, queue, texture.desc, texture.data),
+                };
+                (handle, texture)
+            })
+            .collect();
+    }
+
+    pub fn buffer_memory(&self, meshes: &[MeshHandle]) -> u64 {
+        meshes
+            .iter()
+            .filter_map(|h| self.mesh(*h))
+            .map(|m| m.vertex_buf.size() + m.attribute_buf.as_ref().map_or(0, Buffer::size))
+            .sum()
+    }
+
+    #[inline]
+    fn next_id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
@@ -0,0 +1,166 @@
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use glam::{Vec3, Vec4};
+use wgpu::*;
+
+use crate::render::ShaderData;
+
+/// Used when the scene has no directional light to take the sun direction from.
+const DEFAULT_SUN_DIRECTION: Vec3 = Vec3::new(0., -1., 0.);
+/// Keeps the sun above the horizon, where the Preetham model breaks down.
+const MAX_SUN_ZENITH_ANGLE: f32 = FRAC_PI_2 - 0.01;
+
+/// The analytic daylight model of Preetham et al., lit by the sun in the direction of
+/// the first [`crate::scene::DirectionalLight`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SkyConfig {
+    /// Haziness of the air, from about 2 for a clear sky to 10 for a hazy one.
+    pub turbidity: f32,
+    /// Scales the luminance of the model, which is in thousands of cd/m².
+    pub intensity: f32,
+}
+
+impl Default for SkyConfig {
+    fn default() -> Self {
+        Self {
+            turbidity: 2.5,
+            intensity: 0.1,
+        }
+    }
+}
+
+/// Fog thinning out exponentially with the height above `base_height`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FogConfig {
+    /// Linear RGB.
+    pub color: Vec3,
+    /// Extinction per unit of distance at `base_height`.
+    pub density: f32,
+    /// How quickly the density decreases with the height.
+    pub height_falloff: f32,
+    pub base_height: f32,
+}
+
+impl Default for FogConfig {
+    fn default() -> Self {
+        Self {
+            color: Vec3::new(0.5, 0.6, 0.7),
+            density: 0.02,
+            height_falloff: 0.2,
+            base_height: 0.,
+        }
+    }
+}
+
+/// Coefficients of the Perez distribution for the luminance and both chromaticities.
+fn perez_coefficients(turbidity: f32) -> [Vec3; 5] {
+    let t = turbidity;
+    [
+        Vec3::new(
+            0.1787 * t - 1.4630,
+            -0.0193 * t - 0.2592,
+            -0.0167 * t - 0.2608,
+        ),
+        Vec3::new(
+            -0.3554 * t + 0.4275,
+            -0.0665 * t + 0.0008,
+            -0.0950 * t + 0.0092,
+        ),
+        Vec3::new(
+            -0.0227 * t + 5.3251,
+            -0.0004 * t + 0.2125,
+            -0.0079 * t + 0.2102,
+        ),
+        Vec3::new(
+            0.1206 * t - 2.5771,
+            -0.0641 * t - 0.8989,
+            -0.0441 * t - 1.6537,
+        ),
+        Vec3::new(
+            -0.0670 * t + 0.3703,
+            -0.0033 * t + 0.0452,
+            -0.0109 * t + 0.0529,
+        ),
+    ]
+}
+
+/// Luminance and chromaticity of the zenith for a sun at `sun_theta` from it.
+fn zenith_luminance(turbidity: f32, sun_theta: f32) -> Vec3 {
+    let t = turbidity;
+    let chi = (4. / 9. - t / 120.) * (PI - 2. * sun_theta);
+    let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+    let turbidities = Vec3::new(t * t, t, 1.);
+    let thetas = Vec4::new(sun_theta.powi(3), sun_theta.powi(2), sun_theta, 1.);
+    let chromaticity = |rows: [Vec4; 3]| {
+        turbidities.dot(Vec3::new(
+            rows[0].dot(thetas),
+            rows[1].dot(thetas),
+            rows[2].dot(thetas),
+        ))
+    };
+    let x = chromaticity([
+        Vec4::new(0.00166, -0.00375, 0.00209, 0.),
+        Vec4::new(-0.02903, 0.06377, -0.03202, 0.00394),
+        Vec4::new(0.11693, -0.21196, 0.06052, 0.25886),
+    ]);
+    let y = chromaticity([
+        Vec4::new(0.00275, -0.00610, 0.00317, 0.),
+        Vec4::new(-0.04214, 0.08970, -0.04153, 0.00516),
+        Vec4::new(0.15346, -0.26756, 0.06670, 0.26688),
+    ]);
+    Vec3::new(luminance, x, y)
+}
+
+/// Sky and fog settings for the scene and skybox shaders, bound at binding 15.
+pub(crate) struct GpuAtmosphere {
+    pub sky: Option<SkyConfig>,
+    pub fog: Option<FogConfig>,
+    /// Direction the sunlight travels in.
+    pub sun_direction: Option<Vec3>,
+}
+
+impl ShaderData for GpuAtmosphere {
+    fn as_raw(&self) -> Vec<u8> {
+        let sky = self.sky.unwrap_or_default();
+        let to_sun = -self
+            .sun_direction
+            .unwrap_or(DEFAULT_SUN_DIRECTION)
+            .normalize();
+        // Keeps the azimuth of a sun below the horizon.
+        let sun_theta = to_sun.y.clamp(-1., 1.).acos().min(MAX_SUN_ZENITH_ANGLE);
+        let horizontal = Vec3::new(to_sun.x, 0., to_sun.z).normalize_or_zero();
+        let to_sun = horizontal * sun_theta.sin() + Vec3::Y * sun_theta.cos();
+        let fog = self.fog.unwrap_or_default();
+
+        let mut buf = Vec::with_capacity(144);
+        for coefficient in perez_coefficients(sky.turbidity) {
+            buf.extend_from_slice(bytemuck::cast_slice(coefficient.extend(0.).as_ref()));
+        }
+        let zenith = zenith_luminance(sky.turbidity, sun_theta);
+        buf.extend_from_slice(bytemuck::cast_slice(zenith.as_ref()));
+        buf.extend_from_slice(bytemuck::bytes_of(&(self.sky.is_some() as u32)));
+        buf.extend_from_slice(bytemuck::cast_slice(to_sun.as_ref()));
+        buf.extend_from_slice(bytemuck::bytes_of(&sky.intensity));
+        buf.extend_from_slice(bytemuck::cast_slice(fog.color.as_ref()));
+        buf.extend_from_slice(bytemuck::bytes_of(&fog.density));
+        buf.extend_from_slice(bytemuck::bytes_of(&fog.height_falloff));
+        buf.extend_from_slice(bytemuck::bytes_of(&fog.base_height));
+        buf.extend_from_slice(bytemuck::bytes_of(&(self.fog.is_some() as u32)));
+        buf.extend_from_slice(&[0; 4]);
+        buf
+    }
+
+    fn min_binding_size() -> Option<std::num::NonZeroU64> {
+        std::num::NonZeroU64::new(144)
+    }
+}
+
+pub(crate) fn create_atmosphere_uniform(device: &Device) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: None,
+        size: GpuAtmosphere::min_binding_size().unwrap().get(),
+        usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
@@ -0,0 +1,381 @@
+use std::borrow::Cow;
+
+use glam::UVec2;
+use wgpu::*;
+
+use crate::{render::ShaderData, stats::GpuTimer};
+
+/// Format of the scene target while bloom is enabled.
+pub const HDR_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+/// Of the downsampled chain, the first being half the size of the target.
+const MAX_BLOOM_MIPS: u32 = 6;
+/// Size below which no further mips are created.
+const MIN_MIP_SIZE: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BloomConfig {
+    /// Brightness above which pixels start to glow, 1 being white on the screen.
+    pub threshold: f32,
+    /// Scales the blurred highlights added to the scene.
+    pub intensity: f32,
+}
+
+impl Default for BloomConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 1.,
+            intensity: 0.1,
+        }
+    }
+}
+
+struct BloomUniform<'a> {
+    config: &'a BloomConfig,
+    enabled: bool,
+}
+
+impl ShaderData for BloomUniform<'_> {
+    fn as_raw(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16);
+        buf.extend_from_slice(bytemuck::bytes_of(&self.config.threshold));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.config.intensity));
+        buf.extend_from_slice(bytemuck::bytes_of(&(self.enabled as u32)));
+        buf.extend_from_slice(&[0; 4]);
+        buf
+    }
+}
+
+fn create_target(device: &Device, dim: UVec2, format: TextureFormat) -> TextureView {
+    device
+        .create_texture(&TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: dim.x,
+                height: dim.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        })
+        .create_view(&TextureViewDescriptor::default())
+}
+
+fn create_fullscreen_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    entry_point: &str,
+    target: ColorTargetState,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: PipelineCompilationOptions::default(),
+            buffers: &[],
+        },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point,
+            compilation_options: PipelineCompilationOptions::default(),
+            targets: &[Some(target)],
+        }),
+        primitive: PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+/// Screen sized textures, recreated when the target is resized.
+struct BloomTargets {
+    scene_view: TextureView,
+    // Each half the size of the previous one. Separate textures rather than the mips of
+    // one, as every pass reads one of them while rendering to the next.
+    mip_views: Vec<TextureView>,
+    prefilter_bind_group: BindGroup,
+    // Reading the mip at the same index.
+    mip_bind_groups: Vec<BindGroup>,
+    composite_bind_group: BindGroup,
+}
+
+/// Renders the scene into an HDR target, blurs what's brighter than the threshold over
+/// a chain of downsampled targets and composites the result over the scene while
+/// tonemapping it into the color target passed to [`crate::WgpuRenderer::draw`].
+pub(crate) struct Bloom {
+    config: BloomConfig,
+    uniform: Buffer,
+    sampler: Sampler,
+    layout: BindGroupLayout,
+    pipeline_layout: PipelineLayout,
+    shader_module: ShaderModule,
+    prefilter_pipeline: RenderPipeline,
+    downsample_pipeline: RenderPipeline,
+    upsample_pipeline: RenderPipeline,
+    composite_pipeline: RenderPipeline,
+    targets: BloomTargets,
+}
+
+impl Bloom {
+    pub fn new(device: &Device, format: TextureFormat, dim: UVec2, config: BloomConfig) -> Self {
+        let uniform = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: 16,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: None,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let texture_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: true },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture_entry(1),
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+                texture_entry(3),
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            ..Default::default()
+        });
+
+        let shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("bloom.wgsl"))),
+        });
+        let create_pipeline = |entry_point, target| {
+            create_fullscreen_pipeline(
+                device,
+                &pipeline_layout,
+                &shader_module,
+                entry_point,
+                target,
+            )
+        };
+        let prefilter_pipeline = create_pipeline("fragment_prefilter", HDR_FORMAT.into());
+        let downsample_pipeline = create_pipeline("fragment_downsample", HDR_FORMAT.into());
+        let upsample_pipeline = create_pipeline(
+            "fragment_upsample",
+            ColorTargetState {
+                format: HDR_FORMAT,
+                blend: Some(BlendState {
+                    color: BlendComponent {
+                        src_factor: BlendFactor::One,
+                        dst_factor: BlendFactor::One,
+                        operation: BlendOperation::Add,
+                    },
+                    alpha: BlendComponent::REPLACE,
+                }),
+                write_mask: ColorWrites::ALL,
+            },
+        );
+        let composite_pipeline = create_pipeline("fragment_composite", format.into());
+
+        let targets = Self::create_targets(device, &layout, &uniform, &sampler, dim);
+
+        Self {
+            config,
+            uniform,
+            sampler,
+            layout,
+            pipeline_layout,
+            shader_module,
+            prefilter_pipeline,
+            downsample_pipeline,
+            upsample_pipeline,
+            composite_pipeline,
+            targets,
+        }
+    }
+
+    fn create_targets(
+        device: &Device,
+        layout: &BindGroupLayout,
+        uniform: &Buffer,
+        sampler: &Sampler,
+        dim: UVec2,
+    ) -> BloomTargets {
+        let dim = dim.max(UVec2::ONE);
+        let scene_view = create_target(device, dim, HDR_FORMAT);
+
+        let mut mip_dim = (dim / 2).max(UVec2::ONE);
+        let mut mip_views = vec![create_target(device, mip_dim, HDR_FORMAT)];
+        while (mip_views.len() as u32) < MAX_BLOOM_MIPS && mip_dim.min_element() >= MIN_MIP_SIZE {
+            mip_dim /= 2;
+            mip_views.push(create_target(device, mip_dim, HDR_FORMAT));
+        }
+
+        let create_bind_group = |input: &TextureView, bloom: &TextureView| {
+            device.create_bind_group(&BindGroupDescriptor {
+                label: None,
+                layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: uniform.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::TextureView(input),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: BindingResource::Sampler(sampler),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: BindingResource::TextureView(bloom),
+                    },
+                ],
+            })
+        };
+        // Only the composite reads the bloom at binding 3, which can't be a target of
+        // the other passes.
+        let prefilter_bind_group = create_bind_group(&scene_view, &scene_view);
+        let mip_bind_groups = mip_views
+            .iter()
+            .map(|view| create_bind_group(view, view))
+            .collect();
+        let composite_bind_group = create_bind_group(&scene_view, &mip_views[0]);
+
+        BloomTargets {
+            scene_view,
+            mip_views,
+            prefilter_bind_group,
+            mip_bind_groups,
+            composite_bind_group,
+        }
+    }
+
+    pub fn resize(&mut self, device: &Device, dim: UVec2) {
+        self.targets =
+            Self::create_targets(device, &self.layout, &self.uniform, &self.sampler, dim);
+    }
+
+    /// The color target of the scene passes.
+    #[inline]
+    pub fn scene_view(&self) -> &TextureView {
+        &self.targets.scene_view
+    }
+
+    #[inline]
+    pub fn set_config(&mut self, config: BloomConfig) {
+        self.config = config;
+    }
+
+    /// For a new format of the color target.
+    pub fn rebuild_pipeline(&mut self, device: &Device, format: TextureFormat) {
+        self.composite_pipeline = create_fullscreen_pipeline(
+            device,
+            &self.pipeline_layout,
+            &self.shader_module,
+            "fragment_composite",
+            format.into(),
+        );
+    }
+
+    /// `enabled` is unset to copy the scene as is, for the debug view modes.
+    pub fn update(&self, queue: &Queue, enabled: bool) {
+        let uniform = BloomUniform {
+            config: &self.config,
+            enabled,
+        };
+        queue.write_buffer(&self.uniform, 0, &uniform.as_raw());
+    }
+
+    /// Blurs the highlights of the scene target, then composites them into
+    /// `color_target`. Only the composite runs unless `enabled`.
+    pub fn encode(
+        &self,
+        command_encoder: &mut CommandEncoder,
+        color_target: &TextureView,
+        enabled: bool,
+        mut gpu_timer: Option<&mut GpuTimer>,
+    ) {
+        let mut passes = Vec::new();
+        if enabled {
+            let mips = &self.targets.mip_views;
+            let bind_groups = &self.targets.mip_bind_groups;
+            passes.push((
+                Some("bloom"),
+                &mips[0],
+                &self.prefilter_pipeline,
+                &self.targets.prefilter_bind_group,
+            ));
+            for i in 1..mips.len() {
+                passes.push((
+                    None,
+                    &mips[i],
+                    &self.downsample_pipeline,
+                    &bind_groups[i - 1],
+                ));
+            }
+            for i in (1..mips.len()).rev() {
+                passes.push((None, &mips[i - 1], &self.upsample_pipeline, &bind_groups[i]));
+            }
+        }
+        passes.push((
+            Some("composite"),
+            color_target,
+            &self.composite_pipeline,
+            &self.targets.composite_bind_group,
+        ));
+
+        for (name, target, pipeline, bind_group) in passes {
+            let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Load,
+                        store: StoreOp::Store,
+                    },
+                })],
+                timestamp_writes: name
+                    .and_then(|name| gpu_timer.as_mut().and_then(|timer| timer.pass_writes(name))),
+                ..Default::default()
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// Bloom over a chain of half resolution targets: bright pixels are extracted while
+// downsampling, blurred back up with each target added to the next larger one, and the
+// result is composited over the scene before tonemapping.
+
+struct Bloom {
+    threshold: f32,
+    intensity: f32,
+    // Unset for the debug view modes, which are copied without bloom or tonemapping.
+    enabled: u32,
+}
+
+struct VertexOutput {
+    @builtin(position) position_cs: vec4f,
+    @location(0) uv: vec2f,
+}
+
+@group(0) @binding(0) var<uniform> bloom: Bloom;
+// The HDR scene for the prefilter and composite, the previous target otherwise.
+@group(0) @binding(1) var input_map: texture_2d<f32>;
+@group(0) @binding(2) var input_sampler: sampler;
+@group(0) @binding(3) var bloom_map: texture_2d<f32>;
+
+// Width of the soft transition below the threshold, relative to it.
+const KNEE: f32 = 0.5;
+
+// A triangle covering the whole target.
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let uv = vec2f(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+
+    var output: VertexOutput;
+    output.position_cs = vec4f(uv * 2. - 1., 0., 1.);
+    output.uv = vec2f(uv.x, 1. - uv.y);
+    return output;
+}
+
+fn sample_input(uv: vec2f, offset: vec2f) -> vec3f {
+    let texel_size = 1. / vec2f(textureDimensions(input_map));
+    return textureSampleLevel(input_map, input_sampler, uv + offset * texel_size, 0.).rgb;
+}
+
+// The 13 tap filter of Jimenez, "Next Generation Post Processing in Call of Duty:
+// Advanced Warfare", which doesn't flicker as much as a box filter when bright pixels
+// move.
+fn downsample_13(uv: vec2f) -> vec3f {
+    let a = sample_input(uv, vec2f(-2., -2.));
+    let b = sample_input(uv, vec2f(0., -2.));
+    let c = sample_input(uv, vec2f(2., -2.));
+    let d = sample_input(uv, vec2f(-2., 0.));
+    let e = sample_input(uv, vec2f(0., 0.));
+    let f = sample_input(uv, vec2f(2., 0.));
+    let g = sample_input(uv, vec2f(-2., 2.));
+    let h = sample_input(uv, vec2f(0., 2.));
+    let i = sample_input(uv, vec2f(2., 2.));
+    let j = sample_input(uv, vec2f(-1., -1.));
+    let k = sample_input(uv, vec2f(1., -1.));
+    let l = sample_input(uv, vec2f(-1., 1.));
+    let m = sample_input(uv, vec2f(1., 1.));
+
+    return e * 0.125 + (a + c + g + i) * 0.03125 + (b + d + f + h) * 0.0625
+        + (j + k + l + m) * 0.125;
+}
+
+// Keeps what's above the threshold, with a quadratic knee instead of a hard cut.
+fn apply_threshold(color: vec3f) -> vec3f {
+    let brightness = max(color.r, max(color.g, color.b));
+    let knee = bloom.threshold * KNEE;
+    let soft = clamp(brightness - bloom.threshold + knee, 0., 2. * knee);
+    let contribution = max(soft * soft / (4. * knee + 0.00001), brightness - bloom.threshold);
+    return color * contribution / max(brightness, 0.00001);
+}
+
+@fragment
+fn fragment_prefilter(input: VertexOutput) -> @location(0) vec4f {
+    return vec4f(apply_threshold(downsample_13(input.uv)), 1.);
+}
+
+@fragment
+fn fragment_downsample(input: VertexOutput) -> @location(0) vec4f {
+    return vec4f(downsample_13(input.uv), 1.);
+}
+
+// A 3x3 tent filter, added to the larger target by the blend state.
+@fragment
+fn fragment_upsample(input: VertexOutput) -> @location(0) vec4f {
+    let color = sample_input(input.uv, vec2f(0., 0.)) * 4.
+        + (sample_input(input.uv, vec2f(-1., 0.)) + sample_input(input.uv, vec2f(1., 0.))
+            + sample_input(input.uv, vec2f(0., -1.)) + sample_input(input.uv, vec2f(0., 1.))) * 2.
+        + sample_input(input.uv, vec2f(-1., -1.)) + sample_input(input.uv, vec2f(1., -1.))
+        + sample_input(input.uv, vec2f(-1., 1.)) + sample_input(input.uv, vec2f(1., 1.));
+    return vec4f(color / 16., 1.);
+}
+
+// The fitted ACES curve of Narkowicz.
+fn tonemap(color: vec3f) -> vec3f {
+    return saturate(color * (2.51 * color + 0.03) / (color * (2.43 * color + 0.59) + 0.14));
+}
+
+@fragment
+fn fragment_composite(input: VertexOutput) -> @location(0) vec4f {
+    let scene = textureSampleLevel(input_map, input_sampler, input.uv, 0.);
+    if bloom.enabled == 0u {
+        return scene;
+    }
+
+    let glow = textureSampleLevel(bloom_map, input_sampler, input.uv, 0.).rgb;
+    return vec4f(tonemap(scene.rgb + glow * bloom.intensity), scene.a);
+}
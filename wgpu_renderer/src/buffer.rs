@@ -1,11 +1,14 @@
-use std::marker::PhantomData;
+use std::{fmt, future::Future, marker::PhantomData};
 
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
-    BindingResource, Buffer, BufferUsages, Device, Queue,
+    BindingResource, Buffer, BufferAsyncError, BufferDescriptor, BufferUsages,
+    CommandEncoderDescriptor, Device, DownlevelFlags, ImageCopyBuffer, ImageCopyTexture,
+    ImageDataLayout, Maintain, MapMode, Origin3d, Queue, Texture, TextureAspect, TextureFormat,
+    COPY_BYTES_PER_ROW_ALIGNMENT,
 };
 
-use crate::render::ShaderData;
+use crate::{context::GpuContext, render::ShaderData};
 
 #[derive(Default)]
 pub struct StorageBuffer<T: ShaderData> {
@@ -36,7 +39,7 @@ impl<T: ShaderData> StorageBuffer<T> {
             self.buffer = Some(device.create_buffer_init(&BufferInitDescriptor {
                 label: None,
                 contents: &self.raw,
-                usage: BufferUsages::STORAGE,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
             }));
             self.changed = false;
         } else if let Some(buffer) = &self.buffer {
@@ -44,13 +47,228 @@ impl<T: ShaderData> StorageBuffer<T> {
         }
     }
 
+    /// Writes `data` into the existing buffer, keeping bind groups of it valid. Returns
+    /// false without writing if there is no buffer of the same size yet.
+    pub fn write_in_place(&mut self, queue: &Queue, data: &[T]) -> bool {
+        let raw: Vec<u8> = data.iter().flat_map(|e| e.as_raw()).collect();
+        match &self.buffer {
+            Some(buffer) if buffer.size() == raw.len() as u64 => {
+                queue.write_buffer(buffer, 0, &raw);
+                self.raw = raw;
+                true
+            }
+            _ => false,
+        }
+    }
+
     #[inline]
     pub fn clear(&mut self) {
         self.raw.clear();
     }
 
     #[inline]
-    pub fn binding(&self) -> Option<BindingResource> {
+    pub fn size(&self) -> u64 {
+        self.buffer.as_ref().map(wgpu::Buffer::size).unwrap_or(0)
+    }
+
+    #[inline]
+    pub fn binding(&self) -> Option<BindingResource<'_>> {
         self.buffer.as_ref().map(|b| b.as_entire_binding())
     }
+
+    #[inline]
+    pub fn buffer(&self) -> Option<&Buffer> {
+        self.buffer.as_ref()
+    }
+}
+
+/// Why reading back a buffer or texture failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReadbackError {
+    /// The staging buffer couldn't be mapped, like when the device is lost.
+    Map(BufferAsyncError),
+    /// The device was still busy with the copy after blocking on it.
+    Timeout,
+    /// The format has no copyable aspect with a fixed block size, like `Depth24Plus`, or
+    /// the device can't copy depth and stencil textures at all.
+    UnsupportedFormat(TextureFormat),
+}
+
+impl fmt::Display for ReadbackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadbackError::Map(err) => write!(f, "{}", err),
+            ReadbackError::Timeout => write!(f, "timed out waiting for the copy"),
+            ReadbackError::UnsupportedFormat(format) => {
+                write!(f, "can't copy textures of format {:?}", format)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReadbackError {}
+
+/// Requests the mapping right away, the returned future resolves once the device has
+/// been polled after the copy. Drawing polls it, as does [`Device::poll`], and browsers
+/// do on their own.
+fn map_staging_buffer(
+    staging_buffer: Buffer,
+) -> impl Future<Output = Result<Vec<u8>, ReadbackError>> {
+    let (sender, receiver) = flume::bounded(1);
+    staging_buffer.slice(..).map_async(MapMode::Read, move |r| {
+        // Nobody is waiting anymore if the future was dropped.
+        let _ = sender.send(r);
+    });
+
+    async move {
+        // The callback is dropped without being called if the device is destroyed.
+        receiver
+            .recv_async()
+            .await
+            .unwrap_or(Err(BufferAsyncError))
+            .map_err(ReadbackError::Map)?;
+        let data = staging_buffer.slice(..).get_mapped_range().to_vec();
+        staging_buffer.unmap();
+        Ok(data)
+    }
+}
+
+/// Blocks on the device until `readback` can resolve.
+fn wait_for<T>(
+    context: &GpuContext,
+    readback: impl Future<Output = Result<T, ReadbackError>>,
+) -> Result<T, ReadbackError> {
+    if !context.device().poll(Maintain::wait()).is_queue_empty() {
+        return Err(ReadbackError::Timeout);
+    }
+    pollster::block_on(readback)
+}
+
+/// Copies `buffer` into a staging buffer and reads it back asynchronously, see
+/// [`read_buffer_blocking`] to wait for it instead.
+pub fn read_buffer(
+    context: &GpuContext,
+    buffer: &Buffer,
+) -> impl Future<Output = Result<Vec<u8>, ReadbackError>> {
+    let device = context.device();
+    let staging_buffer = device.create_buffer(&BufferDescriptor {
+        label: None,
+        size: buffer.size(),
+        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut command_encoder =
+        device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+    command_encoder.copy_buffer_to_buffer(buffer, 0, &staging_buffer, 0, buffer.size());
+    context.queue().submit(Some(command_encoder.finish()));
+
+    map_staging_buffer(staging_buffer)
+}
+
+/// Waits for [`read_buffer`] by blocking on the device.
+pub fn read_buffer_blocking(
+    context: &GpuContext,
+    buffer: &Buffer,
+) -> Result<Vec<u8>, ReadbackError> {
+    wait_for(context, read_buffer(context, buffer))
+}
+
+/// Reads back the first mip and layer of `texture` asynchronously with tightly packed
+/// rows, see [`read_texture_blocking`] to wait for it instead. Only the depth of
+/// combined depth-stencil formats is read.
+pub fn read_texture(
+    context: &GpuContext,
+    texture: &Texture,
+) -> impl Future<Output = Result<Vec<u8>, ReadbackError>> {
+    let format = texture.format();
+    let aspect = match format.has_depth_aspect() && format.has_stencil_aspect() {
+        true => TextureAspect::DepthOnly,
+        false => TextureAspect::All,
+    };
+    let depth_copies = context
+        .adapter()
+        .get_downlevel_capabilities()
+        .flags
+        .contains(DownlevelFlags::DEPTH_TEXTURE_AND_BUFFER_COPIES);
+    let pixel_size = format
+        .block_copy_size(Some(aspect))
+        .filter(|_| depth_copies || !format.is_depth_stencil_format());
+    let readback = pixel_size.map(|pixel_size| copy_texture(context, texture, aspect, pixel_size));
+
+    async move {
+        let Some((readback, row_size, padded_row_size)) = readback else {
+            return Err(ReadbackError::UnsupportedFormat(format));
+        };
+        let padded = readback.await?;
+        Ok(padded
+            .chunks_exact(padded_row_size as usize)
+            .flat_map(|row| &row[..row_size as usize])
+            .copied()
+            .collect())
+    }
+}
+
+/// Waits for [`read_texture`] by blocking on the device.
+pub fn read_texture_blocking(
+    context: &GpuContext,
+    texture: &Texture,
+) -> Result<Vec<u8>, ReadbackError> {
+    wait_for(context, read_texture(context, texture))
+}
+
+/// Submits the copy of `texture` with rows padded for the copy alignment, returning the
+/// readback of it along with the unpadded and padded row sizes.
+fn copy_texture(
+    context: &GpuContext,
+    texture: &Texture,
+    aspect: TextureAspect,
+    pixel_size: u32,
+) -> (
+    impl Future<Output = Result<Vec<u8>, ReadbackError>>,
+    u32,
+    u32,
+) {
+    let device = context.device();
+    let extent = texture.size();
+    let row_size = extent.width * pixel_size;
+    let padded_row_size =
+        row_size.div_ceil(COPY_BYTES_PER_ROW_ALIGNMENT) * COPY_BYTES_PER_ROW_ALIGNMENT;
+
+    let staging_buffer = device.create_buffer(&BufferDescriptor {
+        label: None,
+        size: (padded_row_size * extent.height) as u64,
+        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut command_encoder =
+        device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+    command_encoder.copy_texture_to_buffer(
+        ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: Origin3d::ZERO,
+            aspect,
+        },
+        ImageCopyBuffer {
+            buffer: &staging_buffer,
+            layout: ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_row_size),
+                rows_per_image: Some(extent.height),
+            },
+        },
+        wgpu::Extent3d {
+            depth_or_array_layers: 1,
+            ..extent
+        },
+    );
+    context.queue().submit(Some(command_encoder.finish()));
+
+    (
+        map_staging_buffer(staging_buffer),
+        row_size,
+        padded_row_size,
+    )
 }
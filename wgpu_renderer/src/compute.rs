@@ -0,0 +1,104 @@
+use glam::{UVec2, UVec3};
+use wgpu::*;
+
+use crate::context::GpuContext;
+
+pub struct ComputePass {
+    pipeline: ComputePipeline,
+}
+
+impl ComputePass {
+    /// The bind group layouts are derived from the bindings used by `entry_point`.
+    pub fn new(context: &GpuContext, shader: ShaderSource<'_>, entry_point: &str) -> Self {
+        let device = context.device();
+        let shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: shader,
+        });
+
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: None,
+            layout: None,
+            module: &shader_module,
+            entry_point,
+            compilation_options: PipelineCompilationOptions::default(),
+        });
+
+        Self { pipeline }
+    }
+
+    /// `resources` are bound to consecutive bindings starting from 0.
+    pub fn create_bind_group(
+        &self,
+        context: &GpuContext,
+        group: u32,
+        resources: &[BindingResource],
+    ) -> BindGroup {
+        let entries = resources
+            .iter()
+            .enumerate()
+            .map(|(binding, resource)| BindGroupEntry {
+                binding: binding as u32,
+                resource: resource.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        context.device().create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &self.pipeline.get_bind_group_layout(group),
+            entries: &entries,
+        })
+    }
+
+    pub fn encode(
+        &self,
+        command_encoder: &mut CommandEncoder,
+        bind_groups: &[&BindGroup],
+        workgroups: UVec3,
+        timestamp_writes: Option<ComputePassTimestampWrites>,
+    ) {
+        let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: None,
+            timestamp_writes,
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        for (index, bind_group) in bind_groups.iter().enumerate() {
+            pass.set_bind_group(index as u32, bind_group, &[]);
+        }
+        pass.dispatch_workgroups(workgroups.x, workgroups.y, workgroups.z);
+    }
+
+    pub fn dispatch(&self, context: &GpuContext, bind_groups: &[&BindGroup], workgroups: UVec3) {
+        let mut command_encoder = context
+            .device()
+            .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        self.encode(&mut command_encoder, bind_groups, workgroups, None);
+        context.queue().submit(Some(command_encoder.finish()));
+    }
+}
+
+pub fn create_storage_texture(
+    context: &GpuContext,
+    dim: UVec2,
+    format: TextureFormat,
+) -> (Texture, TextureView) {
+    let texture = context.device().create_texture(&TextureDescriptor {
+        label: None,
+        size: Extent3d {
+            width: dim.x,
+            height: dim.y,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format,
+        usage: TextureUsages::STORAGE_BINDING
+            | TextureUsages::TEXTURE_BINDING
+            | TextureUsages::COPY_SRC,
+        view_formats: &[],
+    });
+    let view = texture.create_view(&TextureViewDescriptor::default());
+    (texture, view)
+}
@@ -0,0 +1,161 @@
+use std::{
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+};
+
+use wgpu::*;
+
+use crate::{
+    assets::{AssetRegistry, MeshHandle, TextureHandle},
+    texture::ColorSpace,
+};
+
+struct GpuContextInner {
+    instance: Arc<Instance>,
+    adapter: Arc<Adapter>,
+    device: Device,
+    queue: Queue,
+    device_lost: Arc<AtomicBool>,
+    replacement: Mutex<Option<GpuContext>>,
+    assets: RwLock<AssetRegistry>,
+}
+
+/// Handle to the wgpu instance, adapter, device, queue and uploaded assets. Cloning is
+/// cheap, and renderers created with clones of the same context share all of them.
+#[derive(Clone)]
+pub struct GpuContext {
+    inner: Arc<GpuContextInner>,
+}
+
+impl GpuContext {
+    pub async fn new() -> Self {
+        let instance = Instance::default();
+        let adapter = instance
+            .request_adapter(&RequestAdapterOptions::default())
+            .await
+            .unwrap();
+        Self::from_adapter(Arc::new(instance), Arc::new(adapter)).await
+    }
+
+    async fn from_adapter(instance: Arc<Instance>, adapter: Arc<Adapter>) -> Self {
+        let device_lost = Arc::new(AtomicBool::new(false));
+        let (device, queue) = adapter
+            .request_device(
+                &DeviceDescriptor {
+                    label: None,
+                    required_features: adapter.features()
+                        & (Features::TIMESTAMP_QUERY | Features::POLYGON_MODE_LINE),
+                    // More bind groups are left for the user bind groups of the renderers.
+                    required_limits: Limits {
+                        max_bind_groups: adapter.limits().max_bind_groups,
+                        ..Limits::downlevel_defaults()
+                    },
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let flag = device_lost.clone();
+        device.set_device_lost_callback(move |reason, message| match reason {
+            DeviceLostReason::Dropped | DeviceLostReason::ReplacedCallback => {}
+            _ => {
+                log::error!("Device lost ({:?}): {}", reason, message);
+                flag.store(true, Ordering::Release);
+            }
+        });
+
+        log::info!("Wgpu context set up.");
+
+        Self {
+            inner: Arc::new(GpuContextInner {
+                instance,
+                adapter,
+                device,
+                queue,
+                device_lost,
+                replacement: Mutex::new(None),
+                assets: Default::default(),
+            }),
+        }
+    }
+
+    /// Returns a context with a new device on the same adapter. All clones of a lost
+    /// context recover to the same new context, so they keep sharing the device.
+    pub async fn recover(&self) -> Self {
+        if let Some(context) = self.inner.replacement.lock().unwrap().as_ref() {
+            return context.clone();
+        }
+
+        log::info!("Recovering from device loss.");
+        // The instance and adapter survive a device loss, so surfaces created from
+        // them stay valid.
+        // The device is requested without holding the lock as it's awaited. If another
+        // clone recovered meanwhile, its context wins and this device is dropped.
+        let context =
+            Self::from_adapter(self.inner.instance.clone(), self.inner.adapter.clone()).await;
+        let mut replacement = self.inner.replacement.lock().unwrap();
+        if let Some(replacement) = replacement.as_ref() {
+            return replacement.clone();
+        }
+
+        context
+            .assets_mut()
+            .reupload(context.device(), context.queue(), &mut self.assets_mut());
+        replacement.insert(context).clone()
+    }
+
+    #[inline]
+    pub fn assets(&self) -> RwLockReadGuard<'_, AssetRegistry> {
+        self.inner.assets.read().unwrap()
+    }
+
+    #[inline]
+    pub fn assets_mut(&self) -> RwLockWriteGuard<'_, AssetRegistry> {
+        self.inner.assets.write().unwrap()
+    }
+
+    #[inline]
+    pub fn load_obj(&self, path: impl AsRef<Path>) -> Vec<MeshHandle> {
+        self.assets_mut().load_obj(self.device(), path)
+    }
+
+    #[inline]
+    pub fn load_texture(
+        &self,
+        path: impl AsRef<Path>,
+        color_space: ColorSpace,
+    ) -> io::Result<TextureHandle> {
+        self.assets_mut()
+            .load_texture(self.device(), self.queue(), path, color_space)
+    }
+
+    #[inline]
+    pub fn is_device_lost(&self) -> bool {
+        self.inner.device_lost.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub fn instance(&self) -> &Instance {
+        &self.inner.instance
+    }
+
+    #[inline]
+    pub fn adapter(&self) -> &Adapter {
+        &self.inner.adapter
+    }
+
+    #[inline]
+    pub fn device(&self) -> &Device {
+        &self.inner.device
+    }
+
+    #[inline]
+    pub fn queue(&self) -> &Queue {
+        &self.inner.queue
+    }
+}
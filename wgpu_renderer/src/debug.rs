@@ -0,0 +1,145 @@
+use std::f32::consts::TAU;
+
+use bytemuck::{Pod, Zeroable};
+use glam::{Mat4, Vec3};
+
+use crate::scene::Aabb;
+
+/// Segments of the circles making up spheres.
+const CIRCLE_SEGMENTS: usize = 32;
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct DebugVertex {
+    pub position: Vec3,
+    pub color: Vec3,
+}
+
+/// Lines drawn on top of the scene. Everything added is drawn by the next
+/// [`crate::WgpuRenderer::draw`] and cleared afterwards, so it has to be added again
+/// every frame.
+pub struct DebugLines {
+    pub(crate) vertices: Vec<DebugVertex>,
+    /// Hide lines behind scene geometry.
+    pub depth_test: bool,
+}
+
+impl Default for DebugLines {
+    fn default() -> Self {
+        Self {
+            vertices: Vec::new(),
+            depth_test: true,
+        }
+    }
+}
+
+impl DebugLines {
+    #[inline]
+    pub fn line(&mut self, start: Vec3, end: Vec3, color: Vec3) {
+        self.vertices.extend([
+            DebugVertex {
+                position: start,
+                color,
+            },
+            DebugVertex {
+                position: end,
+                color,
+            },
+        ]);
+    }
+
+    pub fn aabb(&mut self, aabb: &Aabb, color: Vec3) {
+        let corners = (0..8)
+            .map(|i| {
+                Vec3::new(
+                    if i & 1 != 0 { aabb.max.x } else { aabb.min.x },
+                    if i & 2 != 0 { aabb.max.y } else { aabb.min.y },
+                    if i & 4 != 0 { aabb.max.z } else { aabb.min.z },
+                )
+            })
+            .collect::<Vec<_>>();
+        self.box_edges(corners.try_into().unwrap(), color);
+    }
+
+    /// The corners of the volume `view_proj` maps to clip space, like a camera frustum
+    /// or the bounds of an orthographic shadow projection.
+    pub fn frustum(&mut self, view_proj: Mat4, color: Vec3) {
+        let inverse = view_proj.inverse();
+        let corners = (0..8)
+            .map(|i| {
+                let ndc = Vec3::new(
+                    if i & 1 != 0 { 1. } else { -1. },
+                    if i & 2 != 0 { 1. } else { -1. },
+                    if i & 4 != 0 { 1. } else { 0. },
+                );
+                inverse.project_point3(ndc)
+            })
+            .collect::<Vec<_>>();
+        self.box_edges(corners.try_into().unwrap(), color);
+    }
+
+    /// Corner `i` has the maximum x, y and z coordinates if bit 0, 1 and 2 are set.
+    fn box_edges(&mut self, corners: [Vec3; 8], color: Vec3) {
+        for i in 0..8 {
+            for axis in [1, 2, 4] {
+                if i & axis == 0 {
+                    self.line(corners[i], corners[i | axis], color);
+                }
+            }
+        }
+    }
+
+    pub fn circle(&mut self, center: Vec3, normal: Vec3, radius: f32, color: Vec3) {
+        let (u, v) = normal.normalize().any_orthonormal_pair();
+        let point = |i: usize| {
+            let angle = i as f32 / CIRCLE_SEGMENTS as f32 * TAU;
+            center + (u * angle.cos() + v * angle.sin()) * radius
+        };
+
+        for i in 0..CIRCLE_SEGMENTS {
+            self.line(point(i), point(i + 1), color);
+        }
+    }
+
+    pub fn sphere(&mut self, center: Vec3, radius: f32, color: Vec3) {
+        for normal in [Vec3::X, Vec3::Y, Vec3::Z] {
+            self.circle(center, normal, radius, color);
+        }
+    }
+
+    pub fn arrow(&mut self, start: Vec3, end: Vec3, color: Vec3) {
+        self.line(start, end, color);
+
+        let direction = end - start;
+        let length = direction.length();
+        if length <= f32::EPSILON {
+            return;
+        }
+
+        let direction = direction / length;
+        let (u, v) = direction.any_orthonormal_pair();
+        let head = length * 0.15;
+        for side in [u, -u, v, -v] {
+            self.line(end, end - direction * head + side * head * 0.5, color);
+        }
+    }
+
+    /// X, Y and Z axes of `transform` in red, green and blue.
+    pub fn axes(&mut self, transform: Mat4, size: f32) {
+        let origin = transform.transform_point3(Vec3::ZERO);
+        for (axis, color) in [(Vec3::X, Vec3::X), (Vec3::Y, Vec3::Y), (Vec3::Z, Vec3::Z)] {
+            let end = transform.transform_point3(axis * size);
+            self.arrow(origin, end, color);
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.vertices.is_empty()
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+    }
+}
@@ -0,0 +1,24 @@
+struct Camera {
+    view: mat4x4f,
+    proj: mat4x4f,
+}
+
+struct VertexOutput {
+    @builtin(position) position_cs: vec4f,
+    @location(0) color: vec3f,
+}
+
+@group(0) @binding(0) var<uniform> camera: Camera;
+
+@vertex
+fn vertex(@location(0) position: vec3f, @location(1) color: vec3f) -> VertexOutput {
+    var output: VertexOutput;
+    output.position_cs = camera.proj * camera.view * vec4f(position, 1.);
+    output.color = color;
+    return output;
+}
+
+@fragment
+fn fragment(input: VertexOutput) -> @location(0) vec4f {
+    return vec4f(input.color, 1.);
+}
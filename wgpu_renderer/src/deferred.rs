@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+
+use glam::UVec2;
+use wgpu::*;
+
+use crate::{
+    assets::{AssetRegistry, MeshHandle},
+    material::Materials,
+    stats::{FrameStats, GpuTimer},
+    taa,
+    user::UserBindings,
+};
+
+// sRGB encoded so dark albedos don't band compared to forward shading.
+const ALBEDO_FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+const NORMAL_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+const MATERIAL_FORMAT: TextureFormat = TextureFormat::Rgba8Unorm;
+const EMISSIVE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+
+fn create_target(device: &Device, dim: UVec2, format: TextureFormat) -> TextureView {
+    device
+        .create_texture(&TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: dim.x,
+                height: dim.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        })
+        .create_view(&TextureViewDescriptor::default())
+}
+
+struct GBuffer {
+    albedo_view: TextureView,
+    normal_view: TextureView,
+    material_view: TextureView,
+    emissive_view: TextureView,
+}
+
+impl GBuffer {
+    fn new(device: &Device, dim: UVec2) -> Self {
+        let dim = dim.max(UVec2::ONE);
+        Self {
+            albedo_view: create_target(device, dim, ALBEDO_FORMAT),
+            normal_view: create_target(device, dim, NORMAL_FORMAT),
+            material_view: create_target(device, dim, MATERIAL_FORMAT),
+            emissive_view: create_target(device, dim, EMISSIVE_FORMAT),
+        }
+    }
+}
+
+/// Pipelines of the deferred path, built from the `vertex`, `fragment_gbuffer`,
+/// `vertex_fullscreen` and `fragment_lighting` entry points of the scene shader, or
+/// `fragment_lighting_taa` with motion vectors.
+struct DeferredPipelines {
+    gbuffer: RenderPipeline,
+    lighting: RenderPipeline,
+}
+
+impl DeferredPipelines {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        device: &Device,
+        shader_module: &ShaderModule,
+        scene_pipeline_layout: &PipelineLayout,
+        vertex_buffers: &[VertexBufferLayout],
+        lighting_layout: &PipelineLayout,
+        format: TextureFormat,
+        motion_vectors: bool,
+        shader_defs: &HashMap<String, f64>,
+    ) -> Self {
+        let compilation_options = PipelineCompilationOptions {
+            constants: shader_defs,
+            ..Default::default()
+        };
+        let gbuffer = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: None,
+            layout: Some(scene_pipeline_layout),
+            vertex: VertexState {
+                module: shader_module,
+                entry_point: "vertex",
+                compilation_options: compilation_options.clone(),
+                buffers: vertex_buffers,
+            },
+            fragment: Some(FragmentState {
+                module: shader_module,
+                entry_point: "fragment_gbuffer",
+                compilation_options: compilation_options.clone(),
+                targets: &[
+                    Some(ALBEDO_FORMAT.into()),
+                    Some(NORMAL_FORMAT.into()),
+                    Some(MATERIAL_FORMAT.into()),
+                    Some(EMISSIVE_FORMAT.into()),
+                ],
+            }),
+            primitive: PrimitiveState::default(),
+            depth_stencil: Some(DepthStencilState {
+                format: TextureFormat::Depth24Plus,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::LessEqual,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            multisample: MultisampleState::default(),
+            multiview: None,
+        });
+        let lighting_targets = [Some(format.into()), Some(taa::motion_target(true))];
+        let lighting = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: None,
+            layout: Some(lighting_layout),
+            vertex: VertexState {
+                module: shader_module,
+                entry_point: "vertex_fullscreen",
+                compilation_options: compilation_options.clone(),
+                buffers: &[],
+            },
+            fragment: Some(FragmentState {
+                module: shader_module,
+                entry_point: match motion_vectors {
+                    true => "fragment_lighting_taa",
+                    false => "fragment_lighting",
+                },
+                compilation_options,
+                targets: &lighting_targets[..1 + motion_vectors as usize],
+            }),
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            multiview: None,
+        });
+
+        Self { gbuffer, lighting }
+    }
+}
+
+/// Renders albedo, normals, material parameters and emission of the nearest surfaces
+/// into a G-buffer, then lights every pixel once in a fullscreen pass, so the cost of
+/// lighting doesn't grow with overdraw. The G-buffer is bound to group 3 of the lighting
+/// pass, with the depth target at binding 3 and the emission at binding 4.
+pub(crate) struct Deferred {
+    gbuffer_layout: BindGroupLayout,
+    lighting_layout: PipelineLayout,
+    pipelines: DeferredPipelines,
+    gbuffer: GBuffer,
+}
+
+impl Deferred {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &Device,
+        shader_module: &ShaderModule,
+        scene_layout: &BindGroupLayout,
+        mesh_layout: &BindGroupLayout,
+        material_layout: &BindGroupLayout,
+        scene_pipeline_layout: &PipelineLayout,
+        vertex_buffers: &[VertexBufferLayout],
+        format: TextureFormat,
+        motion_vectors: bool,
+        shader_defs: &HashMap<String, f64>,
+        dim: UVec2,
+    ) -> Self {
+        let texture_entry = |binding, sample_type| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type,
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let unfilterable = TextureSampleType::Float { filterable: false };
+        let gbuffer_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                texture_entry(0, unfilterable),
+                texture_entry(1, unfilterable),
+                texture_entry(2, unfilterable),
+                texture_entry(3, TextureSampleType::Depth),
+                texture_entry(4, unfilterable),
+            ],
+        });
+        // The mesh and material layouts are unused, but keep the G-buffer at the same
+        // group for every shader.
+        let lighting_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[scene_layout, mesh_layout, material_layout, &gbuffer_layout],
+            ..Default::default()
+        });
+        let pipelines = DeferredPipelines::new(
+            device,
+            shader_module,
+            scene_pipeline_layout,
+            vertex_buffers,
+            &lighting_layout,
+            format,
+            motion_vectors,
+            shader_defs,
+        );
+
+        Self {
+            gbuffer_layout,
+            lighting_layout,
+            pipelines,
+            gbuffer: GBuffer::new(device, dim),
+        }
+    }
+
+    #[inline]
+    pub fn resize(&mut self, device: &Device, dim: UVec2) {
+        self.gbuffer = GBuffer::new(device, dim);
+    }
+
+    /// For a new target format, shader defs or motion vectors.
+    #[allow(clippy::too_many_arguments)]
+    pub fn rebuild_pipelines(
+        &mut self,
+        device: &Device,
+        shader_module: &ShaderModule,
+        scene_pipeline_layout: &PipelineLayout,
+        vertex_buffers: &[VertexBufferLayout],
+        format: TextureFormat,
+        motion_vectors: bool,
+        shader_defs: &HashMap<String, f64>,
+    ) {
+        self.pipelines = DeferredPipelines::new(
+            device,
+            shader_module,
+            scene_pipeline_layout,
+            vertex_buffers,
+            &self.lighting_layout,
+            format,
+            motion_vectors,
+            shader_defs,
+        );
+    }
+
+    /// Fills the G-buffer and `depth_target`, then lights `color_target`, writing motion
+    /// vectors to `motion_target` if the pipelines were built for them. Every mesh is
+    /// drawn as the instance at its index in `materials`, with the bind group of its
+    /// textures and its `user` bind groups and attributes.
+    #[allow(clippy::too_many_arguments)]
+    pub fn encode(
+        &self,
+        device: &Device,
+        command_encoder: &mut CommandEncoder,
+        assets: &AssetRegistry,
+        bind_groups: (&BindGroup, &BindGroup),
+        mesh_groups: &[(&BindGroup, &[MeshHandle])],
+        materials: &Materials,
+        user: &UserBindings,
+        targets: (&TextureView, Option<&TextureView>, &TextureView),
+        clear_color: Color,
+        stats: &mut FrameStats,
+        mut gpu_timer: Option<&mut GpuTimer>,
+    ) {
+        let (scene_bind_group, static_mesh_bind_group) = bind_groups;
+        let (color_target, motion_target, depth_target) = targets;
+
+        {
+            let attachment = |view| {
+                Some(RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Color::TRANSPARENT),
+                        store: StoreOp::Store,
+                    },
+                })
+            };
+            let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+                label: None,
+                color_attachments: &[
+                    attachment(&self.gbuffer.albedo_view),
+                    attachment(&self.gbuffer.normal_view),
+                    attachment(&self.gbuffer.material_view),
+                    attachment(&self.gbuffer.emissive_view),
+                ],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                    view: depth_target,
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Clear(1.),
+                        store: StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: gpu_timer
+                    .as_mut()
+                    .and_then(|timer| timer.pass_writes("gbuffer")),
+                ..Default::default()
+            });
+
+            pass.set_pipeline(&self.pipelines.gbuffer);
+            pass.set_bind_group(0, scene_bind_group, &[]);
+            for (mesh_bind_group, meshes) in mesh_groups {
+                pass.set_bind_group(1, mesh_bind_group, &[]);
+                for handle in meshes.iter() {
+                    let Some(mesh) = assets.mesh(*handle) else {
+                        continue;
+                    };
+                    if !user.bind(&mut pass, *handle, mesh) {
+                        continue;
+                    }
+                    materials.bind(&mut pass, 2, *handle, mesh);
+                    pass.set_vertex_buffer(0, mesh.vertex_buf.slice(..));
+                    pass.draw(0..mesh.vertex_count, 0..1);
+                    stats.draw_calls += 1;
+                    stats.triangles += mesh.vertex_count / 3;
+                }
+            }
+        }
+
+        // The depth target is only known at draw time.
+        let gbuffer_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &self.gbuffer_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&self.gbuffer.albedo_view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&self.gbuffer.normal_view),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(&self.gbuffer.material_view),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::TextureView(depth_target),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::TextureView(&self.gbuffer.emissive_view),
+                },
+            ],
+        });
+
+        let motion_attachment = motion_target.map(|view| RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: Operations {
+                load: LoadOp::Clear(Color::TRANSPARENT),
+                store: StoreOp::Store,
+            },
+        });
+        let color_attachments = [
+            Some(RenderPassColorAttachment {
+                view: color_target,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Clear(clear_color),
+                    store: StoreOp::Store,
+                },
+            }),
+            motion_attachment,
+        ];
+        let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+            label: None,
+            color_attachments: &color_attachments[..1 + motion_target.is_some() as usize],
+            timestamp_writes: gpu_timer
+                .as_mut()
+                .and_then(|timer| timer.pass_writes("lighting")),
+            ..Default::default()
+        });
+        pass.set_pipeline(&self.pipelines.lighting);
+        pass.set_bind_group(0, scene_bind_group, &[]);
+        pass.set_bind_group(1, static_mesh_bind_group, &[]);
+        materials.bind_default(&mut pass, 2);
+        pass.set_bind_group(3, &gbuffer_bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
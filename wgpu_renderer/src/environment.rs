@@ -0,0 +1,682 @@
+use std::{
+    borrow::Cow,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use glam::{UVec2, Vec4};
+use wgpu::{util::*, *};
+
+use crate::taa;
+
+const FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+const CUBE_SIZE: u32 = 512;
+const IRRADIANCE_SIZE: u32 = 32;
+const PREFILTERED_SIZE: u32 = 128;
+/// Mips of the prefiltered radiance, from smooth to fully rough.
+const PREFILTERED_MIPS: u32 = 5;
+const BRDF_LUT_SIZE: u32 = 256;
+
+/// A linear RGB image, as stored in Radiance `.hdr` files.
+#[derive(Debug, Clone)]
+pub struct HdrImage {
+    pub dim: UVec2,
+    /// Rows from top to bottom, the alpha is always 1.
+    pub pixels: Vec<Vec4>,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn rgbe_to_linear(rgbe: [u8; 4]) -> Vec4 {
+    if rgbe[3] == 0 {
+        return Vec4::W;
+    }
+    let scale = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+    Vec4::new(
+        rgbe[0] as f32 * scale,
+        rgbe[1] as f32 * scale,
+        rgbe[2] as f32 * scale,
+        1.,
+    )
+}
+
+impl HdrImage {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::decode(&std::fs::read(path)?)
+    }
+
+    /// Decodes flat and run length encoded RGBE pixels with the usual `-Y height +X
+    /// width` orientation.
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut lines = bytes.split(|&b| b == b'\n');
+        let mut read = 0;
+        let mut next_line = || {
+            let line = lines.next()?;
+            read += line.len() + 1;
+            std::str::from_utf8(line).ok()
+        };
+
+        if !next_line().is_some_and(|magic| magic.starts_with("#?")) {
+            return Err(invalid_data("Not a Radiance HDR file"));
+        }
+        loop {
+            match next_line() {
+                Some("") => break,
+                Some(line) if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" => {
+                    return Err(invalid_data("Only RGBE pixels are supported"));
+                }
+                Some(_) => {}
+                None => return Err(invalid_data("Missing HDR resolution")),
+            }
+        }
+        let resolution = next_line().unwrap_or_default();
+        let (height, width) = match resolution.split_whitespace().collect::<Vec<_>>()[..] {
+            ["-Y", height, "+X", width] => (height.parse::<u32>(), width.parse::<u32>()),
+            _ => return Err(invalid_data("Unsupported HDR orientation")),
+        };
+        let (Ok(height @ 1..), Ok(width @ 1..)) = (height, width) else {
+            return Err(invalid_data("Invalid HDR resolution"));
+        };
+        let data = bytes.get(read..).unwrap_or_default();
+        // Runs encode at most 127 values of a channel in 2 bytes, so every pixel takes
+        // more than 1/16 of a byte even when fully run length encoded.
+        let pixel_count = width
+            .checked_mul(height)
+            .filter(|&count| count as u64 <= data.len() as u64 * 16)
+            .ok_or_else(|| invalid_data("HDR resolution larger than the pixels"))?;
+
+        let mut data = data.iter().copied();
+        let mut next_byte = || {
+            data.next()
+                .ok_or_else(|| invalid_data("Truncated HDR pixels"))
+        };
+        let mut pixels = Vec::with_capacity(pixel_count as usize);
+        let mut scanline = vec![[0u8; 4]; width as usize];
+        for _ in 0..height {
+            let first = [next_byte()?, next_byte()?, next_byte()?, next_byte()?];
+            let encoded_width = (first[2] as u32) << 8 | first[3] as u32;
+            if first[0] == 2 && first[1] == 2 && (8..0x8000).contains(&width) {
+                if encoded_width != width {
+                    return Err(invalid_data("Mismatched HDR scanline width"));
+                }
+                // Every channel is encoded separately, as runs and literals.
+                for channel in 0..4 {
+                    let mut x = 0;
+                    while x < width as usize {
+                        let count = next_byte()? as usize;
+                        let (count, run) = match count > 128 {
+                            true => (count - 128, Some(next_byte()?)),
+                            false => (count, None),
+                        };
+                        if count == 0 || x + count > width as usize {
+                            return Err(invalid_data("Invalid HDR run length"));
+                        }
+                        for pixel in &mut scanline[x..x + count] {
+                            pixel[channel] = match run {
+                                Some(value) => value,
+                                None => next_byte()?,
+                            };
+                        }
+                        x += count;
+                    }
+                }
+            } else {
+                scanline[0] = first;
+                for pixel in &mut scanline[1..] {
+                    *pixel = [next_byte()?, next_byte()?, next_byte()?, next_byte()?];
+                }
+            }
+            pixels.extend(scanline.iter().map(|&rgbe| rgbe_to_linear(rgbe)));
+        }
+
+        Ok(Self {
+            dim: UVec2::new(width, height),
+            pixels,
+        })
+    }
+}
+
+fn create_cube(device: &Device, size: u32, mip_level_count: u32) -> Texture {
+    device.create_texture(&TextureDescriptor {
+        label: None,
+        size: Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        },
+        mip_level_count,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: FORMAT,
+        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::STORAGE_BINDING,
+        view_formats: &[],
+    })
+}
+
+fn create_cube_view(texture: &Texture) -> TextureView {
+    texture.create_view(&TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::Cube),
+        ..Default::default()
+    })
+}
+
+/// The faces of a single mip, for the generation passes.
+fn create_mip_view(texture: &Texture, mip: u32) -> TextureView {
+    texture.create_view(&TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::D2Array),
+        base_mip_level: mip,
+        mip_level_count: Some(1),
+        ..Default::default()
+    })
+}
+
+fn texture_entry(
+    binding: u32,
+    sample_type: TextureSampleType,
+    view_dimension: TextureViewDimension,
+) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Texture {
+            sample_type,
+            view_dimension,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+fn storage_entry(binding: u32, view_dimension: TextureViewDimension) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::StorageTexture {
+            access: StorageTextureAccess::WriteOnly,
+            format: FORMAT,
+            view_dimension,
+        },
+        count: None,
+    }
+}
+
+/// One of the entry points of `environment.wgsl`, run once when the environment is
+/// created.
+struct GenerationPass {
+    layout: BindGroupLayout,
+    pipeline: ComputePipeline,
+}
+
+impl GenerationPass {
+    fn new(
+        device: &Device,
+        shader_module: &ShaderModule,
+        entries: &[BindGroupLayoutEntry],
+        entry_point: &str,
+    ) -> Self {
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries,
+        });
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[&layout],
+                ..Default::default()
+            })),
+            module: shader_module,
+            entry_point,
+            compilation_options: PipelineCompilationOptions::default(),
+        });
+
+        Self { layout, pipeline }
+    }
+
+    /// Dispatches a workgroup for every 8x8 texels of `layers` layers, `resources` being
+    /// pairs of bindings and resources.
+    fn dispatch(
+        &self,
+        device: &Device,
+        command_encoder: &mut CommandEncoder,
+        resources: &[(u32, BindingResource)],
+        size: u32,
+        layers: u32,
+    ) {
+        let entries = resources
+            .iter()
+            .map(|(binding, resource)| BindGroupEntry {
+                binding: *binding,
+                resource: resource.clone(),
+            })
+            .collect::<Vec<_>>();
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &self.layout,
+            entries: &entries,
+        });
+
+        let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(size.div_ceil(8), size.div_ceil(8), layers);
+    }
+}
+
+/// Image based lighting from an equirectangular HDR panorama, converted to a cubemap
+/// drawn as the skybox. The irradiance, prefiltered radiance and BRDF lookup table are
+/// generated on the GPU and bound to the scene shader at bindings 9 to 14.
+pub(crate) struct Environment {
+    uniform: Buffer,
+    pub skybox_view: TextureView,
+    pub irradiance_view: TextureView,
+    pub prefiltered_view: TextureView,
+    pub brdf_lut_view: TextureView,
+    pub sampler: Sampler,
+    /// False for the placeholder bound without an environment.
+    pub enabled: bool,
+}
+
+impl Environment {
+    /// Black placeholder textures, the scene shader falls back to a constant ambient
+    /// light.
+    pub fn empty(device: &Device, queue: &Queue) -> Self {
+        let black_cube = |_| {
+            let texture = device.create_texture_with_data(
+                queue,
+                &TextureDescriptor {
+                    label: None,
+                    size: Extent3d {
+                        width: 1,
+                        height: 1,
+                        depth_or_array_layers: 6,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: FORMAT,
+                    usage: TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                },
+                TextureDataOrder::LayerMajor,
+                &[0; 48],
+            );
+            create_cube_view(&texture)
+        };
+        let [skybox_view, irradiance_view, prefiltered_view] = [(); 3].map(black_cube);
+        let brdf_lut_view = device
+            .create_texture_with_data(
+                queue,
+                &TextureDescriptor {
+                    label: None,
+                    size: Extent3d::default(),
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: FORMAT,
+                    usage: TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                },
+                TextureDataOrder::LayerMajor,
+                &[0; 8],
+            )
+            .create_view(&TextureViewDescriptor::default());
+
+        Self {
+            uniform: create_uniform(device),
+            skybox_view,
+            irradiance_view,
+            prefiltered_view,
+            brdf_lut_view,
+            sampler: create_sampler(device),
+            enabled: false,
+        }
+    }
+
+    /// Fails if the panorama doesn't fit in the maximum 2D texture size of the device.
+    pub fn new(device: &Device, queue: &Queue, image: &HdrImage) -> io::Result<Self> {
+        let max_dim = device.limits().max_texture_dimension_2d;
+        if image.dim.max_element() > max_dim {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "HDR panorama of {}x{} exceeds the maximum texture size of {}",
+                    image.dim.x, image.dim.y, max_dim
+                ),
+            ));
+        }
+        if image.pixels.len() != image.dim.element_product() as usize {
+            return Err(invalid_data("HDR pixels don't match the dimensions"));
+        }
+        let shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("environment.wgsl"))),
+        });
+        let unfilterable = TextureSampleType::Float { filterable: false };
+        let filterable = TextureSampleType::Float { filterable: true };
+        let equirect_pass = GenerationPass::new(
+            device,
+            &shader_module,
+            &[
+                texture_entry(0, unfilterable, TextureViewDimension::D2),
+                storage_entry(5, TextureViewDimension::D2Array),
+            ],
+            "equirect_to_cube",
+        );
+        let downsample_pass = GenerationPass::new(
+            device,
+            &shader_module,
+            &[
+                texture_entry(1, unfilterable, TextureViewDimension::D2Array),
+                storage_entry(5, TextureViewDimension::D2Array),
+            ],
+            "downsample",
+        );
+        let sampler_entry = BindGroupLayoutEntry {
+            binding: 3,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Sampler(SamplerBindingType::Filtering),
+            count: None,
+        };
+        let irradiance_pass = GenerationPass::new(
+            device,
+            &shader_module,
+            &[
+                texture_entry(2, filterable, TextureViewDimension::Cube),
+                sampler_entry,
+                storage_entry(5, TextureViewDimension::D2Array),
+            ],
+            "irradiance",
+        );
+        let prefilter_pass = GenerationPass::new(
+            device,
+            &shader_module,
+            &[
+                texture_entry(2, filterable, TextureViewDimension::Cube),
+                sampler_entry,
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage_entry(5, TextureViewDimension::D2Array),
+            ],
+            "prefilter",
+        );
+        let brdf_lut_pass = GenerationPass::new(
+            device,
+            &shader_module,
+            &[storage_entry(6, TextureViewDimension::D2)],
+            "brdf_lut",
+        );
+
+        let panorama_view = device
+            .create_texture_with_data(
+                queue,
+                &TextureDescriptor {
+                    label: None,
+                    size: Extent3d {
+                        width: image.dim.x,
+                        height: image.dim.y,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: TextureFormat::Rgba32Float,
+                    usage: TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                },
+                TextureDataOrder::LayerMajor,
+                bytemuck::cast_slice(&image.pixels),
+            )
+            .create_view(&TextureViewDescriptor::default());
+        let cube_mips = CUBE_SIZE.ilog2() + 1;
+        let skybox = create_cube(device, CUBE_SIZE, cube_mips);
+        let irradiance = create_cube(device, IRRADIANCE_SIZE, 1);
+        let prefiltered = create_cube(device, PREFILTERED_SIZE, PREFILTERED_MIPS);
+        let brdf_lut = device.create_texture(&TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: BRDF_LUT_SIZE,
+                height: BRDF_LUT_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: FORMAT,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::STORAGE_BINDING,
+            view_formats: &[],
+        });
+        let skybox_view = create_cube_view(&skybox);
+        let sampler = create_sampler(device);
+        let prefilter_params = (0..PREFILTERED_MIPS)
+            .map(|mip| {
+                let roughness = mip as f32 / (PREFILTERED_MIPS - 1) as f32;
+                device.create_buffer_init(&BufferInitDescriptor {
+                    label: None,
+                    contents: bytemuck::cast_slice(&[roughness, CUBE_SIZE as f32, 0., 0.]),
+                    usage: BufferUsages::UNIFORM,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut command_encoder =
+            device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        equirect_pass.dispatch(
+            device,
+            &mut command_encoder,
+            &[
+                (0, BindingResource::TextureView(&panorama_view)),
+                (
+                    5,
+                    BindingResource::TextureView(&create_mip_view(&skybox, 0)),
+                ),
+            ],
+            CUBE_SIZE,
+            6,
+        );
+        for mip in 1..cube_mips {
+            downsample_pass.dispatch(
+                device,
+                &mut command_encoder,
+                &[
+                    (
+                        1,
+                        BindingResource::TextureView(&create_mip_view(&skybox, mip - 1)),
+                    ),
+                    (
+                        5,
+                        BindingResource::TextureView(&create_mip_view(&skybox, mip)),
+                    ),
+                ],
+                CUBE_SIZE >> mip,
+                6,
+            );
+        }
+        irradiance_pass.dispatch(
+            device,
+            &mut command_encoder,
+            &[
+                (2, BindingResource::TextureView(&skybox_view)),
+                (3, BindingResource::Sampler(&sampler)),
+                (
+                    5,
+                    BindingResource::TextureView(&create_mip_view(&irradiance, 0)),
+                ),
+            ],
+            IRRADIANCE_SIZE,
+            6,
+        );
+        for (mip, params) in prefilter_params.iter().enumerate() {
+            let mip = mip as u32;
+            prefilter_pass.dispatch(
+                device,
+                &mut command_encoder,
+                &[
+                    (2, BindingResource::TextureView(&skybox_view)),
+                    (3, BindingResource::Sampler(&sampler)),
+                    (4, params.as_entire_binding()),
+                    (
+                        5,
+                        BindingResource::TextureView(&create_mip_view(&prefiltered, mip)),
+                    ),
+                ],
+                PREFILTERED_SIZE >> mip,
+                6,
+            );
+        }
+        brdf_lut_pass.dispatch(
+            device,
+            &mut command_encoder,
+            &[(
+                6,
+                BindingResource::TextureView(
+                    &brdf_lut.create_view(&TextureViewDescriptor::default()),
+                ),
+            )],
+            BRDF_LUT_SIZE,
+            1,
+        );
+        queue.submit(Some(command_encoder.finish()));
+
+        Ok(Self {
+            uniform: create_uniform(device),
+            skybox_view,
+            irradiance_view: create_cube_view(&irradiance),
+            prefiltered_view: create_cube_view(&prefiltered),
+            brdf_lut_view: brdf_lut.create_view(&TextureViewDescriptor::default()),
+            sampler,
+            enabled: true,
+        })
+    }
+
+    pub fn update(&self, queue: &Queue, intensity: f32) {
+        let max_level = (PREFILTERED_MIPS - 1) as f32;
+        let mut raw = Vec::with_capacity(16);
+        raw.extend_from_slice(bytemuck::cast_slice(&[intensity, max_level]));
+        raw.extend_from_slice(bytemuck::cast_slice(&[self.enabled as u32, 0]));
+        queue.write_buffer(&self.uniform, 0, &raw);
+    }
+
+    #[inline]
+    pub fn uniform_binding(&self) -> BindingResource<'_> {
+        self.uniform.as_entire_binding()
+    }
+}
+
+fn create_uniform(device: &Device) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: None,
+        size: 16,
+        usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_sampler(device: &Device) -> Sampler {
+    device.create_sampler(&SamplerDescriptor {
+        mag_filter: FilterMode::Linear,
+        min_filter: FilterMode::Linear,
+        mipmap_filter: FilterMode::Linear,
+        ..Default::default()
+    })
+}
+
+/// The `fragment` entry point of `skybox.wgsl` draws the environment, `fragment_sky`
+/// the procedural sky.
+pub(crate) fn create_skybox_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    motion_vectors: bool,
+    fragment_entry_point: &str,
+) -> RenderPipeline {
+    // The background has no motion vectors, TAA reprojects it with the camera.
+    let targets = [Some(format.into()), Some(taa::motion_target(false))];
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: PipelineCompilationOptions::default(),
+            buffers: &[],
+        },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: fragment_entry_point,
+            compilation_options: PipelineCompilationOptions::default(),
+            targets: &targets[..1 + motion_vectors as usize],
+        }),
+        primitive: PrimitiveState::default(),
+        // Only covers the pixels left at the cleared depth.
+        depth_stencil: Some(DepthStencilState {
+            format: TextureFormat::Depth24Plus,
+            depth_write_enabled: false,
+            depth_compare: CompareFunction::LessEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hdr_file(resolution: &str, pixels: &[u8]) -> Vec<u8> {
+        let header = format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n{resolution}\n");
+        [header.as_bytes(), pixels].concat()
+    }
+
+    #[test]
+    fn decode_flat() {
+        let pixels = [128, 64, 0, 129, 0, 0, 0, 0];
+        let image = HdrImage::decode(&hdr_file("-Y 1 +X 2", &pixels)).unwrap();
+        assert_eq!(image.dim, UVec2::new(2, 1));
+        assert_eq!(image.pixels, [Vec4::new(1., 0.5, 0., 1.), Vec4::W]);
+    }
+
+    #[test]
+    fn decode_run_length() {
+        // A run of 8 for red, green and blue, and literals for the exponent.
+        let mut pixels = vec![2, 2, 0, 8];
+        for value in [128, 64, 0] {
+            pixels.extend([128 + 8, value]);
+        }
+        pixels.extend([8, 129, 129, 129, 129, 128, 128, 128, 128]);
+        let image = HdrImage::decode(&hdr_file("-Y 1 +X 8", &pixels)).unwrap();
+        assert_eq!(image.dim, UVec2::new(8, 1));
+        assert_eq!(image.pixels[..4], [Vec4::new(1., 0.5, 0., 1.); 4]);
+        assert_eq!(image.pixels[4..], [Vec4::new(0.5, 0.25, 0., 1.); 4]);
+    }
+
+    #[test]
+    fn decode_rejects_invalid_headers() {
+        assert!(HdrImage::decode(b"P6\n1 1\n255\n").is_err());
+        assert!(HdrImage::decode(&hdr_file("+Y 1 +X 1", &[0; 4])).is_err());
+        assert!(HdrImage::decode(&hdr_file("-Y 0 +X 1", &[])).is_err());
+        let error = HdrImage::decode(&hdr_file("-Y 65536 +X 65536", &[0; 4])).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decode_rejects_truncated_pixels() {
+        assert!(HdrImage::decode(&hdr_file("-Y 2 +X 2", &[0; 12])).is_err());
+        // The run goes past the end of the scanline.
+        let pixels = [2, 2, 0, 8, 128 + 9, 0];
+        assert!(HdrImage::decode(&hdr_file("-Y 1 +X 8", &pixels)).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+// Converts an equirectangular panorama to a cubemap and precomputes the split sum
+// approximation of image based lighting: diffuse irradiance, specular radiance
+// prefiltered for increasing roughness, and the BRDF integrated over the view angle.
+
+struct PrefilterParams {
+    roughness: f32,
+    // Of the first mip of the source cubemap, to pick the mip matching every sample.
+    source_size: f32,
+}
+
+@group(0) @binding(0) var panorama: texture_2d<f32>;
+@group(0) @binding(1) var source_mip: texture_2d_array<f32>;
+@group(0) @binding(2) var source_cube: texture_cube<f32>;
+@group(0) @binding(3) var source_sampler: sampler;
+@group(0) @binding(4) var<uniform> prefilter_params: PrefilterParams;
+@group(0) @binding(5) var output_faces: texture_storage_2d_array<rgba16float, write>;
+@group(0) @binding(6) var output_lut: texture_storage_2d<rgba16float, write>;
+
+const PI: f32 = 3.14159265;
+const IRRADIANCE_STEP: f32 = 0.025;
+const PREFILTER_SAMPLES: u32 = 1024u;
+const BRDF_SAMPLES: u32 = 1024u;
+
+// Direction through the texel at `uv` in [-1, 1] of a face, in the order and
+// orientation sampled by cube textures.
+fn cube_direction(face: u32, uv: vec2f) -> vec3f {
+    switch face {
+        case 0u: { return normalize(vec3f(1., -uv.y, -uv.x)); }
+        case 1u: { return normalize(vec3f(-1., -uv.y, uv.x)); }
+        case 2u: { return normalize(vec3f(uv.x, 1., uv.y)); }
+        case 3u: { return normalize(vec3f(uv.x, -1., -uv.y)); }
+        case 4u: { return normalize(vec3f(uv.x, -uv.y, 1.)); }
+        default: { return normalize(vec3f(-uv.x, -uv.y, -1.)); }
+    }
+}
+
+fn face_direction(id: vec3u) -> vec3f {
+    let size = vec2f(textureDimensions(output_faces));
+    let uv = (vec2f(id.xy) + 0.5) / size * 2. - 1.;
+    return cube_direction(id.z, uv);
+}
+
+fn load_panorama(texel: vec2i) -> vec3f {
+    let size = vec2i(textureDimensions(panorama));
+    // Wraps around horizontally, clamps at the poles.
+    let wrapped = vec2i((texel.x % size.x + size.x) % size.x, clamp(texel.y, 0, size.y - 1));
+    return textureLoad(panorama, wrapped, 0).rgb;
+}
+
+fn tangent_frame(normal: vec3f) -> mat3x3f {
+    let up = select(vec3f(1., 0., 0.), vec3f(0., 1., 0.), abs(normal.y) < 0.999);
+    let tangent = normalize(cross(up, normal));
+    return mat3x3f(tangent, cross(normal, tangent), normal);
+}
+
+fn hammersley(i: u32, count: u32) -> vec2f {
+    return vec2f(f32(i) / f32(count), f32(reverseBits(i)) * 2.3283064365386963e-10);
+}
+
+// A half vector around +Z distributed like GGX with `roughness`.
+fn importance_sample_ggx(xi: vec2f, roughness: f32) -> vec3f {
+    let alpha = roughness * roughness;
+    let phi = 2. * PI * xi.x;
+    let cos_theta = sqrt((1. - xi.y) / (1. + (alpha * alpha - 1.) * xi.y));
+    let sin_theta = sqrt(1. - cos_theta * cos_theta);
+    return vec3f(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let alpha2 = pow(roughness, 4.);
+    let denom = n_dot_h * n_dot_h * (alpha2 - 1.) + 1.;
+    return alpha2 / (PI * denom * denom);
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn equirect_to_cube(@builtin(global_invocation_id) id: vec3u) {
+    if any(id.xy >= textureDimensions(output_faces)) {
+        return;
+    }
+
+    let direction = face_direction(id);
+    let size = vec2f(textureDimensions(panorama));
+    let uv = vec2f(atan2(direction.z, direction.x) / (2. * PI) + 0.5, acos(direction.y) / PI);
+
+    // Filtered by hand, as 32 bit float textures aren't filterable everywhere.
+    let texel = uv * size - 0.5;
+    let base = vec2i(floor(texel));
+    let t = fract(texel);
+    let color = mix(
+        mix(load_panorama(base), load_panorama(base + vec2i(1, 0)), t.x),
+        mix(load_panorama(base + vec2i(0, 1)), load_panorama(base + vec2i(1, 1)), t.x),
+        t.y,
+    );
+    textureStore(output_faces, id.xy, id.z, vec4f(color, 1.));
+}
+
+// Box filters the previous mip of every face.
+@compute @workgroup_size(8, 8, 1)
+fn downsample(@builtin(global_invocation_id) id: vec3u) {
+    if any(id.xy >= textureDimensions(output_faces)) {
+        return;
+    }
+
+    let texel = vec2i(id.xy) * 2;
+    let layer = i32(id.z);
+    let color = textureLoad(source_mip, texel, layer, 0)
+        + textureLoad(source_mip, texel + vec2i(1, 0), layer, 0)
+        + textureLoad(source_mip, texel + vec2i(0, 1), layer, 0)
+        + textureLoad(source_mip, texel + vec2i(1, 1), layer, 0);
+    textureStore(output_faces, id.xy, id.z, color / 4.);
+}
+
+// Cosine weighted integral of the radiance over the hemisphere around every direction.
+@compute @workgroup_size(8, 8, 1)
+fn irradiance(@builtin(global_invocation_id) id: vec3u) {
+    if any(id.xy >= textureDimensions(output_faces)) {
+        return;
+    }
+
+    let frame = tangent_frame(face_direction(id));
+    // The irradiance is smooth, a small mip avoids aliasing with the coarse steps.
+    let level = max(f32(textureNumLevels(source_cube)) - 6., 0.);
+
+    var irradiance = vec3f(0.);
+    var samples = 0.;
+    for (var phi = 0.; phi < 2. * PI; phi += IRRADIANCE_STEP * 2.) {
+        for (var theta = 0.; theta < 0.5 * PI; theta += IRRADIANCE_STEP) {
+            let local = vec3f(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let radiance = textureSampleLevel(source_cube, source_sampler, frame * local, level).rgb;
+            irradiance += radiance * cos(theta) * sin(theta);
+            samples += 1.;
+        }
+    }
+    textureStore(output_faces, id.xy, id.z, vec4f(PI * irradiance / samples, 1.));
+}
+
+// Convolves the radiance with GGX lobes of `prefilter_params.roughness`, assuming the
+// view direction matches the normal.
+@compute @workgroup_size(8, 8, 1)
+fn prefilter(@builtin(global_invocation_id) id: vec3u) {
+    if any(id.xy >= textureDimensions(output_faces)) {
+        return;
+    }
+
+    let normal = face_direction(id);
+    let frame = tangent_frame(normal);
+    let roughness = prefilter_params.roughness;
+    let texel_solid_angle = 4. * PI / (6. * prefilter_params.source_size * prefilter_params.source_size);
+
+    var radiance = vec3f(0.);
+    var weights = 0.;
+    for (var i = 0u; i < PREFILTER_SAMPLES; i += 1u) {
+        let half_dir = frame * importance_sample_ggx(hammersley(i, PREFILTER_SAMPLES), roughness);
+        let to_light = reflect(-normal, half_dir);
+        let n_dot_l = dot(normal, to_light);
+        if n_dot_l <= 0. {
+            continue;
+        }
+
+        // Samples covering more texels read from smaller mips, against fireflies.
+        let n_dot_h = max(dot(normal, half_dir), 0.);
+        let pdf = distribution_ggx(n_dot_h, roughness) / 4. + 0.0001;
+        let sample_solid_angle = 1. / (f32(PREFILTER_SAMPLES) * pdf);
+        let level = select(0.5 * log2(sample_solid_angle / texel_solid_angle) + 1., 0., roughness == 0.);
+
+        radiance += textureSampleLevel(source_cube, source_sampler, to_light, level).rgb * n_dot_l;
+        weights += n_dot_l;
+    }
+    textureStore(output_faces, id.xy, id.z, vec4f(radiance / weights, 1.));
+}
+
+fn geometry_schlick(n_dot_v: f32, roughness: f32) -> f32 {
+    // The remapping for image based lighting.
+    let k = roughness * roughness / 2.;
+    return n_dot_v / (n_dot_v * (1. - k) + k);
+}
+
+// Scale and bias applied to the reflectance at normal incidence, indexed by the cosine
+// of the view angle and the roughness.
+@compute @workgroup_size(8, 8, 1)
+fn brdf_lut(@builtin(global_invocation_id) id: vec3u) {
+    let size = textureDimensions(output_lut);
+    if any(id.xy >= size) {
+        return;
+    }
+
+    let uv = (vec2f(id.xy) + 0.5) / vec2f(size);
+    let n_dot_v = uv.x;
+    let roughness = uv.y;
+    let to_view = vec3f(sqrt(1. - n_dot_v * n_dot_v), 0., n_dot_v);
+
+    var scale = 0.;
+    var bias = 0.;
+    for (var i = 0u; i < BRDF_SAMPLES; i += 1u) {
+        let half_dir = importance_sample_ggx(hammersley(i, BRDF_SAMPLES), roughness);
+        let to_light = reflect(-to_view, half_dir);
+        let n_dot_l = saturate(to_light.z);
+        let n_dot_h = saturate(half_dir.z);
+        let v_dot_h = saturate(dot(to_view, half_dir));
+        if n_dot_l <= 0. {
+            continue;
+        }
+
+        let geometry = geometry_schlick(n_dot_v, roughness) * geometry_schlick(n_dot_l, roughness);
+        let visibility = geometry * v_dot_h / (n_dot_h * n_dot_v);
+        let fresnel = pow(1. - v_dot_h, 5.);
+        scale += (1. - fresnel) * visibility;
+        bias += fresnel * visibility;
+    }
+    textureStore(output_lut, id.xy, vec4f(scale, bias, 0., 0.) / f32(BRDF_SAMPLES));
+}
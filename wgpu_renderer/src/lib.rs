@@ -1,22 +1,60 @@
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     fs::File,
-    io::{Read, Write},
+    io::{self, Write},
     path::Path,
     time::Instant,
 };
 
-use buffer::StorageBuffer;
-use glam::{Mat4, UVec2};
+use animation::SkinnedModel;
+use assets::MeshHandle;
+use atmosphere::{FogConfig, GpuAtmosphere, SkyConfig};
+use bloom::{Bloom, BloomConfig};
+use buffer::{read_texture_blocking, StorageBuffer};
+use compute::ComputePass;
+use context::GpuContext;
+use debug::{DebugLines, DebugVertex};
+use deferred::Deferred;
+use environment::{Environment, HdrImage};
+use glam::{Mat4, UVec2, UVec3, Vec2};
+use material::Materials;
 use png::ColorType;
-use render::{GpuCamera, GpuDirectionalLight, GpuMesh, Vertex};
-use scene::{Camera, DirectionalLight};
+use render::{
+    AlphaMode, GpuCamera, GpuDirectionalLight, GpuPointLight, RenderPath, TransparencyMode, Vertex,
+    ViewMode,
+};
+use scene::{Aabb, Camera, DirectionalLight, PointLight, Ray};
+use shadow::{ShadowCascade, ShadowFilter, ShadowMaps};
+use ssao::{Ssao, SsaoConfig};
+use stats::{FrameStats, GpuTimer, StatsRecorder};
+use taa::{Taa, TaaConfig};
+use transparency::Transparency;
+use user::UserBindings;
 use wgpu::{util::*, *};
 
 use crate::render::ShaderData;
 
+pub mod animation;
+pub mod assets;
+pub mod atmosphere;
+pub mod bloom;
 pub mod buffer;
+pub mod compute;
+pub mod context;
+pub mod debug;
+mod deferred;
+pub mod environment;
+mod material;
 pub mod render;
 pub mod scene;
+pub mod shadow;
+pub mod ssao;
+pub mod stats;
+pub mod taa;
+pub mod texture;
+mod transparency;
+mod user;
 
 fn create_depth_targets(device: &Device, dim: UVec2) -> (Texture, TextureView) {
     let target = device.create_texture(&TextureDescriptor {
@@ -30,13 +68,441 @@ fn create_depth_targets(device: &Device, dim: UVec2) -> (Texture, TextureView) {
         sample_count: 1,
         dimension: TextureDimension::D2,
         format: TextureFormat::Depth24Plus,
-        usage: TextureUsages::RENDER_ATTACHMENT,
+        // Sampled by the lighting pass of the deferred path.
+        usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
         view_formats: &[TextureFormat::Depth24Plus],
     });
     let target_view = target.create_view(&TextureViewDescriptor::default());
     (target, target_view)
 }
 
+/// The automatic modes are supported everywhere, falling back on their own.
+fn supported_present_mode(capabilities: &SurfaceCapabilities, mode: PresentMode) -> PresentMode {
+    if capabilities.present_modes.contains(&mode)
+        || matches!(mode, PresentMode::AutoVsync | PresentMode::AutoNoVsync)
+    {
+        mode
+    } else {
+        log::warn!("Surface doesn't support {:?}, falling back to Fifo.", mode);
+        PresentMode::Fifo
+    }
+}
+
+const VERTEX_ATTRIBUTES: [VertexAttribute; 5] = vertex_attr_array![
+    0 => Float32x3,
+    1 => Float32x3,
+    2 => Uint16x4,
+    3 => Float32x4,
+    4 => Float32x2,
+];
+
+fn vertex_buffer_layout() -> VertexBufferLayout<'static> {
+    VertexBufferLayout {
+        array_stride: std::mem::size_of::<Vertex>() as BufferAddress,
+        step_mode: VertexStepMode::Vertex,
+        attributes: &VERTEX_ATTRIBUTES,
+    }
+}
+
+/// Prepends `skinning.wgsl`, declaring the joints of the mesh bind group and
+/// `skin_matrix`, to the WGSL shader of meshes.
+pub(crate) fn with_skinning(source: &str) -> ShaderSource<'static> {
+    ShaderSource::Wgsl(Cow::Owned(format!(
+        "{}\n{}",
+        include_str!("skinning.wgsl"),
+        source
+    )))
+}
+
+/// `shader_defs` are the values of override constants in the shader, constants it
+/// doesn't declare are ignored. With `motion_vectors`, the `fragment_taa` entry point
+/// also writes them to a second target.
+fn create_scene_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    vertex_buffers: &[VertexBufferLayout],
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    motion_vectors: bool,
+    shader_defs: &HashMap<String, f64>,
+) -> RenderPipeline {
+    let compilation_options = PipelineCompilationOptions {
+        constants: shader_defs,
+        ..Default::default()
+    };
+    let targets = [Some(format.into()), Some(taa::motion_target(true))];
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: compilation_options.clone(),
+            buffers: vertex_buffers,
+        },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: match motion_vectors {
+                true => "fragment_taa",
+                false => "fragment",
+            },
+            compilation_options,
+            targets: &targets[..1 + motion_vectors as usize],
+        }),
+        primitive: PrimitiveState::default(),
+        depth_stencil: Some(DepthStencilState {
+            format: TextureFormat::Depth24Plus,
+            depth_write_enabled: true,
+            depth_compare: CompareFunction::LessEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+/// Returns `None` for [`ViewMode::Shaded`], which uses the scene pipeline.
+fn create_view_mode_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    motion_vectors: bool,
+    view_mode: ViewMode,
+) -> Option<RenderPipeline> {
+    let line_polygons = device.features().contains(Features::POLYGON_MODE_LINE);
+    let entry_point = match view_mode {
+        ViewMode::Shaded => return None,
+        ViewMode::Wireframe if line_polygons => "fragment_wireframe_lines",
+        ViewMode::Wireframe => "fragment_wireframe",
+        ViewMode::Normals => "fragment_normals",
+        ViewMode::Uvs => "fragment_uvs",
+        ViewMode::Depth => "fragment_depth",
+        ViewMode::Overdraw => "fragment_overdraw",
+        ViewMode::LightOnly => "fragment_light",
+    };
+    let overdraw = view_mode == ViewMode::Overdraw;
+    let targets = [
+        Some(ColorTargetState {
+            format,
+            blend: overdraw.then_some(BlendState {
+                color: BlendComponent {
+                    src_factor: BlendFactor::One,
+                    dst_factor: BlendFactor::One,
+                    operation: BlendOperation::Add,
+                },
+                alpha: BlendComponent::REPLACE,
+            }),
+            write_mask: ColorWrites::ALL,
+        }),
+        Some(taa::motion_target(false)),
+    ];
+
+    Some(device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: PipelineCompilationOptions::default(),
+            buffers: &[vertex_buffer_layout()],
+        },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point,
+            compilation_options: PipelineCompilationOptions::default(),
+            targets: &targets[..1 + motion_vectors as usize],
+        }),
+        primitive: PrimitiveState {
+            polygon_mode: match view_mode {
+                ViewMode::Wireframe if line_polygons => PolygonMode::Line,
+                _ => PolygonMode::Fill,
+            },
+            ..Default::default()
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: TextureFormat::Depth24Plus,
+            depth_write_enabled: !overdraw,
+            depth_compare: match overdraw {
+                true => CompareFunction::Always,
+                false => CompareFunction::LessEqual,
+            },
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState::default(),
+        multiview: None,
+    }))
+}
+
+fn create_outline_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    motion_vectors: bool,
+) -> RenderPipeline {
+    let targets = [Some(format.into()), Some(taa::motion_target(false))];
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: PipelineCompilationOptions::default(),
+            buffers: &[vertex_buffer_layout()],
+        },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "fragment",
+            compilation_options: PipelineCompilationOptions::default(),
+            targets: &targets[..1 + motion_vectors as usize],
+        }),
+        primitive: PrimitiveState {
+            cull_mode: Some(Face::Front),
+            ..Default::default()
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: TextureFormat::Depth24Plus,
+            depth_write_enabled: false,
+            depth_compare: CompareFunction::LessEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+fn create_debug_lines_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    motion_vectors: bool,
+    depth_test: bool,
+) -> RenderPipeline {
+    let targets = [Some(format.into()), Some(taa::motion_target(false))];
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: PipelineCompilationOptions::default(),
+            buffers: &[VertexBufferLayout {
+                array_stride: std::mem::size_of::<DebugVertex>() as BufferAddress,
+                step_mode: VertexStepMode::Vertex,
+                attributes: &vertex_attr_array![0 => Float32x3, 1 => Float32x3],
+            }],
+        },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "fragment",
+            compilation_options: PipelineCompilationOptions::default(),
+            targets: &targets[..1 + motion_vectors as usize],
+        }),
+        primitive: PrimitiveState {
+            topology: PrimitiveTopology::LineList,
+            ..Default::default()
+        },
+        depth_stencil: Some(DepthStencilState {
+            format: TextureFormat::Depth24Plus,
+            depth_write_enabled: false,
+            depth_compare: match depth_test {
+                true => CompareFunction::LessEqual,
+                false => CompareFunction::Always,
+            },
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+fn create_scene_layout(device: &Device) -> BindGroupLayout {
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: None,
+        entries: &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX_FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: GpuCamera::min_binding_size(),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: ShadowCascade::min_binding_size(),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Depth,
+                    view_dimension: TextureViewDimension::D2Array,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Sampler(SamplerBindingType::Comparison),
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 5,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2Array,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 6,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 8,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: GpuPointLight::min_binding_size(),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 9,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 10,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::Cube,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 11,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::Cube,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 12,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 13,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 14,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::Cube,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 15,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: GpuAtmosphere::min_binding_size(),
+                },
+                count: None,
+            },
+        ],
+    })
+}
+
+fn create_mesh_layout(device: &Device) -> BindGroupLayout {
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: None,
+        entries: &[BindGroupLayoutEntry {
+            binding: 0,
+            visibility: ShaderStages::VERTEX,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: Mat4::min_binding_size(),
+            },
+            count: None,
+        }],
+    })
+}
+
+fn create_mesh_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    joints: BindingResource,
+) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
+        label: None,
+        layout,
+        entries: &[BindGroupEntry {
+            binding: 0,
+            resource: joints,
+        }],
+    })
+}
+
 pub struct WgpuImageRenderer {
     internal: WgpuRenderer,
     target: Texture,
@@ -48,29 +514,23 @@ pub struct WgpuImageRenderer {
 impl WgpuImageRenderer {
     pub async fn new(
         dim: UVec2,
-        shader: ShaderSource<'_>,
+        shader: ShaderSource<'static>,
         renderer_config: Option<RendererConfig>,
     ) -> Self {
-        let renderer = WgpuRenderer::new(shader, renderer_config.clone()).await;
-        let config = renderer_config.unwrap_or_default();
-
-        let target = renderer.device.create_texture(&TextureDescriptor {
-            label: None,
-            size: Extent3d {
-                width: dim.x,
-                height: dim.y,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: TextureDimension::D2,
-            format: config.primary_target_format,
-            usage: TextureUsages::RENDER_ATTACHMENT,
-            view_formats: &[config.primary_target_format],
-        });
-        let target_view = target.create_view(&TextureViewDescriptor::default());
+        Self::with_context(GpuContext::new().await, dim, shader, renderer_config)
+    }
 
-        let (depth_target, depth_target_view) = create_depth_targets(&renderer.device, dim);
+    pub fn with_context(
+        context: GpuContext,
+        dim: UVec2,
+        shader: ShaderSource<'static>,
+        renderer_config: Option<RendererConfig>,
+    ) -> Self {
+        let mut renderer = WgpuRenderer::with_context(context, shader, renderer_config);
+        renderer.resize(dim);
+        let (target, target_view) = Self::create_target(&renderer, dim);
+        let (depth_target, depth_target_view) =
+            create_depth_targets(renderer.context.device(), dim);
 
         Self {
             internal: renderer,
@@ -81,6 +541,29 @@ impl WgpuImageRenderer {
         }
     }
 
+    fn create_target(renderer: &WgpuRenderer, dim: UVec2) -> (Texture, TextureView) {
+        let format = renderer.config.primary_target_format;
+        let target = renderer
+            .context
+            .device()
+            .create_texture(&TextureDescriptor {
+                label: None,
+                size: Extent3d {
+                    width: dim.x,
+                    height: dim.y,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format,
+                usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+                view_formats: &[format],
+            });
+        let target_view = target.create_view(&TextureViewDescriptor::default());
+        (target, target_view)
+    }
+
     #[inline]
     pub fn renderer(&self) -> &WgpuRenderer {
         &self.internal
@@ -92,63 +575,30 @@ impl WgpuImageRenderer {
     }
 
     pub async fn draw(&mut self) {
+        if self.internal.is_device_lost() {
+            self.internal.recover_device().await;
+            let size = self.target.size();
+            let dim = UVec2::new(size.width, size.height);
+            (self.target, self.target_view) = Self::create_target(&self.internal, dim);
+            (self.depth_target, self.depth_target_view) =
+                create_depth_targets(self.internal.context.device(), dim);
+        }
+
         self.internal
             .draw(&self.target_view, &self.depth_target_view);
     }
 
     pub async fn save_result(&self, path: impl AsRef<Path>) {
         let extent = self.target.size();
-        let mut texture_data =
-            Vec::<u8>::with_capacity((extent.width * extent.height * 4) as usize);
-
-        let out_staging_buffer = self.internal.device.create_buffer(&BufferDescriptor {
-            label: None,
-            size: texture_data.capacity() as u64,
-            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
-            mapped_at_creation: false,
-        });
-
-        let mut command_encoder = self
-            .internal
-            .device
-            .create_command_encoder(&CommandEncoderDescriptor { label: None });
-        command_encoder.copy_texture_to_buffer(
-            ImageCopyTexture {
-                texture: &self.target,
-                mip_level: 0,
-                origin: Origin3d::ZERO,
-                aspect: TextureAspect::All,
-            },
-            ImageCopyBuffer {
-                buffer: &out_staging_buffer,
-                layout: ImageDataLayout {
-                    offset: 0,
-                    bytes_per_row: Some(extent.width * 4 as u32),
-                    rows_per_image: Some(extent.height as u32),
-                },
-            },
-            extent,
-        );
-        self.internal.queue.submit(Some(command_encoder.finish()));
+        let texture_data = match read_texture_blocking(&self.internal.context, &self.target) {
+            Ok(texture_data) => texture_data,
+            Err(err) => {
+                log::error!("Couldn't read back the render result: {}", err);
+                return;
+            }
+        };
         log::info!("Copied texture");
 
-        let buffer_slice = out_staging_buffer.slice(..);
-        let (sender, receiver) = flume::bounded(1);
-
-        buffer_slice.map_async(MapMode::Read, move |r| sender.send(r).unwrap());
-        self.internal
-            .device
-            .poll(Maintain::wait())
-            .panic_on_timeout();
-        receiver.recv_async().await.unwrap().unwrap();
-
-        {
-            let view = buffer_slice.get_mapped_range();
-            texture_data.extend_from_slice(&view[..]);
-        }
-
-        out_staging_buffer.unmap();
-
         let mut png_image = Vec::with_capacity(texture_data.capacity());
         let mut encoder = png::Encoder::new(
             std::io::Cursor::new(&mut png_image),
@@ -170,8 +620,10 @@ impl WgpuImageRenderer {
 pub struct WgpuSurfaceRenderer<'r> {
     internal: WgpuRenderer,
     surface: Surface<'r>,
+    surface_config: SurfaceConfiguration,
     depth_target: Texture,
     depth_target_view: TextureView,
+    last_frame_instant: Option<Instant>,
     last_printed_instant: Instant,
     frame_count: u32,
 }
@@ -180,18 +632,68 @@ impl<'r> WgpuSurfaceRenderer<'r> {
     pub async fn new(
         target: impl Into<SurfaceTarget<'r>>,
         dim: UVec2,
-        shader: ShaderSource<'_>,
+        shader: ShaderSource<'static>,
+        renderer_config: Option<RendererConfig>,
+    ) -> Self {
+        Self::with_context(
+            GpuContext::new().await,
+            target,
+            dim,
+            shader,
+            renderer_config,
+        )
+    }
+
+    pub fn with_context(
+        context: GpuContext,
+        target: impl Into<SurfaceTarget<'r>>,
+        dim: UVec2,
+        shader: ShaderSource<'static>,
         renderer_config: Option<RendererConfig>,
     ) -> Self {
-        let renderer = WgpuRenderer::new(shader, renderer_config).await;
-        let surface = renderer.instance.create_surface(target).unwrap();
-        let (depth_target, depth_target_view) = create_depth_targets(&renderer.device, dim);
+        let mut renderer = WgpuRenderer::with_context(context, shader, renderer_config);
+        let surface = renderer.context.instance().create_surface(target).unwrap();
+        let capabilities = surface.get_capabilities(renderer.context.adapter());
+
+        let config = renderer.config.clone();
+        let format = if capabilities.formats.contains(&config.primary_target_format) {
+            config.primary_target_format
+        } else {
+            let fallback = capabilities
+                .formats
+                .iter()
+                .copied()
+                .find(TextureFormat::is_srgb)
+                .unwrap_or(capabilities.formats[0]);
+            log::warn!(
+                "Surface doesn't support {:?}, using {:?} instead.",
+                config.primary_target_format,
+                fallback
+            );
+            fallback
+        };
+        renderer.set_target_format(format);
+
+        let surface_config = SurfaceConfiguration {
+            usage: TextureUsages::RENDER_ATTACHMENT,
+            format,
+            width: dim.x,
+            height: dim.y,
+            present_mode: supported_present_mode(&capabilities, config.present_mode),
+            desired_maximum_frame_latency: config.desired_maximum_frame_latency,
+            alpha_mode: capabilities.alpha_modes[0],
+            view_formats: Vec::new(),
+        };
+        let (depth_target, depth_target_view) =
+            create_depth_targets(renderer.context.device(), dim.max(UVec2::ONE));
 
         let mut sr = Self {
             internal: renderer,
             surface,
+            surface_config,
             depth_target,
             depth_target_view,
+            last_frame_instant: None,
             last_printed_instant: Instant::now(),
             frame_count: 0,
         };
@@ -200,34 +702,93 @@ impl<'r> WgpuSurfaceRenderer<'r> {
     }
 
     pub fn resize(&mut self, dim: UVec2) {
+        self.surface_config.width = dim.x;
+        self.surface_config.height = dim.y;
+        // Minimized windows report a zero size, which can't be configured.
+        if self.is_zero_sized() {
+            return;
+        }
+
         (self.depth_target, self.depth_target_view) =
-            create_depth_targets(&self.internal.device, dim);
-        self.surface.configure(
-            &self.internal.device,
-            &SurfaceConfiguration {
-                present_mode: PresentMode::AutoVsync,
-                ..self
-                    .surface
-                    .get_default_config(&self.internal.adapter, dim.x, dim.y)
-                    .unwrap()
-            },
-        );
+            create_depth_targets(self.internal.context.device(), dim);
+        self.internal.resize(dim);
+        self.surface
+            .configure(self.internal.context.device(), &self.surface_config);
     }
 
-    pub fn draw(&mut self) {
-        let Ok(frame) = self.surface.get_current_texture() else {
-            log::error!("Failed to acquire next swap chain texture.");
-            return;
+    #[inline]
+    fn is_zero_sized(&self) -> bool {
+        self.surface_config.width == 0 || self.surface_config.height == 0
+    }
+
+    /// Falls back to [`PresentMode::Fifo`] if the surface doesn't support `present_mode`.
+    pub fn set_present_mode(&mut self, present_mode: PresentMode) {
+        let capabilities = self
+            .surface
+            .get_capabilities(self.internal.context.adapter());
+        let present_mode = supported_present_mode(&capabilities, present_mode);
+        self.internal.config.present_mode = present_mode;
+        self.surface_config.present_mode = present_mode;
+        if !self.is_zero_sized() {
+            self.surface
+                .configure(self.internal.context.device(), &self.surface_config);
+        }
+    }
+
+    #[inline]
+    pub fn surface_config(&self) -> &SurfaceConfiguration {
+        &self.surface_config
+    }
+
+    /// Recreates the renderer and the depth target on a new device. Frames are skipped
+    /// until this is called after [`WgpuRenderer::is_device_lost`].
+    pub async fn recover_device(&mut self) {
+        self.internal.recover_device().await;
+        self.resize(UVec2::new(
+            self.surface_config.width,
+            self.surface_config.height,
+        ));
+    }
+
+    /// Only fails if the surface is out of memory, after which rendering can't continue.
+    pub fn draw(&mut self) -> Result<(), SurfaceError> {
+        if self.is_zero_sized() || self.internal.is_device_lost() {
+            return Ok(());
+        }
+
+        let frame = match self.surface.get_current_texture() {
+            Ok(frame) => frame,
+            Err(SurfaceError::Lost | SurfaceError::Outdated) => {
+                log::warn!("Surface lost or outdated, reconfiguring.");
+                self.surface
+                    .configure(self.internal.context.device(), &self.surface_config);
+                return Ok(());
+            }
+            Err(SurfaceError::Timeout) => {
+                log::warn!("Timed out acquiring next swap chain texture, skipping frame.");
+                return Ok(());
+            }
+            Err(err @ SurfaceError::OutOfMemory) => {
+                log::error!("Failed to acquire next swap chain texture: {}", err);
+                return Err(err);
+            }
         };
         let view = frame.texture.create_view(&TextureViewDescriptor::default());
-        self.internal.draw(&view, &self.depth_target_view);
         self.update_frame_counter();
+        self.internal.draw(&view, &self.depth_target_view);
         frame.present();
+        Ok(())
     }
 
     fn update_frame_counter(&mut self) {
         self.frame_count += 1;
         let new_instant = Instant::now();
+        self.internal.stats.frame_time = self
+            .last_frame_instant
+            .map(|last| new_instant - last)
+            .unwrap_or_default();
+        self.last_frame_instant = Some(new_instant);
+
         let elapsed_secs = (new_instant - self.last_printed_instant).as_secs_f32();
         if elapsed_secs > 1.0 {
             let elapsed_ms = elapsed_secs * 1000.0;
@@ -253,8 +814,46 @@ impl<'r> WgpuSurfaceRenderer<'r> {
 
 #[derive(Clone)]
 pub struct RendererConfig {
+    /// For surfaces, this is only a preference and will be replaced by a format the
+    /// surface supports.
     pub primary_target_format: TextureFormat,
     pub clear_color: Color,
+    /// Use `AutoVsync`/`Fifo` for vsync, `Mailbox` or `Immediate` to disable it.
+    pub present_mode: PresentMode,
+    pub desired_maximum_frame_latency: u32,
+    pub view_mode: ViewMode,
+    /// Width and height of every shadow cascade.
+    pub shadow_map_resolution: u32,
+    /// Darkens the ambient light in creases and contacts, `None` disables it.
+    pub ssao: Option<SsaoConfig>,
+    pub render_path: RenderPath,
+    /// Scales the light of the environment set with [`WgpuRenderer::set_environment`].
+    pub environment_intensity: f32,
+    /// Drawn as the background instead of the clear color, unless there's an
+    /// environment.
+    pub sky: Option<SkyConfig>,
+    pub fog: Option<FogConfig>,
+    /// Makes emissive and other bright surfaces glow. The scene is then rendered to an
+    /// HDR target and tonemapped into the color target, `None` disables it.
+    pub bloom: Option<BloomConfig>,
+    /// Temporal anti-aliasing, which jitters the projection of
+    /// [`WgpuRenderer::set_camera`] and expects it to be called every frame. `None`
+    /// disables it.
+    pub taa: Option<TaaConfig>,
+    /// Draws meshes with [`render::AlphaMode::Blend`] after the opaque ones. `None`
+    /// draws them as opaque, and the scene shader then doesn't need the entry points of
+    /// [`TransparencyMode`].
+    pub transparency: Option<TransparencyMode>,
+    /// Layouts of extra bind groups for the scene shader, from group 3 onwards, set with
+    /// [`WgpuRenderer::set_user_bind_group`] or per mesh with
+    /// [`WgpuRenderer::set_mesh_user_bind_group`]. The deferred lighting pass has the
+    /// G-buffer at group 3, so they only reach the `vertex`, `fragment`, `fragment_taa`
+    /// and `fragment_gbuffer` entry points. Devices may only support a single one.
+    pub user_bind_group_layouts: Vec<Vec<BindGroupLayoutEntry>>,
+    /// Extra vertex attributes of the scene shader, from location 5 onwards, with their
+    /// values in [`render::GpuMesh::attributes`]. Meshes missing a user bind group or
+    /// the attributes aren't drawn by the scene pipeline.
+    pub user_vertex_attributes: Vec<VertexFormat>,
 }
 
 impl Default for RendererConfig {
@@ -262,273 +861,1459 @@ impl Default for RendererConfig {
         Self {
             primary_target_format: TextureFormat::Rgba8Unorm,
             clear_color: Color::TRANSPARENT,
+            present_mode: PresentMode::AutoVsync,
+            desired_maximum_frame_latency: 2,
+            view_mode: ViewMode::Shaded,
+            shadow_map_resolution: 2048,
+            ssao: None,
+            render_path: RenderPath::Forward,
+            environment_intensity: 1.,
+            sky: None,
+            fog: None,
+            bloom: None,
+            taa: None,
+            transparency: None,
+            user_bind_group_layouts: Vec::new(),
+            user_vertex_attributes: Vec::new(),
+        }
+    }
+}
+
+impl RendererConfig {
+    /// Format of the pipelines drawing the scene, which only write to the color target
+    /// directly while bloom and TAA are disabled.
+    fn scene_target_format(&self) -> TextureFormat {
+        match (self.bloom, self.taa) {
+            (None, None) => self.primary_target_format,
+            _ => bloom::HDR_FORMAT,
+        }
+    }
+
+    /// Format of the target TAA resolves into, the scene target of the bloom or the
+    /// color target.
+    fn taa_target_format(&self) -> TextureFormat {
+        match self.bloom {
+            Some(_) => bloom::HDR_FORMAT,
+            None => self.primary_target_format,
         }
     }
 }
 
 pub struct WgpuRenderer {
-    instance: Instance,
-    adapter: Adapter,
-    device: Device,
-    queue: Queue,
+    context: GpuContext,
     config: RendererConfig,
 
+    shader_source: ShaderSource<'static>,
+    shader_module: ShaderModule,
+    pipeline_layout: PipelineLayout,
     pipeline: RenderPipeline,
+    // Without the material and user bind groups, for the view modes and the outline.
+    view_mode_layout: PipelineLayout,
+    view_mode_shader_module: ShaderModule,
+    view_mode_pipeline: Option<RenderPipeline>,
+    outline_shader_module: ShaderModule,
+    outline_pipeline: RenderPipeline,
+    skybox_shader_module: ShaderModule,
+    skybox_pipeline: RenderPipeline,
+    sky_pipeline: RenderPipeline,
+    debug_lines_layout: PipelineLayout,
+    debug_lines_shader_module: ShaderModule,
+    // With and without depth test.
+    debug_lines_pipelines: [RenderPipeline; 2],
 
     camera: GpuCamera,
-    pub meshes: Vec<GpuMesh>,
+    // Kept to fit the shadow cascades to the view frustum.
+    scene_camera: Option<Camera>,
+    pub meshes: Vec<MeshHandle>,
+    pub skinned_models: Vec<SkinnedModel>,
     pub dir_lights: Vec<DirectionalLight>,
+    pub point_lights: Vec<PointLight>,
+    /// Drawn with an outline.
+    pub selected: Option<MeshHandle>,
+    pub debug_lines: DebugLines,
 
     camera_uniform: Option<Buffer>,
     dir_lights_storage: StorageBuffer<GpuDirectionalLight>,
+    point_lights_storage: StorageBuffer<GpuPointLight>,
 
     scene_layout: BindGroupLayout,
     scene_bind_group: Option<BindGroup>,
+    mesh_layout: BindGroupLayout,
+    // Bound for meshes without a skin. Holds a single identity joint, which is never
+    // read as their weights are all zero.
+    static_mesh_bind_group: BindGroup,
+    materials: Materials,
+    user: UserBindings,
+    shadow_maps: ShadowMaps,
+    // Override constants of the scene pipeline, enabling the shadow filters in use.
+    shader_defs: HashMap<String, f64>,
+    // Size of the targets passed to `draw`, which screen space effects have to match.
+    target_size: UVec2,
+    ssao: Option<Ssao>,
+    // Bound in place of the occlusion while SSAO is disabled.
+    unoccluded_view: TextureView,
+    deferred: Option<Deferred>,
+    transparency: Option<Transparency>,
+    bloom: Option<Bloom>,
+    taa: Option<Taa>,
+    environment: Environment,
+    atmosphere_uniform: Buffer,
+    // Kept to regenerate the environment if the device is lost.
+    environment_image: Option<HdrImage>,
+
+    stats: FrameStats,
+    gpu_timer: Option<GpuTimer>,
+    stats_recorder: Option<StatsRecorder>,
 }
 
 impl WgpuRenderer {
-    pub async fn new(shader: ShaderSource<'_>, config: Option<RendererConfig>) -> Self {
-        let config = config.unwrap_or_default();
+    /// WGSL scene shaders are prefixed with `skinning.wgsl`, declaring the joints of the
+    /// mesh bind group and `skin_matrix`, other sources have to declare them.
+    pub async fn new(shader: ShaderSource<'static>, config: Option<RendererConfig>) -> Self {
+        Self::with_context(GpuContext::new().await, shader, config)
+    }
 
-        env_logger::builder()
-            .filter_level(log::LevelFilter::Info)
-            .init();
-
-        let instance = Instance::default();
-        let adapter = instance
-            .request_adapter(&RequestAdapterOptions::default())
-            .await
-            .unwrap();
-        let (device, queue) = adapter
-            .request_device(
-                &DeviceDescriptor {
-                    label: None,
-                    required_features: Features::empty(),
-                    required_limits: Limits::downlevel_defaults(),
-                },
-                None,
-            )
-            .await
-            .unwrap();
+    pub fn with_context(
+        context: GpuContext,
+        shader: ShaderSource<'static>,
+        config: Option<RendererConfig>,
+    ) -> Self {
+        let config = config.unwrap_or_default();
+        let device = context.device();
 
         let shader_module = device.create_shader_module(ShaderModuleDescriptor {
             label: None,
-            source: shader,
+            source: match &shader {
+                ShaderSource::Wgsl(source) => with_skinning(source),
+                shader => shader.clone(),
+            },
         });
 
-        let scene_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        let scene_layout = create_scene_layout(device);
+        let mesh_layout = create_mesh_layout(device);
+        let identity_joint = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
-            entries: &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::VERTEX_FRAGMENT,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: GpuCamera::min_binding_size(),
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                },
-            ],
+            contents: &Mat4::IDENTITY.as_raw(),
+            usage: BufferUsages::STORAGE,
         });
-
-        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+        let static_mesh_bind_group =
+            create_mesh_bind_group(device, &mesh_layout, identity_joint.as_entire_binding());
+        let materials = Materials::new(device, context.queue(), &mut context.assets_mut());
+
+        let user = UserBindings::new(device, &config);
+        let bind_group_layouts = [&scene_layout, &mesh_layout, materials.layout()]
+            .into_iter()
+            .chain(user.layouts())
+            .collect::<Vec<_>>();
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
             label: None,
-            layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
-                label: None,
-                bind_group_layouts: &[&scene_layout],
-                ..Default::default()
-            })),
-            vertex: VertexState {
-                module: &shader_module,
-                entry_point: "vertex",
-                compilation_options: PipelineCompilationOptions::default(),
-                buffers: &[VertexBufferLayout {
-                    array_stride: std::mem::size_of::<Vertex>() as BufferAddress,
-                    step_mode: VertexStepMode::Vertex,
-                    attributes: &vertex_attr_array![0 => Float32x3, 1 => Float32x3],
-                }],
-            },
-            fragment: Some(FragmentState {
-                module: &shader_module,
-                entry_point: "fragment",
-                compilation_options: PipelineCompilationOptions::default(),
-                targets: &[Some(config.primary_target_format.into())],
-            }),
-            primitive: PrimitiveState::default(),
-            depth_stencil: Some(DepthStencilState {
-                format: TextureFormat::Depth24Plus,
-                depth_write_enabled: true,
-                depth_compare: CompareFunction::LessEqual,
-                stencil: StencilState::default(),
-                bias: DepthBiasState::default(),
-            }),
-            multisample: MultisampleState::default(),
-            multiview: None,
+            bind_group_layouts: &bind_group_layouts,
+            ..Default::default()
+        });
+        let view_mode_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&scene_layout, &mesh_layout],
+            ..Default::default()
+        });
+        let shader_defs = shadow::shader_defs(&[]);
+        let scene_format = config.scene_target_format();
+        let motion_vectors = config.taa.is_some();
+        let pipeline = create_scene_pipeline(
+            device,
+            &pipeline_layout,
+            &user.vertex_buffers(),
+            &shader_module,
+            scene_format,
+            motion_vectors,
+            &shader_defs,
+        );
+        let view_mode_shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: with_skinning(include_str!("view_modes.wgsl")),
+        });
+        let view_mode_pipeline = create_view_mode_pipeline(
+            device,
+            &view_mode_layout,
+            &view_mode_shader_module,
+            scene_format,
+            motion_vectors,
+            config.view_mode,
+        );
+        let outline_shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: with_skinning(include_str!("outline.wgsl")),
+        });
+        let outline_pipeline = create_outline_pipeline(
+            device,
+            &view_mode_layout,
+            &outline_shader_module,
+            scene_format,
+            motion_vectors,
+        );
+        let debug_lines_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&scene_layout],
+            ..Default::default()
+        });
+        let debug_lines_shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("debug_lines.wgsl"))),
+        });
+        let debug_lines_pipelines = [true, false].map(|depth_test| {
+            create_debug_lines_pipeline(
+                device,
+                &debug_lines_layout,
+                &debug_lines_shader_module,
+                scene_format,
+                motion_vectors,
+                depth_test,
+            )
+        });
+        let skybox_shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("skybox.wgsl"))),
+        });
+        // Only needs the scene bind group, like the debug lines.
+        let [skybox_pipeline, sky_pipeline] = ["fragment", "fragment_sky"].map(|entry_point| {
+            environment::create_skybox_pipeline(
+                device,
+                &debug_lines_layout,
+                &skybox_shader_module,
+                scene_format,
+                motion_vectors,
+                entry_point,
+            )
         });
 
-        log::info!("Wgpu context set up.");
+        let shadow_maps = ShadowMaps::new(
+            device,
+            &mesh_layout,
+            materials.layout(),
+            config.shadow_map_resolution,
+            0,
+            false,
+        );
+
+        let target_size = UVec2::ONE;
+        let ssao = config.ssao.map(|ssao_config| {
+            Ssao::new(
+                device,
+                context.queue(),
+                &scene_layout,
+                &mesh_layout,
+                materials.layout(),
+                target_size,
+                ssao_config,
+            )
+        });
+        let unoccluded_view = ssao::create_unoccluded_view(device, context.queue());
+        let environment = Environment::empty(device, context.queue());
+        let atmosphere_uniform = atmosphere::create_atmosphere_uniform(device);
+        let deferred = (config.render_path == RenderPath::Deferred).then(|| {
+            Deferred::new(
+                device,
+                &shader_module,
+                &scene_layout,
+                &mesh_layout,
+                materials.layout(),
+                &pipeline_layout,
+                &user.vertex_buffers(),
+                scene_format,
+                motion_vectors,
+                &shader_defs,
+                target_size,
+            )
+        });
+        let transparency = config.transparency.map(|mode| {
+            Transparency::new(
+                device,
+                &shader_module,
+                &pipeline_layout,
+                &user.vertex_buffers(),
+                scene_format,
+                motion_vectors,
+                &shader_defs,
+                mode,
+                target_size,
+            )
+        });
+        let bloom = config.bloom.map(|bloom_config| {
+            Bloom::new(
+                device,
+                config.primary_target_format,
+                target_size,
+                bloom_config,
+            )
+        });
+        let taa = config.taa.map(|taa_config| {
+            Taa::new(device, config.taa_target_format(), target_size, taa_config)
+        });
+
+        let gpu_timer = GpuTimer::new(device, context.queue());
+        if gpu_timer.is_none() {
+            log::info!("Timestamp queries not supported, GPU pass times unavailable.");
+        }
 
         Self {
-            instance,
-            adapter,
-            device,
-            queue,
+            context,
             config,
 
+            // Kept around to rebuild the pipeline if the device is lost.
+            shader_source: shader,
+            shader_module,
+            pipeline_layout,
             pipeline,
+            view_mode_layout,
+            view_mode_shader_module,
+            view_mode_pipeline,
+            outline_shader_module,
+            outline_pipeline,
+            skybox_shader_module,
+            skybox_pipeline,
+            sky_pipeline,
+            debug_lines_layout,
+            debug_lines_shader_module,
+            debug_lines_pipelines,
 
             camera: GpuCamera::default(),
+            scene_camera: None,
             meshes: Vec::new(),
+            skinned_models: Vec::new(),
             dir_lights: Vec::new(),
+            point_lights: Vec::new(),
+            selected: None,
+            debug_lines: DebugLines::default(),
 
             camera_uniform: None,
             dir_lights_storage: StorageBuffer::default(),
+            point_lights_storage: StorageBuffer::default(),
 
             scene_layout,
             scene_bind_group: None,
+            mesh_layout,
+            static_mesh_bind_group,
+            materials,
+            user,
+            shadow_maps,
+            shader_defs,
+            target_size,
+            ssao,
+            unoccluded_view,
+            deferred,
+            transparency,
+            bloom,
+            taa,
+            environment,
+            atmosphere_uniform,
+            environment_image: None,
+
+            stats: FrameStats::default(),
+            gpu_timer,
+            stats_recorder: None,
         }
     }
 
+    /// With TAA, this advances the jitter and should be called once per frame. Cameras
+    /// moving further than the cut thresholds of [`TaaConfig`] since the last call
+    /// discard the history.
     pub fn set_camera(&mut self, camera: &Camera) {
+        let view = camera.view_matrix();
+        let proj = camera.projection_matrix();
+        let prev_view_proj = self
+            .scene_camera
+            .map(|c| c.projection_matrix() * c.view_matrix())
+            .unwrap_or(proj * view);
+
+        // View modes are drawn without jitter.
+        let shaded = self.config.view_mode == ViewMode::Shaded;
+        let mut jitter = Vec2::ZERO;
+        if let Some(taa) = self.taa.as_mut().filter(|_| shaded) {
+            jitter = taa.next_jitter(self.target_size);
+            let config = taa.config();
+            let cut = self.scene_camera.is_none_or(|previous| {
+                let position = |c: &Camera| c.view_matrix().inverse().w_axis.truncate();
+                let rotation = previous
+                    .transform
+                    .rotation
+                    .angle_between(camera.transform.rotation);
+                position(&previous).distance(position(camera)) > config.cut_distance
+                    || rotation > config.cut_angle
+            });
+            if cut {
+                taa.reset();
+            }
+        }
+
         self.camera = GpuCamera {
-            view: camera.transform.compute_matrix(),
-            proj: Mat4::perspective_rh(camera.fov, camera.aspect_ratio, camera.near, camera.far),
+            view,
+            proj: Mat4::from_translation(jitter.extend(0.)) * proj,
+            prev_view_proj,
+            jitter,
         };
+        self.scene_camera = Some(*camera);
+        self.write_camera();
+    }
 
-        self.camera_uniform = Some(self.device.create_buffer_init(&BufferInitDescriptor {
-            label: None,
-            contents: &self.camera.as_raw(),
-            usage: BufferUsages::UNIFORM,
-        }));
+    /// Discards the TAA history with the next frame, for camera cuts the thresholds of
+    /// [`TaaConfig`] don't catch.
+    pub fn reset_taa_history(&mut self) {
+        if let Some(taa) = &mut self.taa {
+            taa.reset();
+        }
+    }
+
+    fn write_camera(&mut self) {
+        self.camera_uniform = Some(self.context.device().create_buffer_init(
+            &BufferInitDescriptor {
+                label: None,
+                contents: &self.camera.as_raw(),
+                usage: BufferUsages::UNIFORM,
+            },
+        ));
     }
 
     pub fn write_scene(&mut self) {
+        let layers = self.dir_lights.iter().map(|l| l.cascade_count).sum();
+        let resolution = self.config.shadow_map_resolution;
+        let variance = self.dir_lights.iter().any(|l| {
+            l.cascade_count > 0 && matches!(l.shadow_filter, ShadowFilter::Variance { .. })
+        });
+        if !self.shadow_maps.matches(resolution, layers, variance) {
+            self.shadow_maps = ShadowMaps::new(
+                self.context.device(),
+                &self.mesh_layout,
+                self.materials.layout(),
+                resolution,
+                layers,
+                variance,
+            );
+        }
+
+        let shader_defs = shadow::shader_defs(&self.dir_lights);
+        if shader_defs != self.shader_defs {
+            self.shader_defs = shader_defs;
+            self.pipeline = create_scene_pipeline(
+                self.context.device(),
+                &self.pipeline_layout,
+                &self.user.vertex_buffers(),
+                &self.shader_module,
+                self.config.scene_target_format(),
+                self.config.taa.is_some(),
+                &self.shader_defs,
+            );
+            if let Some(deferred) = &mut self.deferred {
+                deferred.rebuild_pipelines(
+                    self.context.device(),
+                    &self.shader_module,
+                    &self.pipeline_layout,
+                    &self.user.vertex_buffers(),
+                    self.config.scene_target_format(),
+                    self.config.taa.is_some(),
+                    &self.shader_defs,
+                );
+            }
+            if let Some(transparency) = &mut self.transparency {
+                transparency.rebuild_pipelines(
+                    self.context.device(),
+                    &self.shader_module,
+                    &self.pipeline_layout,
+                    &self.user.vertex_buffers(),
+                    self.config.scene_target_format(),
+                    self.config.taa.is_some(),
+                    &self.shader_defs,
+                );
+            }
+        }
+
+        self.dir_lights_storage.clear();
+        let mut first_cascade = 0;
         self.dir_lights.iter().for_each(|l| {
+            let (filter, filter_samples, filter_param) = l.shadow_filter.gpu_params();
             self.dir_lights_storage.push(&GpuDirectionalLight {
                 translation: l.translation,
                 direction: l.direction,
                 color: l.color,
+                first_cascade,
+                cascade_count: l.cascade_count,
+                filter,
+                filter_samples,
+                filter_param,
+                depth_bias: l.depth_bias,
+                normal_bias: l.normal_bias,
+            });
+            first_cascade += l.cascade_count;
+        });
+        self.dir_lights_storage
+            .write(self.context.device(), self.context.queue());
+
+        self.point_lights_storage.clear();
+        self.point_lights.iter().for_each(|l| {
+            self.point_lights_storage.push(&GpuPointLight {
+                position: l.position,
+                radius: l.radius,
+                color: l.color,
             });
         });
-        self.dir_lights_storage.write(&self.device, &self.queue);
+        // Storage buffers can't be empty, a zero radius light is skipped by the shader.
+        if self.point_lights.is_empty() {
+            self.point_lights_storage.push(&GpuPointLight::default());
+        }
+        self.point_lights_storage
+            .write(self.context.device(), self.context.queue());
+
+        let assets = self.context.assets();
+        self.user.retain_meshes(|mesh| assets.mesh(mesh).is_some());
+        drop(assets);
+        self.environment
+            .update(self.context.queue(), self.config.environment_intensity);
+        let atmosphere = GpuAtmosphere {
+            sky: self.config.sky,
+            fog: self.config.fog,
+            sun_direction: self.dir_lights.first().map(|l| l.direction),
+        };
+        self.context
+            .queue()
+            .write_buffer(&self.atmosphere_uniform, 0, &atmosphere.as_raw());
 
         let Some(dir_lights) = self.dir_lights_storage.binding() else {
             log::error!("Failed to get bindng resource for directional lights.");
             return;
         };
+        let Some(point_lights) = self.point_lights_storage.binding() else {
+            log::error!("Failed to get binding resource for point lights.");
+            return;
+        };
 
-        self.scene_bind_group = Some(self.device.create_bind_group(&BindGroupDescriptor {
-            label: None,
-            layout: &self.scene_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: self.camera_uniform.as_ref().unwrap().as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: dir_lights,
-                },
-            ],
-        }));
+        self.scene_bind_group = Some(
+            self.context
+                .device()
+                .create_bind_group(&BindGroupDescriptor {
+                    label: None,
+                    layout: &self.scene_layout,
+                    entries: &[
+                        BindGroupEntry {
+                            binding: 0,
+                            resource: self.camera_uniform.as_ref().unwrap().as_entire_binding(),
+                        },
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: dir_lights,
+                        },
+                        BindGroupEntry {
+                            binding: 2,
+                            resource: self.shadow_maps.cascades_buffer.as_entire_binding(),
+                        },
+                        BindGroupEntry {
+                            binding: 3,
+                            resource: BindingResource::TextureView(&self.shadow_maps.view),
+                        },
+                        BindGroupEntry {
+                            binding: 4,
+                            resource: BindingResource::Sampler(&self.shadow_maps.sampler),
+                        },
+                        BindGroupEntry {
+                            binding: 5,
+                            resource: BindingResource::TextureView(&self.shadow_maps.moments_view),
+                        },
+                        BindGroupEntry {
+                            binding: 6,
+                            resource: BindingResource::TextureView(
+                                self.ssao
+                                    .as_ref()
+                                    .map(Ssao::occlusion_view)
+                                    .unwrap_or(&self.unoccluded_view),
+                            ),
+                        },
+                        BindGroupEntry {
+                            binding: 8,
+                            resource: point_lights,
+                        },
+                        BindGroupEntry {
+                            binding: 9,
+                            resource: self.environment.uniform_binding(),
+                        },
+                        BindGroupEntry {
+                            binding: 10,
+                            resource: BindingResource::TextureView(
+                                &self.environment.irradiance_view,
+                            ),
+                        },
+                        BindGroupEntry {
+                            binding: 11,
+                            resource: BindingResource::TextureView(
+                                &self.environment.prefiltered_view,
+                            ),
+                        },
+                        BindGroupEntry {
+                            binding: 12,
+                            resource: BindingResource::TextureView(&self.environment.brdf_lut_view),
+                        },
+                        BindGroupEntry {
+                            binding: 13,
+                            resource: BindingResource::Sampler(&self.environment.sampler),
+                        },
+                        BindGroupEntry {
+                            binding: 14,
+                            resource: BindingResource::TextureView(&self.environment.skybox_view),
+                        },
+                        BindGroupEntry {
+                            binding: 15,
+                            resource: self.atmosphere_uniform.as_entire_binding(),
+                        },
+                    ],
+                }),
+        );
     }
 
-    pub fn draw(&self, color_target: &TextureView, depth_target: &TextureView) {
+    pub fn draw(&mut self, color_target: &TextureView, depth_target: &TextureView) {
+        if let Some(camera) = &self.scene_camera {
+            self.shadow_maps
+                .update(self.context.queue(), camera, &self.dir_lights);
+        }
+        let meshes = self
+            .meshes
+            .iter()
+            .chain(self.skinned_models.iter().flat_map(|m| &m.meshes));
+        self.materials.write(
+            self.context.device(),
+            self.context.queue(),
+            &mut self.context.assets_mut(),
+            meshes.copied(),
+        );
+
         let Some(scene) = &self.scene_bind_group else {
             log::error!("Failed to get bind group for scene.");
             return;
         };
+        // With TAA and bloom, the scene goes through their targets before reaching the
+        // color target.
+        let taa_target = self
+            .bloom
+            .as_ref()
+            .map(Bloom::scene_view)
+            .unwrap_or(color_target);
+        let scene_target = self.taa.as_ref().map(Taa::scene_view).unwrap_or(taa_target);
+        let motion_target = self.taa.as_ref().map(Taa::motion_view);
+
+        let start = Instant::now();
+        if let Some(pass_times) = self
+            .gpu_timer
+            .as_mut()
+            .and_then(|timer| timer.collect(self.context.device()))
+        {
+            self.stats.gpu_pass_times = pass_times;
+        }
+        self.stats.draw_calls = 0;
+        self.stats.triangles = 0;
 
         let mut command_encoder = self
-            .device
+            .context
+            .device()
             .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        let debug_lines = (!self.debug_lines.is_empty()).then(|| {
+            self.context
+                .device()
+                .create_buffer_init(&BufferInitDescriptor {
+                    label: None,
+                    contents: bytemuck::cast_slice(&self.debug_lines.vertices),
+                    usage: BufferUsages::VERTEX,
+                })
+        });
 
         {
-            let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
-                label: None,
-                color_attachments: &[Some(RenderPassColorAttachment {
-                    view: color_target,
+            let assets = self.context.assets();
+            let skinned = self
+                .skinned_models
+                .iter()
+                .filter_map(|m| Some((m.bind_group.as_ref()?, &m.meshes[..])));
+            let mesh_groups = std::iter::once((&self.static_mesh_bind_group, &self.meshes[..]))
+                .chain(skinned)
+                .collect::<Vec<_>>();
+
+            // Blended meshes are left out of the passes before the transparent ones, and
+            // drawn back to front by the view depth of their centers, which is negative.
+            // View modes and disabled transparency draw them as opaque.
+            let blend = self.config.view_mode == ViewMode::Shaded && self.transparency.is_some();
+            let is_transparent = |handle: &MeshHandle| {
+                blend
+                    && assets
+                        .mesh(*handle)
+                        .is_some_and(|m| m.material.alpha_mode == AlphaMode::Blend)
+            };
+            let opaque_meshes = mesh_groups
+                .iter()
+                .map(|(bind_group, meshes)| {
+                    let meshes = meshes.iter().copied().filter(|h| !is_transparent(h));
+                    (*bind_group, meshes.collect::<Vec<_>>())
+                })
+                .collect::<Vec<_>>();
+            let opaque_groups = opaque_meshes
+                .iter()
+                .map(|(bind_group, meshes)| (*bind_group, &meshes[..]))
+                .collect::<Vec<_>>();
+            let skinned = self.skinned_models.iter().filter_map(|m| {
+                Some((m.bind_group.as_ref()?, &m.meshes[..], &m.joint_matrices[..]))
+            });
+            let mut transparent = Vec::new();
+            for (bind_group, meshes, joint_matrices) in
+                std::iter::once((&self.static_mesh_bind_group, &self.meshes[..], &[][..]))
+                    .chain(skinned)
+            {
+                for &handle in meshes.iter().filter(|h| is_transparent(h)) {
+                    let Some(mesh) = assets.mesh(handle) else {
+                        continue;
+                    };
+                    let Some(bounds) = mesh.approximate_bounds(joint_matrices) else {
+                        continue;
+                    };
+                    let depth = self.camera.view.transform_point3(bounds.center()).z;
+                    transparent.push((bind_group, handle, mesh, depth));
+                }
+            }
+            transparent.sort_by(|a, b| a.3.total_cmp(&b.3));
+            // Accumulated in passes of their own, between the scene pass and the one
+            // drawing the outline and debug lines.
+            let transparency = self
+                .transparency
+                .as_ref()
+                .filter(|_| !transparent.is_empty());
+            let order_independent =
+                transparency.is_some_and(|t| t.mode() == TransparencyMode::WeightedBlended);
+
+            self.shadow_maps.encode(
+                &mut command_encoder,
+                &assets,
+                &mesh_groups,
+                &self.materials,
+                &mut self.stats,
+                self.gpu_timer.as_mut(),
+            );
+
+            if let Some(ssao) = &self.ssao {
+                if self.config.view_mode == ViewMode::Shaded {
+                    ssao.update(self.context.queue(), self.camera.proj);
+                    ssao.encode(
+                        &mut command_encoder,
+                        &assets,
+                        scene,
+                        &opaque_groups,
+                        &self.materials,
+                        &mut self.stats,
+                        self.gpu_timer.as_mut(),
+                    );
+                }
+            }
+
+            // View modes always use the forward path.
+            let deferred = self
+                .deferred
+                .as_ref()
+                .filter(|_| self.config.view_mode == ViewMode::Shaded);
+            if let Some(deferred) = deferred {
+                deferred.encode(
+                    self.context.device(),
+                    &mut command_encoder,
+                    &assets,
+                    (scene, &self.static_mesh_bind_group),
+                    &opaque_groups,
+                    &self.materials,
+                    &self.user,
+                    (scene_target, motion_target, depth_target),
+                    self.config.clear_color,
+                    &mut self.stats,
+                    self.gpu_timer.as_mut(),
+                );
+            }
+
+            // The selection outline and debug lines are drawn over the lit G-buffer.
+            let (color_load, depth_load) = match deferred {
+                Some(_) => (LoadOp::Load, LoadOp::Load),
+                None => (LoadOp::Clear(self.config.clear_color), LoadOp::Clear(1.)),
+            };
+            // Surfaces missing from the motion vectors are reprojected as background.
+            let motion_attachment = motion_target.map(|view| RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: Operations {
+                    load: match deferred {
+                        Some(_) => LoadOp::Load,
+                        None => LoadOp::Clear(Color::TRANSPARENT),
+                    },
+                    store: StoreOp::Store,
+                },
+            });
+            let color_attachments = [
+                Some(RenderPassColorAttachment {
+                    view: scene_target,
                     resolve_target: None,
                     ops: Operations {
-                        load: LoadOp::Clear(self.config.clear_color),
+                        load: color_load,
                         store: StoreOp::Store,
                     },
-                })],
+                }),
+                motion_attachment,
+            ];
+            let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+                label: None,
+                color_attachments: &color_attachments[..1 + motion_target.is_some() as usize],
                 depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
                     view: depth_target,
                     depth_ops: Some(Operations {
-                        load: LoadOp::Clear(1.),
-                        store: StoreOp::Discard,
+                        load: depth_load,
+                        store: match order_independent {
+                            true => StoreOp::Store,
+                            false => StoreOp::Discard,
+                        },
                     }),
                     stencil_ops: None,
                 }),
+                timestamp_writes: self
+                    .gpu_timer
+                    .as_mut()
+                    .and_then(|timer| timer.pass_writes("scene")),
                 ..Default::default()
             });
 
-            pass.set_pipeline(&self.pipeline);
+            pass.set_pipeline(self.view_mode_pipeline.as_ref().unwrap_or(&self.pipeline));
             pass.set_bind_group(0, scene, &[]);
 
-            for mesh in &self.meshes {
+            let forward_groups = if deferred.is_some() {
+                &[][..]
+            } else {
+                &opaque_groups[..]
+            };
+            for (bind_group, meshes) in forward_groups {
+                pass.set_bind_group(1, bind_group, &[]);
+                for handle in meshes.iter() {
+                    let Some(mesh) = assets.mesh(*handle) else {
+                        continue;
+                    };
+                    if self.view_mode_pipeline.is_none()
+                        && !self.user.bind(&mut pass, *handle, mesh)
+                    {
+                        continue;
+                    }
+                    self.materials.bind(&mut pass, 2, *handle, mesh);
+                    pass.set_vertex_buffer(0, mesh.vertex_buf.slice(..));
+                    pass.draw(0..mesh.vertex_count, 0..1);
+                    self.stats.draw_calls += 1;
+                    self.stats.triangles += mesh.vertex_count / 3;
+                }
+            }
+
+            let background = match (self.environment.enabled, self.config.sky) {
+                (true, _) => Some(&self.skybox_pipeline),
+                (false, Some(_)) => Some(&self.sky_pipeline),
+                (false, None) => None,
+            };
+            if let Some(background) =
+                background.filter(|_| self.config.view_mode == ViewMode::Shaded)
+            {
+                pass.set_pipeline(background);
+                pass.draw(0..3, 0..1);
+                self.stats.draw_calls += 1;
+            }
+
+            match transparency {
+                Some(transparency) if order_independent => {
+                    drop(pass);
+                    pass = transparency.begin_accumulation(
+                        &mut command_encoder,
+                        depth_target,
+                        self.gpu_timer.as_mut(),
+                    );
+                    pass.set_bind_group(0, scene, &[]);
+                }
+                Some(transparency) => pass.set_pipeline(transparency.pipeline()),
+                None => {}
+            }
+            for &(bind_group, handle, mesh, _) in &transparent {
+                if !self.user.bind(&mut pass, handle, mesh) {
+                    continue;
+                }
+                pass.set_bind_group(1, bind_group, &[]);
+                self.materials.bind(&mut pass, 2, handle, mesh);
+                pass.set_vertex_buffer(0, mesh.vertex_buf.slice(..));
+                pass.draw(0..mesh.vertex_count, 0..1);
+                self.stats.draw_calls += 1;
+                self.stats.triangles += mesh.vertex_count / 3;
+            }
+            if let Some(transparency) = transparency.filter(|_| order_independent) {
+                drop(pass);
+                transparency.composite(&mut command_encoder, scene_target, self.gpu_timer.as_mut());
+                let color_attachments = color_attachments.map(|attachment| {
+                    attachment.map(|attachment| RenderPassColorAttachment {
+                        ops: Operations {
+                            load: LoadOp::Load,
+                            store: StoreOp::Store,
+                        },
+                        ..attachment
+                    })
+                });
+                pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+                    label: None,
+                    color_attachments: &color_attachments[..1 + motion_target.is_some() as usize],
+                    depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                        view: depth_target,
+                        depth_ops: Some(Operations {
+                            load: LoadOp::Load,
+                            store: StoreOp::Discard,
+                        }),
+                        stencil_ops: None,
+                    }),
+                    timestamp_writes: self
+                        .gpu_timer
+                        .as_mut()
+                        .and_then(|timer| timer.pass_writes("overlays")),
+                    ..Default::default()
+                });
+                pass.set_bind_group(0, scene, &[]);
+            }
+
+            let selected = self.selected.and_then(|selected| {
+                let (bind_group, _) = mesh_groups
+                    .iter()
+                    .find(|(_, meshes)| meshes.contains(&selected))?;
+                Some((bind_group, assets.mesh(selected)?))
+            });
+            if let Some((bind_group, mesh)) = selected {
+                pass.set_pipeline(&self.outline_pipeline);
+                pass.set_bind_group(1, bind_group, &[]);
                 pass.set_vertex_buffer(0, mesh.vertex_buf.slice(..));
                 pass.draw(0..mesh.vertex_count, 0..1);
+                self.stats.draw_calls += 1;
+                self.stats.triangles += mesh.vertex_count / 3;
             }
+
+            if let Some(debug_lines) = &debug_lines {
+                let depth_test = self.debug_lines.depth_test;
+                pass.set_pipeline(&self.debug_lines_pipelines[!depth_test as usize]);
+                pass.set_vertex_buffer(0, debug_lines.slice(..));
+                pass.draw(0..self.debug_lines.vertices.len() as u32, 0..1);
+                self.stats.draw_calls += 1;
+            }
+        }
+        self.debug_lines.clear();
+
+        let shaded = self.config.view_mode == ViewMode::Shaded;
+        if let Some(taa) = &mut self.taa {
+            let reprojection =
+                self.camera.prev_view_proj * self.camera.unjittered_view_proj().inverse();
+            // View modes are copied as they are.
+            taa.update(self.context.queue(), reprojection, shaded);
+            let output = self
+                .bloom
+                .as_ref()
+                .map(Bloom::scene_view)
+                .unwrap_or(color_target);
+            taa.encode(&mut command_encoder, output, self.gpu_timer.as_mut());
+        }
+        if let Some(bloom) = &self.bloom {
+            bloom.update(self.context.queue(), shaded);
+            bloom.encode(
+                &mut command_encoder,
+                color_target,
+                shaded,
+                self.gpu_timer.as_mut(),
+            );
         }
 
-        self.queue.submit(Some(command_encoder.finish()));
-    }
-
-    pub fn load_obj(&mut self, path: impl AsRef<Path>) {
-        let mut source = Vec::new();
-        File::open(path).unwrap().read_to_end(&mut source).unwrap();
-        let obj = obj::ObjData::load_buf(&source[..]).unwrap();
-
-        let mut vertices = Vec::new();
-        for object in obj.objects {
-            for group in object.groups {
-                vertices.clear();
-                for poly in group.polys {
-                    for end_index in 2..poly.0.len() {
-                        for &index in &[0, end_index - 1, end_index] {
-                            let obj::IndexTuple(position_id, Some(_texture_id), Some(normal_id)) =
-                                poly.0[index]
-                            else {
-                                unreachable!()
-                            };
-
-                            vertices.push(Vertex {
-                                position: obj.position[position_id].into(),
-                                normal: obj.normal[normal_id].into(),
-                            });
-                        }
-                    }
+        if let Some(timer) = &self.gpu_timer {
+            timer.resolve(&mut command_encoder);
+        }
+        self.context.queue().submit(Some(command_encoder.finish()));
+        if let Some(timer) = &mut self.gpu_timer {
+            timer.map();
+        }
+
+        self.stats.buffer_memory = self.buffer_memory();
+        self.stats.cpu_time = start.elapsed();
+        if let Some(recorder) = &mut self.stats_recorder {
+            recorder.record(&self.stats);
+        }
+    }
+
+    fn buffer_memory(&self) -> u64 {
+        let assets = self.context.assets();
+        assets.buffer_memory(&self.meshes)
+            + self
+                .skinned_models
+                .iter()
+                .map(|m| assets.buffer_memory(&m.meshes) + m.joints.size())
+                .sum::<u64>()
+            + self.camera_uniform.as_ref().map(Buffer::size).unwrap_or(0)
+            + self.dir_lights_storage.size()
+            + self.point_lights_storage.size()
+            + self.materials.size()
+    }
+
+    #[inline]
+    pub fn stats(&self) -> &FrameStats {
+        &self.stats
+    }
+
+    /// Appends the stats of every following frame to a CSV file at `path`.
+    pub fn record_stats(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.stats_recorder = Some(StatsRecorder::new(path)?);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn stop_recording_stats(&mut self) {
+        self.stats_recorder = None;
+    }
+
+    /// Runs `pass` on the renderer's queue, timed as `name` in the frame stats.
+    pub fn dispatch(
+        &mut self,
+        name: &'static str,
+        pass: &ComputePass,
+        bind_groups: &[&BindGroup],
+        workgroups: UVec3,
+    ) {
+        let mut command_encoder = self
+            .context
+            .device()
+            .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        pass.encode(
+            &mut command_encoder,
+            bind_groups,
+            workgroups,
+            self.gpu_timer
+                .as_mut()
+                .and_then(|timer| timer.compute_pass_writes(name)),
+        );
+        self.context.queue().submit(Some(command_encoder.finish()));
+    }
+
+    /// Every mesh of this renderer with the joint matrices of its current pose, empty
+    /// for static meshes.
+    fn meshes_with_joints(&self) -> impl Iterator<Item = (MeshHandle, &[Mat4])> + '_ {
+        let skinned = self
+            .skinned_models
+            .iter()
+            .map(|m| (&m.meshes, &m.joint_matrices[..]));
+        std::iter::once((&self.meshes, &[][..]))
+            .chain(skinned)
+            .flat_map(|(meshes, joint_matrices)| {
+                meshes.iter().map(move |&handle| (handle, joint_matrices))
+            })
+    }
+
+    /// Returns the closest mesh of this renderer hit by `ray`, skinned meshes are tested
+    /// in their current pose.
+    pub fn pick(&self, ray: &Ray) -> Option<MeshHandle> {
+        let assets = self.context.assets();
+        self.meshes_with_joints()
+            .filter_map(|(handle, joint_matrices)| {
+                let distance = assets.mesh(handle)?.intersect(ray, joint_matrices)?;
+                Some((handle, distance))
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(handle, _)| handle)
+    }
+
+    /// Bounds of `handle` in its current pose, if it's one of this renderer's meshes.
+    pub fn mesh_bounds(&self, handle: MeshHandle) -> Option<Aabb> {
+        let (_, joint_matrices) = self.meshes_with_joints().find(|(h, _)| *h == handle)?;
+        self.context.assets().mesh(handle)?.bounds(joint_matrices)
+    }
+
+    /// Bounds of all meshes of this renderer in their current pose.
+    pub fn bounds(&self) -> Option<Aabb> {
+        let assets = self.context.assets();
+        self.meshes_with_joints()
+            .filter_map(|(handle, joint_matrices)| assets.mesh(handle)?.bounds(joint_matrices))
+            .reduce(|a, b| a.union(&b))
+    }
+
+    /// Loads the meshes into the shared asset registry and adds them to this renderer.
+    pub fn load_obj(&mut self, path: impl AsRef<Path>) -> Vec<MeshHandle> {
+        let handles = self.context.load_obj(path);
+        self.meshes.extend_from_slice(&handles);
+        handles
+    }
+
+    /// Loads static meshes and skinned models into the shared asset registry and adds
+    /// them to this renderer. Returns the index of the first added skinned model.
+    pub fn load_gltf(&mut self, path: impl AsRef<Path>) -> Result<usize, gltf::Error> {
+        let (meshes, skinned_models) = self.context.assets_mut().load_gltf(
+            self.context.device(),
+            self.context.queue(),
+            path,
+        )?;
+        let first_skinned = self.skinned_models.len();
+        self.meshes.extend(meshes);
+        self.skinned_models.extend(skinned_models);
+        self.update_animations(0.);
+        Ok(first_skinned)
+    }
+
+    /// Advances the animation of every skinned model by `delta` seconds and uploads the
+    /// resulting joint matrices.
+    pub fn update_animations(&mut self, delta: f32) {
+        for model in &mut self.skinned_models {
+            let joint_matrices = model.update(delta);
+            if !model
+                .joints
+                .write_in_place(self.context.queue(), &joint_matrices)
+            {
+                model.joints.set(&joint_matrices);
+                model
+                    .joints
+                    .write(self.context.device(), self.context.queue());
+                model.bind_group = model.joints.binding().map(|joints| {
+                    create_mesh_bind_group(self.context.device(), &self.mesh_layout, joints)
+                });
+            }
+            model.joint_matrices = joint_matrices;
+        }
+    }
+
+    #[inline]
+    pub fn is_device_lost(&self) -> bool {
+        self.context.is_device_lost()
+    }
+
+    /// Switches to the recovered context and recreates every GPU resource owned by the
+    /// renderer. Shared assets are re-uploaded by the context, targets owned by the
+    /// caller have to be recreated as well.
+    pub async fn recover_device(&mut self) {
+        let context = self.context.recover().await;
+        let renderer = Self::with_context(
+            context,
+            self.shader_source.clone(),
+            Some(self.config.clone()),
+        );
+        let old = std::mem::replace(self, renderer);
+
+        self.camera = old.camera;
+        self.scene_camera = old.scene_camera;
+        self.resize(old.target_size);
+        self.meshes = old.meshes;
+        self.skinned_models = old.skinned_models;
+        for model in &mut self.skinned_models {
+            model.joints = StorageBuffer::default();
+            model.bind_group = None;
+        }
+        self.dir_lights = old.dir_lights;
+        self.point_lights = old.point_lights;
+        // The new device may have lower limits.
+        if let Err(err) = self.set_environment(old.environment_image) {
+            log::error!("Failed to restore the environment: {}", err);
+        }
+        self.selected = old.selected;
+        self.debug_lines = old.debug_lines;
+        self.stats = old.stats;
+        self.stats_recorder = old.stats_recorder;
+
+        self.write_camera();
+        self.write_scene();
+        self.update_animations(0.);
+    }
+
+    pub fn set_target_format(&mut self, format: TextureFormat) {
+        if self.config.primary_target_format == format {
+            return;
+        }
+
+        self.config.primary_target_format = format;
+        if let Some(bloom) = &mut self.bloom {
+            bloom.rebuild_pipeline(self.context.device(), format);
+        }
+        self.rebuild_scene_pipelines();
+    }
+
+    /// For a new format of the scene target or of the TAA output, or after toggling the
+    /// motion vectors.
+    fn rebuild_scene_pipelines(&mut self) {
+        let format = self.config.scene_target_format();
+        let motion_vectors = self.config.taa.is_some();
+        self.pipeline = create_scene_pipeline(
+            self.context.device(),
+            &self.pipeline_layout,
+            &self.user.vertex_buffers(),
+            &self.shader_module,
+            format,
+            motion_vectors,
+            &self.shader_defs,
+        );
+        self.view_mode_pipeline = create_view_mode_pipeline(
+            self.context.device(),
+            &self.view_mode_layout,
+            &self.view_mode_shader_module,
+            format,
+            motion_vectors,
+            self.config.view_mode,
+        );
+        self.outline_pipeline = create_outline_pipeline(
+            self.context.device(),
+            &self.view_mode_layout,
+            &self.outline_shader_module,
+            format,
+            motion_vectors,
+        );
+        [self.skybox_pipeline, self.sky_pipeline] =
+            ["fragment", "fragment_sky"].map(|entry_point| {
+                environment::create_skybox_pipeline(
+                    self.context.device(),
+                    &self.debug_lines_layout,
+                    &self.skybox_shader_module,
+                    format,
+                    motion_vectors,
+                    entry_point,
+                )
+            });
+        self.debug_lines_pipelines = [true, false].map(|depth_test| {
+            create_debug_lines_pipeline(
+                self.context.device(),
+                &self.debug_lines_layout,
+                &self.debug_lines_shader_module,
+                format,
+                motion_vectors,
+                depth_test,
+            )
+        });
+        if let Some(deferred) = &mut self.deferred {
+            deferred.rebuild_pipelines(
+                self.context.device(),
+                &self.shader_module,
+                &self.pipeline_layout,
+                &self.user.vertex_buffers(),
+                format,
+                motion_vectors,
+                &self.shader_defs,
+            );
+        }
+        if let Some(transparency) = &mut self.transparency {
+            transparency.rebuild_pipelines(
+                self.context.device(),
+                &self.shader_module,
+                &self.pipeline_layout,
+                &self.user.vertex_buffers(),
+                format,
+                motion_vectors,
+                &self.shader_defs,
+            );
+        }
+        if let Some(taa) = &mut self.taa {
+            taa.rebuild_pipeline(self.context.device(), self.config.taa_target_format());
+        }
+    }
+
+    /// Cascades of every shadow casting light as of the last frame, in the order of
+    /// [`WgpuRenderer::dir_lights`].
+    #[inline]
+    pub fn shadow_cascades(&self) -> &[ShadowCascade] {
+        &self.shadow_maps.cascades
+    }
+
+    /// Resizes the screen space effects to match the targets passed to
+    /// [`WgpuRenderer::draw`].
+    pub fn resize(&mut self, dim: UVec2) {
+        let dim = dim.max(UVec2::ONE);
+        if self.target_size == dim {
+            return;
+        }
+
+        self.target_size = dim;
+        if let Some(ssao) = &mut self.ssao {
+            ssao.resize(self.context.device(), dim);
+        }
+        if let Some(deferred) = &mut self.deferred {
+            deferred.resize(self.context.device(), dim);
+        }
+        if let Some(transparency) = &mut self.transparency {
+            transparency.resize(self.context.device(), dim);
+        }
+        if let Some(bloom) = &mut self.bloom {
+            bloom.resize(self.context.device(), dim);
+        }
+        if let Some(taa) = &mut self.taa {
+            taa.resize(self.context.device(), dim);
+        }
+        if self.scene_bind_group.is_some() {
+            self.write_scene();
+        }
+    }
+
+    pub fn set_ssao(&mut self, ssao_config: Option<SsaoConfig>) {
+        self.config.ssao = ssao_config;
+        match (&mut self.ssao, ssao_config) {
+            (Some(ssao), Some(ssao_config)) => ssao.set_config(ssao_config),
+            (ssao, ssao_config) => {
+                *ssao = ssao_config.map(|ssao_config| {
+                    Ssao::new(
+                        self.context.device(),
+                        self.context.queue(),
+                        &self.scene_layout,
+                        &self.mesh_layout,
+                        self.materials.layout(),
+                        self.target_size,
+                        ssao_config,
+                    )
+                });
+                if self.scene_bind_group.is_some() {
+                    self.write_scene();
                 }
+            }
+        }
+    }
 
-                let vertex_buf = self.device.create_buffer_init(&BufferInitDescriptor {
-                    label: None,
-                    contents: bytemuck::cast_slice(&vertices),
-                    usage: BufferUsages::VERTEX,
+    pub fn set_render_path(&mut self, render_path: RenderPath) {
+        self.config.render_path = render_path;
+        self.deferred = (render_path == RenderPath::Deferred).then(|| {
+            Deferred::new(
+                self.context.device(),
+                &self.shader_module,
+                &self.scene_layout,
+                &self.mesh_layout,
+                self.materials.layout(),
+                &self.pipeline_layout,
+                &self.user.vertex_buffers(),
+                self.config.scene_target_format(),
+                self.config.taa.is_some(),
+                &self.shader_defs,
+                self.target_size,
+            )
+        });
+    }
+
+    pub fn set_transparency(&mut self, transparency: Option<TransparencyMode>) {
+        self.config.transparency = transparency;
+        self.transparency = transparency.map(|mode| {
+            Transparency::new(
+                self.context.device(),
+                &self.shader_module,
+                &self.pipeline_layout,
+                &self.user.vertex_buffers(),
+                self.config.scene_target_format(),
+                self.config.taa.is_some(),
+                &self.shader_defs,
+                mode,
+                self.target_size,
+            )
+        });
+    }
+
+    pub fn set_bloom(&mut self, bloom_config: Option<BloomConfig>) {
+        self.config.bloom = bloom_config;
+        match (&mut self.bloom, bloom_config) {
+            (Some(bloom), Some(bloom_config)) => bloom.set_config(bloom_config),
+            (bloom, bloom_config) => {
+                *bloom = bloom_config.map(|bloom_config| {
+                    Bloom::new(
+                        self.context.device(),
+                        self.config.primary_target_format,
+                        self.target_size,
+                        bloom_config,
+                    )
                 });
-                self.meshes.push(GpuMesh {
-                    vertex_count: vertices.len() as u32,
-                    vertex_buf,
+                self.rebuild_scene_pipelines();
+            }
+        }
+    }
+
+    pub fn set_taa(&mut self, taa_config: Option<TaaConfig>) {
+        self.config.taa = taa_config;
+        match (&mut self.taa, taa_config) {
+            (Some(taa), Some(taa_config)) => taa.set_config(taa_config),
+            (taa, taa_config) => {
+                *taa = taa_config.map(|taa_config| {
+                    Taa::new(
+                        self.context.device(),
+                        self.config.taa_target_format(),
+                        self.target_size,
+                        taa_config,
+                    )
                 });
+                self.rebuild_scene_pipelines();
             }
         }
     }
 
+    /// Lights the scene with an equirectangular HDR panorama, which is also drawn as the
+    /// background. `None` goes back to a constant ambient light and the clear color.
+    /// Keeps the current environment if the panorama is too large for the device.
+    pub fn set_environment(&mut self, image: Option<HdrImage>) -> io::Result<()> {
+        let (device, queue) = (self.context.device(), self.context.queue());
+        self.environment = match &image {
+            Some(image) => Environment::new(device, queue, image)?,
+            None => Environment::empty(device, queue),
+        };
+        self.environment_image = image;
+        if self.scene_bind_group.is_some() {
+            self.write_scene();
+        }
+        Ok(())
+    }
+
+    /// Loads a Radiance `.hdr` panorama with [`WgpuRenderer::set_environment`].
+    pub fn load_environment(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.set_environment(Some(HdrImage::load(path)?))
+    }
+
+    /// Takes effect with the next [`WgpuRenderer::write_scene`].
+    pub fn set_sky(&mut self, sky: Option<SkyConfig>) {
+        self.config.sky = sky;
+    }
+
+    /// Takes effect with the next [`WgpuRenderer::write_scene`].
+    pub fn set_fog(&mut self, fog: Option<FogConfig>) {
+        self.config.fog = fog;
+    }
+
+    pub fn set_view_mode(&mut self, view_mode: ViewMode) {
+        self.config.view_mode = view_mode;
+        self.view_mode_pipeline = create_view_mode_pipeline(
+            self.context.device(),
+            &self.view_mode_layout,
+            &self.view_mode_shader_module,
+            self.config.scene_target_format(),
+            self.config.taa.is_some(),
+            view_mode,
+        );
+    }
+
+    /// Layout of the user bind group at `index` in
+    /// [`RendererConfig::user_bind_group_layouts`], bound to group `3 + index`.
+    #[inline]
+    pub fn user_bind_group_layout(&self, index: usize) -> Option<&BindGroupLayout> {
+        self.user.layouts().get(index)
+    }
+
+    /// Sets the user bind group at `index` for every mesh without its own. User bind
+    /// groups belong to the device and have to be set again after
+    /// [`WgpuRenderer::recover_device`].
+    #[inline]
+    pub fn set_user_bind_group(&mut self, index: usize, bind_group: Option<BindGroup>) {
+        self.user.set_bind_group(index, bind_group);
+    }
+
+    /// Sets the user bind group at `index` for `mesh` only, `None` falls back to the one
+    /// of [`WgpuRenderer::set_user_bind_group`].
+    #[inline]
+    pub fn set_mesh_user_bind_group(
+        &mut self,
+        mesh: MeshHandle,
+        index: usize,
+        bind_group: Option<BindGroup>,
+    ) {
+        self.user.set_mesh_bind_group(mesh, index, bind_group);
+    }
+
+    #[inline]
+    pub fn config(&self) -> &RendererConfig {
+        &self.config
+    }
+
+    #[inline]
+    pub fn context(&self) -> &GpuContext {
+        &self.context
+    }
+
     #[inline]
     pub fn device(&self) -> &Device {
-        &self.device
+        self.context.device()
     }
 }
@@ -0,0 +1,241 @@
+use std::collections::{HashMap, HashSet};
+
+use wgpu::*;
+
+use crate::{
+    assets::{AssetRegistry, MeshHandle},
+    render::{GpuMesh, Material, MaterialTextures, ShaderData},
+    texture::{self, SamplerOptions},
+    with_skinning,
+};
+
+// Slots of the parameters uniform when the renderer is created.
+const INITIAL_SLOTS: u64 = 16;
+
+/// Prepends `alpha_mask.wgsl`, declaring the material bindings and `alpha_mask`, to a
+/// shader of meshes along with the skinning.
+pub(crate) fn with_alpha_mask(source: &str) -> ShaderSource<'static> {
+    with_skinning(&format!("{}\n{}", include_str!("alpha_mask.wgsl"), source))
+}
+
+fn create_layout(device: &Device) -> BindGroupLayout {
+    let texture_entry = |binding| BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::FRAGMENT,
+        ty: BindingType::Texture {
+            sample_type: TextureSampleType::Float { filterable: true },
+            view_dimension: TextureViewDimension::D2,
+            multisampled: false,
+        },
+        count: None,
+    };
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: None,
+        entries: &[
+            texture_entry(0),
+            texture_entry(1),
+            texture_entry(2),
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Material::min_binding_size(),
+                },
+                count: None,
+            },
+        ],
+    })
+}
+
+fn create_uniform(device: &Device, size: BufferAddress) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: None,
+        size,
+        usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// `textures` are the base color, metallic-roughness and emissive textures.
+fn create_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    textures: [&TextureView; 3],
+    sampler: &Sampler,
+    uniform: &Buffer,
+) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
+        label: None,
+        layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(textures[0]),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(textures[1]),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::TextureView(textures[2]),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: BindingResource::Sampler(sampler),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::Buffer(BufferBinding {
+                    buffer: uniform,
+                    offset: 0,
+                    size: Material::min_binding_size(),
+                }),
+            },
+        ],
+    })
+}
+
+/// Materials of the drawn meshes, bound as group 2 of the scene shader. The parameters
+/// of every mesh are in a slot of a uniform buffer selected by a dynamic offset, so
+/// there is only a bind group per set of textures.
+pub(crate) struct Materials {
+    layout: BindGroupLayout,
+    // Bound in place of missing textures.
+    white_texture: TextureView,
+    uniform: Buffer,
+    // Size of a slot, aligned for dynamic offsets.
+    stride: BufferAddress,
+    // Meshes missing from them use the default material in the first slot.
+    offsets: HashMap<MeshHandle, u32>,
+    // The default textures are always there, they're all white.
+    bind_groups: HashMap<MaterialTextures, BindGroup>,
+}
+
+impl Materials {
+    pub fn new(device: &Device, queue: &Queue, assets: &mut AssetRegistry) -> Self {
+        let layout = create_layout(device);
+        let white_texture = texture::create_white_view(device, queue);
+        let alignment = device.limits().min_uniform_buffer_offset_alignment as BufferAddress;
+        let stride = Material::min_binding_size()
+            .unwrap()
+            .get()
+            .next_multiple_of(alignment);
+        let uniform = create_uniform(device, stride * INITIAL_SLOTS);
+        let default_bind_group = create_bind_group(
+            device,
+            &layout,
+            [&white_texture; 3],
+            &assets.sampler(device, SamplerOptions::default()),
+            &uniform,
+        );
+        queue.write_buffer(&uniform, 0, &Material::default().as_raw());
+
+        Self {
+            layout,
+            white_texture,
+            uniform,
+            stride,
+            offsets: HashMap::new(),
+            bind_groups: HashMap::from([(MaterialTextures::default(), default_bind_group)]),
+        }
+    }
+
+    #[inline]
+    pub fn layout(&self) -> &BindGroupLayout {
+        &self.layout
+    }
+
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.uniform.size()
+    }
+
+    /// Writes the materials of `meshes` as they are now, and creates the bind groups of
+    /// their textures. Called every frame so newly loaded meshes and edited materials
+    /// show up right away.
+    pub fn write(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        assets: &mut AssetRegistry,
+        meshes: impl IntoIterator<Item = MeshHandle>,
+    ) {
+        let stride = self.stride as usize;
+        let mut raw = Material::default().as_raw();
+        raw.resize(stride, 0);
+        self.offsets.clear();
+        let mut used_textures = HashSet::from([MaterialTextures::default()]);
+        for handle in meshes {
+            let Some(material) = assets.mesh(handle).map(|mesh| mesh.material) else {
+                continue;
+            };
+            self.offsets.insert(handle, raw.len() as u32);
+            let start = raw.len();
+            raw.extend(material.as_raw());
+            raw.resize(start + stride, 0);
+            used_textures.insert(material.textures);
+        }
+
+        if raw.len() as BufferAddress > self.uniform.size() {
+            let size = (raw.len() as BufferAddress).next_power_of_two();
+            self.uniform = create_uniform(device, size);
+            // They bind the previous buffer.
+            self.bind_groups.clear();
+        }
+        queue.write_buffer(&self.uniform, 0, &raw);
+
+        self.bind_groups
+            .retain(|textures, _| used_textures.contains(textures));
+        for textures in used_textures {
+            if self.bind_groups.contains_key(&textures) {
+                continue;
+            }
+            let sampler = assets.sampler(device, textures.sampler);
+            let views = [
+                textures.base_color,
+                textures.metallic_roughness,
+                textures.emissive,
+            ]
+            .map(|texture| {
+                texture
+                    .and_then(|t| assets.texture(t))
+                    .map_or(&self.white_texture, |t| &t.view)
+            });
+            let bind_group =
+                create_bind_group(device, &self.layout, views, &sampler, &self.uniform);
+            self.bind_groups.insert(textures, bind_group);
+        }
+    }
+
+    /// Binds the material of `mesh` as `group`, the default one if it wasn't written by
+    /// [`Materials::write`].
+    pub fn bind<'a>(
+        &'a self,
+        pass: &mut RenderPass<'a>,
+        group: u32,
+        handle: MeshHandle,
+        mesh: &GpuMesh,
+    ) {
+        let offset = self.offsets.get(&handle).copied().unwrap_or(0);
+        let bind_group = self
+            .bind_groups
+            .get(&mesh.material.textures)
+            .unwrap_or_else(|| &self.bind_groups[&MaterialTextures::default()]);
+        pass.set_bind_group(group, bind_group, &[offset]);
+    }
+
+    /// Binds the default material as `group`, for passes without meshes.
+    #[inline]
+    pub fn bind_default<'a>(&'a self, pass: &mut RenderPass<'a>, group: u32) {
+        pass.set_bind_group(group, &self.bind_groups[&MaterialTextures::default()], &[0]);
+    }
+}
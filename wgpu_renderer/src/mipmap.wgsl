@@ -0,0 +1,26 @@
+// Downsamples the previous mip of a texture into the next one.
+
+struct VertexOutput {
+    @builtin(position) position_cs: vec4f,
+    @location(0) uv: vec2f,
+}
+
+@group(0) @binding(0) var source_map: texture_2d<f32>;
+@group(0) @binding(1) var source_sampler: sampler;
+
+// A triangle covering the whole target.
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let uv = vec2f(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+
+    var output: VertexOutput;
+    output.position_cs = vec4f(uv * 2. - 1., 0., 1.);
+    output.uv = vec2f(uv.x, 1. - uv.y);
+    return output;
+}
+
+// Sampling between the 2x2 texels under the pixel averages them.
+@fragment
+fn fragment(input: VertexOutput) -> @location(0) vec4f {
+    return textureSampleLevel(source_map, source_sampler, input.uv, 0.);
+}
@@ -0,0 +1,37 @@
+// Draws the back faces of the selected mesh pushed out along their normals, so only a
+// rim around the mesh passes the depth test.
+
+const OUTLINE_COLOR: vec3f = vec3f(1., 0.5, 0.);
+// In normalized device coordinates.
+const OUTLINE_WIDTH: f32 = 0.006;
+
+struct Camera {
+    view: mat4x4f,
+    proj: mat4x4f,
+}
+
+struct VertexInput {
+    @location(0) position: vec3f,
+    @location(1) normal: vec3f,
+    @location(2) joints: vec4u,
+    @location(3) weights: vec4f,
+}
+
+@group(0) @binding(0) var<uniform> camera: Camera;
+
+@vertex
+fn vertex(input: VertexInput) -> @builtin(position) vec4f {
+    let skin = skin_matrix(input.joints, input.weights);
+    let view_proj = camera.proj * camera.view;
+    let position_cs = view_proj * skin * vec4f(input.position, 1.);
+    let normal_cs = (view_proj * skin * vec4f(input.normal, 0.)).xy;
+
+    // Scaled by w to keep the width constant on screen.
+    let offset = normalize(normal_cs + vec2f(1e-6)) * OUTLINE_WIDTH * position_cs.w;
+    return position_cs + vec4f(offset, 0., 0.);
+}
+
+@fragment
+fn fragment() -> @location(0) vec4f {
+    return vec4f(OUTLINE_COLOR, 1.);
+}
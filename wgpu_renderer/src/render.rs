@@ -1,8 +1,17 @@
-use std::num::NonZeroU64;
+use std::{collections::HashMap, num::NonZeroU64, str::FromStr};
 
 use bytemuck::{Pod, Zeroable};
-use glam::{Mat4, Vec3};
-use wgpu::Buffer;
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use wgpu::{
+    util::{BufferInitDescriptor, DeviceExt},
+    Buffer, BufferUsages, Device,
+};
+
+use crate::{
+    assets::TextureHandle,
+    scene::{Aabb, Ray},
+    texture::SamplerOptions,
+};
 
 pub trait ShaderData: Sized {
     fn as_raw(&self) -> Vec<u8>;
@@ -17,26 +26,341 @@ pub trait ShaderData: Sized {
 pub struct Vertex {
     pub position: Vec3,
     pub normal: Vec3,
+    pub joints: [u16; 4],
+    /// All zero for meshes that aren't skinned.
+    pub weights: [f32; 4],
+    pub uv: Vec2,
+}
+
+impl Vertex {
+    /// Same as the skinning in the vertex shader, `joint_matrices` are ignored for
+    /// vertices without weights.
+    pub fn skinned_position(&self, joint_matrices: &[Mat4]) -> Vec3 {
+        // Meshes with weights aren't necessarily skinned.
+        if self.weights == [0.; 4] || joint_matrices.is_empty() {
+            return self.position;
+        }
+
+        let skin = self
+            .joints
+            .iter()
+            .zip(self.weights)
+            .map(|(&joint, weight)| joint_matrices[joint as usize] * weight)
+            .fold(Mat4::ZERO, |acc, m| acc + m);
+        skin.transform_point3(self.position)
+    }
+}
+
+/// Textures multiplied with the parameters of a [`Material`], sampled with the first
+/// UV set. Missing textures are white.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct MaterialTextures {
+    /// sRGB color and alpha.
+    pub base_color: Option<TextureHandle>,
+    /// Linear, with the roughness in green and the metallic in blue like glTF.
+    pub metallic_roughness: Option<TextureHandle>,
+    /// sRGB color.
+    pub emissive: Option<TextureHandle>,
+    pub sampler: SamplerOptions,
+}
+
+/// How the alpha of the base color covers what's behind a surface, like glTF.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AlphaMode {
+    /// The alpha is ignored.
+    #[default]
+    Opaque,
+    /// Fragments with an alpha below `cutoff` are discarded, from the shadows and the
+    /// ambient occlusion too. The others are opaque.
+    Mask { cutoff: f32 },
+    /// Blended over the opaque meshes without writing depth, see
+    /// [`TransparencyMode`]. Opaque without [`crate::RendererConfig::transparency`].
+    Blend,
+}
+
+/// Metallic-roughness surface parameters, like the glTF PBR material.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Material {
+    /// Linear RGB color and alpha.
+    pub base_color: Vec4,
+    pub metallic: f32,
+    pub roughness: f32,
+    /// Linear RGB light given off by the surface, above 1 to glow with bloom.
+    pub emissive: Vec3,
+    /// Bound to group 2 of the scene shader, with the sampler at binding 3.
+    pub textures: MaterialTextures,
+    pub alpha_mode: AlphaMode,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            base_color: Vec4::ONE,
+            metallic: 0.,
+            roughness: 0.5,
+            emissive: Vec3::ZERO,
+            textures: MaterialTextures::default(),
+            alpha_mode: AlphaMode::Opaque,
+        }
+    }
+}
+
+impl ShaderData for Material {
+    fn as_raw(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(48);
+        buf.extend_from_slice(bytemuck::cast_slice(self.base_color.as_ref()));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.metallic));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.roughness));
+        // Nothing is below the cutoff of the other modes.
+        let alpha_cutoff = match self.alpha_mode {
+            AlphaMode::Mask { cutoff } => cutoff,
+            AlphaMode::Opaque | AlphaMode::Blend => 0.,
+        };
+        buf.extend_from_slice(bytemuck::bytes_of(&alpha_cutoff));
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(bytemuck::cast_slice(self.emissive.as_ref()));
+        buf.extend_from_slice(&[0; 4]);
+        buf
+    }
+
+    fn min_binding_size() -> Option<NonZeroU64> {
+        NonZeroU64::new(48)
+    }
 }
 
 pub struct GpuMesh {
+    /// `object/group` for OBJ files, the mesh name for glTF files.
+    pub name: Option<String>,
+    pub material: Material,
     pub vertex_count: u32,
     pub vertex_buf: Buffer,
+    /// CPU copy of the vertices, used to re-upload the mesh after a device loss.
+    pub vertices: Vec<Vertex>,
+    /// Values of the user vertex attributes of [`crate::RendererConfig`], in their order
+    /// for every vertex. Each one starts at a multiple of its size or 4 bytes, whichever
+    /// is smaller, and every vertex is padded to a multiple of 4 bytes.
+    pub attributes: Vec<u8>,
+    pub attribute_buf: Option<Buffer>,
+    /// Bounds of the vertices before skinning, `None` for empty meshes.
+    pub aabb: Option<Aabb>,
+    /// The joint with the largest total weight, which moves the bounds of skinned
+    /// meshes in [`GpuMesh::approximate_bounds`].
+    pub dominant_joint: Option<u16>,
+}
+
+impl GpuMesh {
+    pub fn new(
+        device: &Device,
+        name: Option<String>,
+        material: Material,
+        vertices: Vec<Vertex>,
+    ) -> Self {
+        let vertex_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&vertices),
+            usage: BufferUsages::VERTEX,
+        });
+
+        let aabb = Aabb::from_points(vertices.iter().map(|v| v.position));
+        let mut joint_weights = HashMap::new();
+        for vertex in &vertices {
+            for (joint, weight) in vertex.joints.into_iter().zip(vertex.weights) {
+                *joint_weights.entry(joint).or_insert(0.) += weight;
+            }
+        }
+        let dominant_joint = joint_weights
+            .into_iter()
+            .filter(|&(_, weight)| weight > 0.)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(joint, _)| joint);
+
+        Self {
+            name,
+            material,
+            vertex_count: vertices.len() as u32,
+            vertex_buf,
+            vertices,
+            attributes: Vec::new(),
+            attribute_buf: None,
+            aabb,
+            dominant_joint,
+        }
+    }
+
+    /// Uploads the values of the user vertex attributes, see [`GpuMesh::attributes`].
+    pub fn set_attributes(&mut self, device: &Device, attributes: Vec<u8>) {
+        self.attribute_buf = (!attributes.is_empty()).then(|| {
+            device.create_buffer_init(&BufferInitDescriptor {
+                label: None,
+                contents: &attributes,
+                usage: BufferUsages::VERTEX,
+            })
+        });
+        self.attributes = attributes;
+    }
+
+    /// Bounds of the vertices skinned by `joint_matrices`, `None` for empty meshes.
+    pub fn bounds(&self, joint_matrices: &[Mat4]) -> Option<Aabb> {
+        Aabb::from_points(
+            self.vertices
+                .iter()
+                .map(|v| v.skinned_position(joint_matrices)),
+        )
+    }
+
+    /// The cached bounds moved by the dominant joint of `joint_matrices`, cheap enough
+    /// to sort meshes every frame but only exact for meshes that aren't skinned.
+    pub fn approximate_bounds(&self, joint_matrices: &[Mat4]) -> Option<Aabb> {
+        let joint_matrix = self
+            .dominant_joint
+            .and_then(|joint| joint_matrices.get(joint as usize));
+        match joint_matrix {
+            Some(&matrix) => self.aabb.map(|aabb| aabb.transform(matrix)),
+            None => self.aabb,
+        }
+    }
+
+    /// Distance along `ray` to the closest triangle hit, using the CPU copy of the
+    /// vertices skinned by `joint_matrices`.
+    pub fn intersect(&self, ray: &Ray, joint_matrices: &[Mat4]) -> Option<f32> {
+        self.vertices
+            .chunks_exact(3)
+            .filter_map(|tri| {
+                ray.intersect_triangle([
+                    tri[0].skinned_position(joint_matrices),
+                    tri[1].skinned_position(joint_matrices),
+                    tri[2].skinned_position(joint_matrices),
+                ])
+            })
+            .min_by(f32::total_cmp)
+    }
+}
+
+/// How the scene shader lights meshes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RenderPath {
+    /// Every mesh is lit as it's drawn.
+    #[default]
+    Forward,
+    /// Meshes are drawn into a G-buffer, which is lit once per pixel. Needs the
+    /// `fragment_gbuffer`, `vertex_fullscreen` and `fragment_lighting` entry points in
+    /// the scene shader.
+    Deferred,
+}
+
+/// How meshes with [`AlphaMode::Blend`] are drawn, after every opaque mesh.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransparencyMode {
+    /// Blended back to front, sorted by the view depth of the center of their bounds.
+    /// Needs the `fragment_blend` entry point in the scene shader.
+    #[default]
+    Sorted,
+    /// Weighted blended order-independent transparency, which needs no sorting and
+    /// handles intersecting meshes, but only approximates the order by depth. Needs the
+    /// `fragment_oit` entry point in the scene shader.
+    WeightedBlended,
+}
+
+/// Replaces the scene shader to inspect meshes and lighting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ViewMode {
+    #[default]
+    Shaded,
+    /// Uses line polygons if the device supports them, otherwise edges are detected
+    /// from barycentric coordinates.
+    Wireframe,
+    Normals,
+    Uvs,
+    /// Linear distance from the camera.
+    Depth,
+    /// Brighter where more fragments are drawn, ignoring depth.
+    Overdraw,
+    /// Diffuse lighting of white surfaces.
+    LightOnly,
+}
+
+impl ViewMode {
+    pub const ALL: [ViewMode; 7] = [
+        ViewMode::Shaded,
+        ViewMode::Wireframe,
+        ViewMode::Normals,
+        ViewMode::Uvs,
+        ViewMode::Depth,
+        ViewMode::Overdraw,
+        ViewMode::LightOnly,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ViewMode::Shaded => "shaded",
+            ViewMode::Wireframe => "wireframe",
+            ViewMode::Normals => "normals",
+            ViewMode::Uvs => "uvs",
+            ViewMode::Depth => "depth",
+            ViewMode::Overdraw => "overdraw",
+            ViewMode::LightOnly => "light-only",
+        }
+    }
+
+    /// The mode after this one in [`ViewMode::ALL`], wrapping around.
+    pub fn next(&self) -> Self {
+        let index = Self::ALL.iter().position(|m| m == self).unwrap();
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+impl FromStr for ViewMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|m| m.name() == s)
+            .ok_or_else(|| format!("unknown view mode `{}`", s))
+    }
+}
+
+impl ShaderData for Mat4 {
+    fn as_raw(&self) -> Vec<u8> {
+        bytemuck::cast_slice(self.as_ref()).to_vec()
+    }
 }
 
 #[derive(Default, Debug)]
 pub struct GpuCamera {
     pub view: Mat4,
+    /// Includes the jitter.
     pub proj: Mat4,
+    /// Unjittered view projection of the previous frame, for motion vectors.
+    pub prev_view_proj: Mat4,
+    /// Offset of the projection in NDC, zero unless TAA is enabled.
+    pub jitter: Vec2,
+}
+
+impl GpuCamera {
+    pub fn unjittered_view_proj(&self) -> Mat4 {
+        Mat4::from_translation(-self.jitter.extend(0.)) * self.proj * self.view
+    }
 }
 
 impl ShaderData for GpuCamera {
+    // The inverse view projection follows the projection, which shaders can't compute
+    // themselves, to reconstruct positions from depth.
     fn as_raw(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(std::mem::size_of::<Self>());
+        let inv_view_proj = (self.proj * self.view).inverse();
+        let mut buf = Vec::with_capacity(272);
         buf.extend_from_slice(bytemuck::cast_slice(self.view.as_ref()));
         buf.extend_from_slice(bytemuck::cast_slice(self.proj.as_ref()));
+        buf.extend_from_slice(bytemuck::cast_slice(inv_view_proj.as_ref()));
+        buf.extend_from_slice(bytemuck::cast_slice(self.prev_view_proj.as_ref()));
+        buf.extend_from_slice(bytemuck::cast_slice(self.jitter.as_ref()));
+        buf.extend_from_slice(&[0; 8]);
         buf
     }
+
+    fn min_binding_size() -> Option<NonZeroU64> {
+        NonZeroU64::new(272)
+    }
 }
 
 #[derive(Default, Debug)]
@@ -44,15 +368,54 @@ pub struct GpuDirectionalLight {
     pub translation: Vec3,
     pub direction: Vec3,
     pub color: Vec3,
+    /// Layer of the first shadow cascade in the shadow map array.
+    pub first_cascade: u32,
+    pub cascade_count: u32,
+    /// Index of the [`ShadowFilter`](crate::shadow::ShadowFilter) in the scene shader.
+    pub filter: u32,
+    pub filter_samples: u32,
+    /// Radius, light size or light bleeding reduction depending on the filter.
+    pub filter_param: f32,
+    pub depth_bias: f32,
+    pub normal_bias: f32,
 }
 
 impl ShaderData for GpuDirectionalLight {
+    // Every vec3 is aligned to 16 bytes, the shadow settings fill the gaps.
     fn as_raw(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(std::mem::size_of::<Self>());
+        let mut buf = Vec::with_capacity(64);
         buf.extend_from_slice(bytemuck::cast_slice(self.translation.as_ref()));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.first_cascade));
         buf.extend_from_slice(bytemuck::cast_slice(self.direction.as_ref()));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.cascade_count));
+        buf.extend_from_slice(bytemuck::cast_slice(self.color.as_ref()));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.filter));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.filter_samples));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.filter_param));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.depth_bias));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.normal_bias));
+        buf
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct GpuPointLight {
+    pub position: Vec3,
+    pub radius: f32,
+    pub color: Vec3,
+}
+
+impl ShaderData for GpuPointLight {
+    fn as_raw(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(32);
+        buf.extend_from_slice(bytemuck::cast_slice(self.position.as_ref()));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.radius));
         buf.extend_from_slice(bytemuck::cast_slice(self.color.as_ref()));
-        buf.extend_from_slice(&[0; 12]);
+        buf.extend_from_slice(&[0; 4]);
         buf
     }
+
+    fn min_binding_size() -> Option<NonZeroU64> {
+        NonZeroU64::new(32)
+    }
 }
@@ -1,4 +1,6 @@
-use glam::{Mat4, Quat, Vec3};
+use glam::{Mat4, Quat, Vec2, Vec3, Vec4Swizzles};
+
+use crate::shadow::ShadowFilter;
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct Transform {
@@ -33,9 +35,146 @@ pub struct Camera {
     pub far: f32,
 }
 
+impl Camera {
+    #[inline]
+    pub fn view_matrix(&self) -> Mat4 {
+        self.transform.compute_matrix()
+    }
+
+    #[inline]
+    pub fn projection_matrix(&self) -> Mat4 {
+        Mat4::perspective_rh(self.fov, self.aspect_ratio, self.near, self.far)
+    }
+
+    /// Ray through `position`, given in pixels from the top left corner of a viewport
+    /// of size `viewport`.
+    pub fn viewport_ray(&self, position: Vec2, viewport: Vec2) -> Ray {
+        let ndc = position / viewport * Vec2::new(2., -2.) + Vec2::new(-1., 1.);
+        let inverse = (self.projection_matrix() * self.view_matrix()).inverse();
+        let near = inverse * ndc.extend(0.).extend(1.);
+        let far = inverse * ndc.extend(1.).extend(1.);
+        let near = near.xyz() / near.w;
+        let far = far.xyz() / far.w;
+
+        Ray {
+            origin: near,
+            direction: (far - near).normalize(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Option<Self> {
+        points.into_iter().fold(None, |aabb: Option<Self>, p| {
+            Some(match aabb {
+                Some(aabb) => Self {
+                    min: aabb.min.min(p),
+                    max: aabb.max.max(p),
+                },
+                None => Self { min: p, max: p },
+            })
+        })
+    }
+
+    #[inline]
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    #[inline]
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    #[inline]
+    pub fn half_extents(&self) -> Vec3 {
+        (self.max - self.min) * 0.5
+    }
+
+    /// The bounds of the box transformed by `matrix`.
+    pub fn transform(&self, matrix: Mat4) -> Self {
+        let center = matrix.transform_point3(self.center());
+        let half_extents = self.half_extents();
+        // Each axis of the result spans the absolute projections of the half extents.
+        let extents = [matrix.x_axis, matrix.y_axis, matrix.z_axis]
+            .into_iter()
+            .zip(half_extents.to_array())
+            .map(|(axis, extent)| axis.truncate().abs() * extent)
+            .fold(Vec3::ZERO, |acc, e| acc + e);
+        Self {
+            min: center - extents,
+            max: center + extents,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+}
+
+impl Ray {
+    /// Möller–Trumbore intersection, hits on both faces are reported.
+    pub fn intersect_triangle(&self, [a, b, c]: [Vec3; 3]) -> Option<f32> {
+        let ab = b - a;
+        let ac = c - a;
+        let p = self.direction.cross(ac);
+        let det = ab.dot(p);
+        if det.abs() < f32::EPSILON {
+            return None;
+        }
+
+        let inv_det = 1. / det;
+        let ao = self.origin - a;
+        let u = ao.dot(p) * inv_det;
+        if !(0. ..=1.).contains(&u) {
+            return None;
+        }
+
+        let q = ao.cross(ab);
+        let v = self.direction.dot(q) * inv_det;
+        if v < 0. || u + v > 1. {
+            return None;
+        }
+
+        let t = ac.dot(q) * inv_det;
+        (t > 0.).then_some(t)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct DirectionalLight {
     pub translation: Vec3,
     pub direction: Vec3,
     pub color: Vec3,
+    /// Number of shadow cascades splitting the view frustum, 0 disables shadows.
+    pub cascade_count: u32,
+    /// 0 splits the frustum uniformly, 1 logarithmically.
+    pub split_lambda: f32,
+    /// Distance from the camera covered by the cascades.
+    pub shadow_distance: f32,
+    pub shadow_filter: ShadowFilter,
+    /// World space offset of the receiver towards the light, against shadow acne.
+    pub depth_bias: f32,
+    /// Offset of the receiver along its normal in shadow map texels, against acne on
+    /// surfaces at grazing angles without the peter-panning of a large depth bias.
+    pub normal_bias: f32,
+}
+
+/// Light emitted in every direction, fading out to nothing at `radius`.
+#[derive(Debug, Clone, Copy)]
+pub struct PointLight {
+    pub position: Vec3,
+    pub color: Vec3,
+    pub radius: f32,
 }
@@ -0,0 +1,727 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use glam::{Mat4, Vec3, Vec3Swizzles};
+use wgpu::*;
+
+use crate::{
+    assets::{AssetRegistry, MeshHandle},
+    material::{with_alpha_mask, Materials},
+    render::{AlphaMode, ShaderData},
+    scene::{Camera, DirectionalLight},
+    stats::{FrameStats, GpuTimer},
+    vertex_buffer_layout,
+};
+
+const SHADOW_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+const MOMENTS_FORMAT: TextureFormat = TextureFormat::Rg32Float;
+/// Size of the Poisson disk in the scene shader.
+pub const MAX_FILTER_SAMPLES: u32 = 16;
+
+/// How the shadow map is filtered, from the cheapest and hardest to the softest.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ShadowFilter {
+    /// A single comparison, filtered by the sampler over 2x2 texels.
+    #[default]
+    Hardware,
+    /// Up to [`MAX_FILTER_SAMPLES`] comparisons spread over a Poisson disk `radius`
+    /// texels wide.
+    Poisson { samples: u32, radius: f32 },
+    /// Poisson filtering with a radius growing with the distance to the blockers, so
+    /// shadows harden near contact. `light_size` is the tangent of the angular
+    /// diameter of the light.
+    Pcss { samples: u32, light_size: f32 },
+    /// Compares against the mean and variance of the depth, blurred over
+    /// `blur_radius` texels. Visibility below `light_bleeding` is cut off to hide light
+    /// leaking through overlapping occluders.
+    Variance {
+        blur_radius: u32,
+        light_bleeding: f32,
+    },
+}
+
+impl ShadowFilter {
+    /// Name of the override constant compiling the filter into the scene shader.
+    fn shader_def(&self) -> Option<&'static str> {
+        match self {
+            ShadowFilter::Hardware => None,
+            ShadowFilter::Poisson { .. } => Some("SHADOW_FILTER_POISSON"),
+            ShadowFilter::Pcss { .. } => Some("SHADOW_FILTER_PCSS"),
+            ShadowFilter::Variance { .. } => Some("SHADOW_FILTER_VARIANCE"),
+        }
+    }
+
+    /// The index of the filter in the scene shader, its sample count and parameter.
+    pub(crate) fn gpu_params(&self) -> (u32, u32, f32) {
+        match *self {
+            ShadowFilter::Hardware => (0, 0, 0.),
+            ShadowFilter::Poisson { samples, radius } => {
+                (1, samples.clamp(1, MAX_FILTER_SAMPLES), radius)
+            }
+            ShadowFilter::Pcss {
+                samples,
+                light_size,
+            } => (2, samples.clamp(1, MAX_FILTER_SAMPLES), light_size),
+            ShadowFilter::Variance {
+                blur_radius,
+                light_bleeding,
+            } => (3, blur_radius, light_bleeding),
+        }
+    }
+}
+
+/// Override constants enabling the filters used by `lights`, so the scene pipeline
+/// doesn't pay for the others.
+pub(crate) fn shader_defs(lights: &[DirectionalLight]) -> HashMap<String, f64> {
+    let mut defs = HashMap::from(
+        [
+            "SHADOW_FILTER_POISSON",
+            "SHADOW_FILTER_PCSS",
+            "SHADOW_FILTER_VARIANCE",
+        ]
+        .map(|name| (name.to_owned(), 0.)),
+    );
+    for light in lights.iter().filter(|l| l.cascade_count > 0) {
+        if let Some(name) = light.shadow_filter.shader_def() {
+            defs.insert(name.to_owned(), 1.);
+        }
+    }
+    defs
+}
+
+/// Orthographic light projection covering one slice of the camera frustum.
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowCascade {
+    pub view_proj: Mat4,
+    /// View space distance from the camera where the slice ends.
+    pub split_far: f32,
+    /// World space size of a shadow map texel.
+    pub texel_size: f32,
+    /// World space distance between the near and far plane of the projection.
+    pub depth_range: f32,
+}
+
+impl ShaderData for ShadowCascade {
+    fn as_raw(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(std::mem::size_of::<Self>());
+        buf.extend_from_slice(bytemuck::cast_slice(self.view_proj.as_ref()));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.split_far));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.texel_size));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.depth_range));
+        buf.extend_from_slice(&[0; 4]);
+        buf
+    }
+
+    fn min_binding_size() -> Option<std::num::NonZeroU64> {
+        std::num::NonZeroU64::new(80)
+    }
+}
+
+/// The projection of the cascade being rendered and the blur of its variance map.
+struct CascadeUniform {
+    view_proj: Mat4,
+    blur_radius: u32,
+}
+
+impl ShaderData for CascadeUniform {
+    fn as_raw(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(80);
+        buf.extend_from_slice(bytemuck::cast_slice(self.view_proj.as_ref()));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.blur_radius));
+        buf.extend_from_slice(&[0; 12]);
+        buf
+    }
+
+    fn min_binding_size() -> Option<std::num::NonZeroU64> {
+        std::num::NonZeroU64::new(80)
+    }
+}
+
+/// Splits `[near, far]` into `count` slices, blending uniform and logarithmic splits by
+/// `lambda`. Returns the far distance of every slice.
+fn split_distances(near: f32, far: f32, count: u32, lambda: f32) -> Vec<f32> {
+    (1..=count)
+        .map(|i| {
+            let t = i as f32 / count as f32;
+            let uniform = near + (far - near) * t;
+            let log = near * (far / near).powf(t);
+            uniform + (log - uniform) * lambda
+        })
+        .collect()
+}
+
+/// Fits a projection around the bounding sphere of the frustum slice, so it doesn't
+/// change size when the camera rotates, and snaps it to whole texels, so it doesn't
+/// shimmer when the camera moves.
+fn fit_cascade(
+    camera: &Camera,
+    light: &DirectionalLight,
+    near: f32,
+    far: f32,
+    resolution: u32,
+) -> ShadowCascade {
+    let camera_to_world = camera.view_matrix().inverse();
+    let tan_y = (camera.fov * 0.5).tan();
+    let tan_x = tan_y * camera.aspect_ratio;
+    let corners = [near, far].into_iter().flat_map(|d| {
+        [(-1., -1.), (1., -1.), (-1., 1.), (1., 1.)].map(|(x, y)| {
+            camera_to_world.transform_point3(Vec3::new(x * tan_x * d, y * tan_y * d, -d))
+        })
+    });
+    let corners = corners.collect::<Vec<_>>();
+
+    let center = corners.iter().sum::<Vec3>() / corners.len() as f32;
+    let radius = corners
+        .iter()
+        .map(|c| c.distance(center))
+        .fold(0., f32::max);
+    // Rounded, so floating point noise doesn't change the texel size.
+    let radius = (radius * 16.).ceil() / 16.;
+
+    let direction = light.direction.normalize();
+    let up = match direction.y.abs() > 0.99 {
+        true => Vec3::Z,
+        false => Vec3::Y,
+    };
+    let light_view = Mat4::look_to_rh(Vec3::ZERO, direction, up);
+
+    let texel_size = 2. * radius / resolution as f32;
+    let center_ls = light_view.transform_point3(center);
+    let snapped = (center_ls.xy() / texel_size).floor() * texel_size;
+
+    // Casters between the light and the slice are kept by extending towards the light.
+    let near_plane = -(center_ls.z + radius + light.shadow_distance);
+    let far_plane = -(center_ls.z - radius);
+    let proj = Mat4::orthographic_rh(
+        snapped.x - radius,
+        snapped.x + radius,
+        snapped.y - radius,
+        snapped.y + radius,
+        near_plane,
+        far_plane,
+    );
+    ShadowCascade {
+        view_proj: proj * light_view,
+        split_far: far,
+        texel_size,
+        depth_range: far_plane - near_plane,
+    }
+}
+
+pub fn compute_cascades(
+    camera: &Camera,
+    light: &DirectionalLight,
+    resolution: u32,
+) -> Vec<ShadowCascade> {
+    let far = light.shadow_distance.min(camera.far);
+    let splits = split_distances(camera.near, far, light.cascade_count, light.split_lambda);
+
+    let mut near = camera.near;
+    splits
+        .into_iter()
+        .map(|split_far| {
+            let cascade = fit_cascade(camera, light, near, split_far, resolution);
+            near = split_far;
+            cascade
+        })
+        .collect()
+}
+
+fn create_layer_views(texture: &Texture, layers: u32) -> Vec<TextureView> {
+    (0..layers)
+        .map(|layer| {
+            texture.create_view(&TextureViewDescriptor {
+                dimension: Some(TextureViewDimension::D2),
+                base_array_layer: layer,
+                array_layer_count: Some(1),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// Depth only, or discarding masked fragments with `fragment_entry_point`. Biasing is
+/// left to the lights, see `DirectionalLight::depth_bias`.
+fn create_depth_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    fragment_entry_point: Option<&str>,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: PipelineCompilationOptions::default(),
+            buffers: &[vertex_buffer_layout()],
+        },
+        fragment: fragment_entry_point.map(|entry_point| FragmentState {
+            module: shader_module,
+            entry_point,
+            compilation_options: PipelineCompilationOptions::default(),
+            targets: &[],
+        }),
+        primitive: PrimitiveState::default(),
+        depth_stencil: Some(DepthStencilState {
+            format: SHADOW_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: CompareFunction::LessEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+fn create_blur_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    entry_point: &str,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: PipelineCompilationOptions::default(),
+            buffers: &[],
+        },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point,
+            compilation_options: PipelineCompilationOptions::default(),
+            targets: &[Some(MOMENTS_FORMAT.into())],
+        }),
+        primitive: PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+/// Turns the depth of variance filtered cascades into blurred depth moments, with a
+/// horizontal pass into `temp_view` and a vertical pass into the moments array.
+struct VarianceBlur {
+    temp_view: TextureView,
+    moments_layer_views: Vec<TextureView>,
+    depth_bind_groups: Vec<BindGroup>,
+    temp_bind_group: BindGroup,
+    horizontal_pipeline: RenderPipeline,
+    vertical_pipeline: RenderPipeline,
+}
+
+impl VarianceBlur {
+    fn new(
+        device: &Device,
+        cascade_layout: &BindGroupLayout,
+        moments: &Texture,
+        depth_layer_views: &[TextureView],
+    ) -> Self {
+        let temp = device.create_texture(&TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                depth_or_array_layers: 1,
+                ..moments.size()
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: MOMENTS_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let temp_view = temp.create_view(&TextureViewDescriptor::default());
+        let moments_layer_views = create_layer_views(moments, moments.depth_or_array_layers());
+
+        let texture_layout = |binding, sample_type| {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: None,
+                entries: &[BindGroupLayoutEntry {
+                    binding,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type,
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                }],
+            })
+        };
+        let depth_layout = texture_layout(0, TextureSampleType::Depth);
+        let temp_layout = texture_layout(1, TextureSampleType::Float { filterable: false });
+
+        let depth_bind_groups = depth_layer_views
+            .iter()
+            .map(|view| {
+                device.create_bind_group(&BindGroupDescriptor {
+                    label: None,
+                    layout: &depth_layout,
+                    entries: &[BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::TextureView(view),
+                    }],
+                })
+            })
+            .collect();
+        let temp_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &temp_layout,
+            entries: &[BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(&temp_view),
+            }],
+        });
+
+        let shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("shadow_blur.wgsl"))),
+        });
+        let pipeline_layout = |texture_layout| {
+            device.create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[cascade_layout, texture_layout],
+                ..Default::default()
+            })
+        };
+        let horizontal_pipeline = create_blur_pipeline(
+            device,
+            &pipeline_layout(&depth_layout),
+            &shader_module,
+            "fragment_horizontal",
+        );
+        let vertical_pipeline = create_blur_pipeline(
+            device,
+            &pipeline_layout(&temp_layout),
+            &shader_module,
+            "fragment_vertical",
+        );
+
+        Self {
+            temp_view,
+            moments_layer_views,
+            depth_bind_groups,
+            temp_bind_group,
+            horizontal_pipeline,
+            vertical_pipeline,
+        }
+    }
+
+    fn encode(
+        &self,
+        command_encoder: &mut CommandEncoder,
+        layer: usize,
+        cascade_bind_group: &BindGroup,
+        gpu_timer: &mut Option<&mut GpuTimer>,
+    ) {
+        let passes = [
+            (
+                &self.temp_view,
+                &self.horizontal_pipeline,
+                &self.depth_bind_groups[layer],
+            ),
+            (
+                &self.moments_layer_views[layer],
+                &self.vertical_pipeline,
+                &self.temp_bind_group,
+            ),
+        ];
+        for (target, pipeline, bind_group) in passes {
+            let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Load,
+                        store: StoreOp::Store,
+                    },
+                })],
+                timestamp_writes: gpu_timer
+                    .as_mut()
+                    .and_then(|timer| timer.pass_writes("shadow blur")),
+                ..Default::default()
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, cascade_bind_group, &[]);
+            pass.set_bind_group(1, bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+    }
+}
+
+/// Depth texture array with one layer per cascade of every shadow casting light, and
+/// a matching array of depth moments if any of them is variance filtered.
+pub(crate) struct ShadowMaps {
+    resolution: u32,
+    layers: u32,
+    pub(crate) view: TextureView,
+    layer_views: Vec<TextureView>,
+    pub(crate) sampler: Sampler,
+    /// A single texel when no light is variance filtered, as it's always bound.
+    pub(crate) moments_view: TextureView,
+    variance_blur: Option<VarianceBlur>,
+    pub(crate) cascades_buffer: Buffer,
+    // A uniform with the projection of every cascade, bound while rendering it.
+    cascade_uniforms: Vec<(Buffer, BindGroup)>,
+    pipeline: RenderPipeline,
+    // For meshes with `AlphaMode::Mask`, discarding the fragments the scene shader does.
+    mask_pipeline: RenderPipeline,
+    pub(crate) cascades: Vec<ShadowCascade>,
+    // The blur radius of every variance filtered cascade.
+    blur_radii: Vec<Option<u32>>,
+}
+
+impl ShadowMaps {
+    pub fn new(
+        device: &Device,
+        mesh_layout: &BindGroupLayout,
+        material_layout: &BindGroupLayout,
+        resolution: u32,
+        layers: u32,
+        variance: bool,
+    ) -> Self {
+        // Bound even without shadow casting lights, so there is always a layer.
+        let layers = layers.max(1);
+        let texture = device.create_texture(&TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: resolution,
+                height: resolution,
+                depth_or_array_layers: layers,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: SHADOW_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let layer_views = create_layer_views(&texture, layers);
+
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            compare: Some(CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let moments_size = match variance {
+            true => texture.size(),
+            false => Extent3d::default(),
+        };
+        let moments = device.create_texture(&TextureDescriptor {
+            label: None,
+            size: moments_size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: MOMENTS_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let moments_view = moments.create_view(&TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+
+        let cascades_buffer = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: ShadowCascade::min_binding_size().unwrap().get() * layers as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let cascade_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: CascadeUniform::min_binding_size(),
+                },
+                count: None,
+            }],
+        });
+        let cascade_uniforms = (0..layers)
+            .map(|_| {
+                let buffer = device.create_buffer(&BufferDescriptor {
+                    label: None,
+                    size: CascadeUniform::min_binding_size().unwrap().get(),
+                    usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                let bind_group = device.create_bind_group(&BindGroupDescriptor {
+                    label: None,
+                    layout: &cascade_layout,
+                    entries: &[BindGroupEntry {
+                        binding: 0,
+                        resource: buffer.as_entire_binding(),
+                    }],
+                });
+                (buffer, bind_group)
+            })
+            .collect();
+
+        let variance_blur =
+            variance.then(|| VarianceBlur::new(device, &cascade_layout, &moments, &layer_views));
+
+        let shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: with_alpha_mask(include_str!("shadow.wgsl")),
+        });
+        let layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&cascade_layout, mesh_layout],
+            ..Default::default()
+        });
+        // Only the masked meshes bind their material.
+        let mask_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&cascade_layout, mesh_layout, material_layout],
+            ..Default::default()
+        });
+        let pipeline = create_depth_pipeline(device, &layout, &shader_module, None);
+        let mask_pipeline =
+            create_depth_pipeline(device, &mask_layout, &shader_module, Some("fragment_mask"));
+
+        Self {
+            resolution,
+            layers,
+            view,
+            layer_views,
+            sampler,
+            moments_view,
+            variance_blur,
+            cascades_buffer,
+            cascade_uniforms,
+            pipeline,
+            mask_pipeline,
+            cascades: Vec::new(),
+            blur_radii: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn matches(&self, resolution: u32, layers: u32, variance: bool) -> bool {
+        self.resolution == resolution
+            && self.layers == layers.max(1)
+            && self.variance_blur.is_some() == variance
+    }
+
+    /// Fits the cascades of every light to `camera` and uploads them.
+    pub fn update(&mut self, queue: &Queue, camera: &Camera, lights: &[DirectionalLight]) {
+        self.cascades.clear();
+        self.blur_radii.clear();
+        for light in lights {
+            let blur_radius = match light.shadow_filter {
+                ShadowFilter::Variance { blur_radius, .. } => Some(blur_radius),
+                _ => None,
+            };
+            for cascade in compute_cascades(camera, light, self.resolution) {
+                self.cascades.push(cascade);
+                self.blur_radii.push(blur_radius);
+            }
+        }
+        self.cascades.truncate(self.layers as usize);
+        self.blur_radii.truncate(self.layers as usize);
+
+        let raw = self
+            .cascades
+            .iter()
+            .flat_map(ShadowCascade::as_raw)
+            .collect::<Vec<_>>();
+        queue.write_buffer(&self.cascades_buffer, 0, &raw);
+        for ((cascade, blur_radius), (buffer, _)) in self
+            .cascades
+            .iter()
+            .zip(&self.blur_radii)
+            .zip(&self.cascade_uniforms)
+        {
+            let uniform = CascadeUniform {
+                view_proj: cascade.view_proj,
+                blur_radius: blur_radius.unwrap_or(0),
+            };
+            queue.write_buffer(buffer, 0, &uniform.as_raw());
+        }
+    }
+
+    /// Renders every mesh into every cascade, then blurs the moments of the variance
+    /// filtered ones.
+    pub fn encode(
+        &self,
+        command_encoder: &mut CommandEncoder,
+        assets: &AssetRegistry,
+        mesh_groups: &[(&BindGroup, &[MeshHandle])],
+        materials: &Materials,
+        stats: &mut FrameStats,
+        mut gpu_timer: Option<&mut GpuTimer>,
+    ) {
+        for (index, (_, bind_group)) in self.cascade_uniforms[..self.cascades.len()]
+            .iter()
+            .enumerate()
+        {
+            {
+                let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+                    label: None,
+                    color_attachments: &[],
+                    depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                        view: &self.layer_views[index],
+                        depth_ops: Some(Operations {
+                            load: LoadOp::Clear(1.),
+                            store: StoreOp::Store,
+                        }),
+                        stencil_ops: None,
+                    }),
+                    timestamp_writes: gpu_timer
+                        .as_mut()
+                        .and_then(|timer| timer.pass_writes("shadows")),
+                    ..Default::default()
+                });
+
+                pass.set_bind_group(0, bind_group, &[]);
+                // Masked meshes last, so the pipeline only changes once.
+                for masked in [false, true] {
+                    let pipeline = match masked {
+                        true => &self.mask_pipeline,
+                        false => &self.pipeline,
+                    };
+                    pass.set_pipeline(pipeline);
+                    for (mesh_bind_group, meshes) in mesh_groups {
+                        pass.set_bind_group(1, mesh_bind_group, &[]);
+                        for &handle in meshes.iter() {
+                            let Some(mesh) = assets.mesh(handle) else {
+                                continue;
+                            };
+                            if matches!(mesh.material.alpha_mode, AlphaMode::Mask { .. }) != masked
+                            {
+                                continue;
+                            }
+                            if masked {
+                                materials.bind(&mut pass, 2, handle, mesh);
+                            }
+                            pass.set_vertex_buffer(0, mesh.vertex_buf.slice(..));
+                            pass.draw(0..mesh.vertex_count, 0..1);
+                            stats.draw_calls += 1;
+                            stats.triangles += mesh.vertex_count / 3;
+                        }
+                    }
+                }
+            }
+
+            if let (Some(blur), Some(_)) = (&self.variance_blur, self.blur_radii[index]) {
+                blur.encode(command_encoder, index, bind_group, &mut gpu_timer);
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+struct VertexInput {
+    @location(0) position: vec3f,
+    @location(1) normal: vec3f,
+    @location(2) joints: vec4u,
+    @location(3) weights: vec4f,
+    @location(4) uv: vec2f,
+}
+
+struct VertexOutput {
+    @builtin(position) position_cs: vec4f,
+    @location(0) uv: vec2f,
+}
+
+struct CascadeUniform {
+    view_proj: mat4x4f,
+    blur_radius: u32,
+}
+
+@group(0) @binding(0) var<uniform> cascade: CascadeUniform;
+
+@vertex
+fn vertex(input: VertexInput) -> VertexOutput {
+    let skin = skin_matrix(input.joints, input.weights);
+    var output: VertexOutput;
+    output.position_cs = cascade.view_proj * skin * vec4f(input.position, 1.);
+    output.uv = input.uv;
+    return output;
+}
+
+// Only used for masked meshes, the others are drawn without a fragment shader.
+@fragment
+fn fragment_mask(input: VertexOutput) {
+    alpha_mask(input.uv);
+}
@@ -0,0 +1,45 @@
+struct CascadeUniform {
+    view_proj: mat4x4f,
+    blur_radius: u32,
+}
+
+@group(0) @binding(0) var<uniform> cascade: CascadeUniform;
+
+// The horizontal pass reads the depth of the cascade, the vertical pass the moments
+// written by the horizontal one.
+@group(1) @binding(0) var depth_map: texture_depth_2d;
+@group(1) @binding(1) var moments_map: texture_2d<f32>;
+
+// A triangle covering the whole target.
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> @builtin(position) vec4f {
+    let uv = vec2f(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    return vec4f(uv * 2. - 1., 0., 1.);
+}
+
+@fragment
+fn fragment_horizontal(@builtin(position) position: vec4f) -> @location(0) vec2f {
+    let size = vec2i(textureDimensions(depth_map));
+    let radius = i32(cascade.blur_radius);
+
+    var moments = vec2f(0.);
+    for (var x = -radius; x <= radius; x += 1) {
+        let coords = clamp(vec2i(position.xy) + vec2i(x, 0), vec2i(0), size - 1);
+        let depth = textureLoad(depth_map, coords, 0);
+        moments += vec2f(depth, depth * depth);
+    }
+    return moments / f32(2 * radius + 1);
+}
+
+@fragment
+fn fragment_vertical(@builtin(position) position: vec4f) -> @location(0) vec2f {
+    let size = vec2i(textureDimensions(moments_map));
+    let radius = i32(cascade.blur_radius);
+
+    var moments = vec2f(0.);
+    for (var y = -radius; y <= radius; y += 1) {
+        let coords = clamp(vec2i(position.xy) + vec2i(0, y), vec2i(0), size - 1);
+        moments += textureLoad(moments_map, coords, 0).xy;
+    }
+    return moments / f32(2 * radius + 1);
+}
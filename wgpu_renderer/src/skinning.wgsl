@@ -0,0 +1,19 @@
+// Prepended to the shaders of meshes, which bind the joints of skinned meshes as
+// group 1.
+@group(1) @binding(0) var<storage> joint_matrices: array<mat4x4f>;
+
+fn skin_matrix(joints: vec4u, weights: vec4f) -> mat4x4f {
+    if all(weights == vec4f(0.)) {
+        return mat4x4f(
+            vec4f(1., 0., 0., 0.),
+            vec4f(0., 1., 0., 0.),
+            vec4f(0., 0., 1., 0.),
+            vec4f(0., 0., 0., 1.),
+        );
+    }
+
+    return joint_matrices[joints.x] * weights.x
+        + joint_matrices[joints.y] * weights.y
+        + joint_matrices[joints.z] * weights.z
+        + joint_matrices[joints.w] * weights.w;
+}
@@ -0,0 +1,103 @@
+// Draws the environment cubemap or the procedural sky behind everything, sharing the
+// bindings of the scene shader.
+
+struct Camera {
+    view: mat4x4f,
+    proj: mat4x4f,
+    inv_view_proj: mat4x4f,
+}
+
+struct Environment {
+    intensity: f32,
+    max_level: f32,
+    enabled: u32,
+}
+
+struct Atmosphere {
+    // Perez coefficients A to E of the luminance and both chromaticities.
+    perez: array<vec4f, 5>,
+    // Luminance and chromaticity of the zenith.
+    zenith: vec3f,
+    sky_enabled: u32,
+    to_sun: vec3f,
+    sky_intensity: f32,
+    fog_color: vec3f,
+    fog_density: f32,
+    fog_height_falloff: f32,
+    fog_base_height: f32,
+    fog_enabled: u32,
+}
+
+struct VertexOutput {
+    @builtin(position) position_cs: vec4f,
+    @location(0) ndc: vec2f,
+}
+
+@group(0) @binding(0) var<uniform> camera: Camera;
+@group(0) @binding(9) var<uniform> environment: Environment;
+@group(0) @binding(13) var environment_sampler: sampler;
+@group(0) @binding(14) var skybox: texture_cube<f32>;
+@group(0) @binding(15) var<uniform> atmosphere: Atmosphere;
+
+// A triangle covering the whole target on the far plane.
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let uv = vec2f(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+
+    var output: VertexOutput;
+    output.ndc = uv * 2. - 1.;
+    output.position_cs = vec4f(output.ndc, 1., 1.);
+    return output;
+}
+
+fn view_direction(ndc: vec2f) -> vec3f {
+    let near = camera.inv_view_proj * vec4f(ndc, 0., 1.);
+    let far = camera.inv_view_proj * vec4f(ndc, 1., 1.);
+    return normalize(far.xyz / far.w - near.xyz / near.w);
+}
+
+// The Perez distribution, relative to the zenith, for a view at `theta` from the zenith
+// and `gamma` from the sun.
+fn perez(theta: f32, gamma: f32) -> vec3f {
+    let a = atmosphere.perez[0].xyz;
+    let b = atmosphere.perez[1].xyz;
+    let c = atmosphere.perez[2].xyz;
+    let d = atmosphere.perez[3].xyz;
+    let e = atmosphere.perez[4].xyz;
+    let cos_gamma = cos(gamma);
+    return (1. + a * exp(b / max(cos(theta), 0.01))) * (1. + c * exp(d * gamma) + e * cos_gamma * cos_gamma);
+}
+
+// Linear RGB of the Preetham sky, the ground reflecting the horizon.
+fn sky_radiance(direction: vec3f) -> vec3f {
+    let up = normalize(vec3f(direction.x, max(direction.y, 0.001), direction.z));
+    let theta = acos(up.y);
+    let gamma = acos(clamp(dot(up, atmosphere.to_sun), -1., 1.));
+    let sun_theta = acos(atmosphere.to_sun.y);
+    let yxy = atmosphere.zenith * perez(theta, gamma) / perez(0., sun_theta);
+
+    let luminance = yxy.x * atmosphere.sky_intensity;
+    let cie = vec3f(
+        yxy.y * luminance / yxy.z,
+        luminance,
+        (1. - yxy.y - yxy.z) * luminance / yxy.z,
+    );
+    let to_rgb = mat3x3f(
+        vec3f(3.2406, -0.9689, 0.0557),
+        vec3f(-1.5372, 1.8758, -0.2040),
+        vec3f(-0.4986, 0.0415, 1.0570),
+    );
+    return max(to_rgb * cie, vec3f(0.));
+}
+
+@fragment
+fn fragment_sky(input: VertexOutput) -> @location(0) vec4f {
+    return vec4f(sky_radiance(view_direction(input.ndc)), 1.);
+}
+
+@fragment
+fn fragment(input: VertexOutput) -> @location(0) vec4f {
+    let direction = view_direction(input.ndc);
+    let radiance = textureSampleLevel(skybox, environment_sampler, direction, 0.).rgb;
+    return vec4f(radiance * environment.intensity, 1.);
+}
@@ -0,0 +1,529 @@
+use std::borrow::Cow;
+
+use glam::{Mat4, UVec2, Vec3, Vec4};
+use wgpu::{util::*, *};
+
+use crate::{
+    assets::{AssetRegistry, MeshHandle},
+    material::{with_alpha_mask, Materials},
+    render::{AlphaMode, ShaderData},
+    stats::{FrameStats, GpuTimer},
+    vertex_buffer_layout,
+};
+
+const NORMAL_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+const DEPTH_FORMAT: TextureFormat = TextureFormat::Depth32Float;
+const OCCLUSION_FORMAT: TextureFormat = TextureFormat::R8Unorm;
+/// Size of the kernel in the shader.
+pub const MAX_SSAO_SAMPLES: u32 = 64;
+const NOISE_SIZE: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SsaoConfig {
+    /// View space radius of the sampled hemisphere.
+    pub radius: f32,
+    /// Exponent applied to the visibility, darkening the occlusion.
+    pub intensity: f32,
+    /// Up to [`MAX_SSAO_SAMPLES`].
+    pub samples: u32,
+    /// View space depth difference below which samples don't occlude, against acne on
+    /// flat surfaces.
+    pub bias: f32,
+}
+
+impl Default for SsaoConfig {
+    fn default() -> Self {
+        Self {
+            radius: 0.5,
+            intensity: 1.5,
+            samples: 32,
+            bias: 0.025,
+        }
+    }
+}
+
+// Deterministic, so the noise pattern doesn't change between runs.
+fn hash(mut x: u32) -> f32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x as f32 / u32::MAX as f32
+}
+
+/// Points in the hemisphere around +Z, denser towards the center as close occluders
+/// matter most.
+fn create_kernel(samples: u32) -> Vec<Vec4> {
+    (0..samples)
+        .map(|i| {
+            let direction = Vec3::new(
+                hash(i * 3) * 2. - 1.,
+                hash(i * 3 + 1) * 2. - 1.,
+                hash(i * 3 + 2),
+            )
+            .normalize_or_zero();
+            let t = i as f32 / samples as f32;
+            let scale = 0.1 + 0.9 * t * t;
+            (direction * hash(i + 0x1000) * scale).extend(0.)
+        })
+        .collect()
+}
+
+struct SsaoUniform<'a> {
+    proj: Mat4,
+    config: &'a SsaoConfig,
+    kernel: &'a [Vec4],
+}
+
+impl ShaderData for SsaoUniform<'_> {
+    fn as_raw(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(144 + 16 * MAX_SSAO_SAMPLES as usize);
+        buf.extend_from_slice(bytemuck::cast_slice(self.proj.as_ref()));
+        buf.extend_from_slice(bytemuck::cast_slice(self.proj.inverse().as_ref()));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.config.radius));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.config.intensity));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.config.bias));
+        buf.extend_from_slice(bytemuck::bytes_of(&(self.kernel.len() as u32)));
+        buf.extend_from_slice(bytemuck::cast_slice(self.kernel));
+        buf.resize(buf.capacity(), 0);
+        buf
+    }
+}
+
+/// Single channel texture bound in place of the occlusion while SSAO is disabled.
+pub(crate) fn create_unoccluded_view(device: &Device, queue: &Queue) -> TextureView {
+    device
+        .create_texture_with_data(
+            queue,
+            &TextureDescriptor {
+                label: None,
+                size: Extent3d::default(),
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: OCCLUSION_FORMAT,
+                usage: TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            TextureDataOrder::LayerMajor,
+            &[u8::MAX],
+        )
+        .create_view(&TextureViewDescriptor::default())
+}
+
+fn create_target(device: &Device, dim: UVec2, format: TextureFormat) -> TextureView {
+    device
+        .create_texture(&TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: dim.x,
+                height: dim.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        })
+        .create_view(&TextureViewDescriptor::default())
+}
+
+fn create_fullscreen_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    entry_point: &str,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: PipelineCompilationOptions::default(),
+            buffers: &[],
+        },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point,
+            compilation_options: PipelineCompilationOptions::default(),
+            targets: &[Some(OCCLUSION_FORMAT.into())],
+        }),
+        primitive: PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+/// Renders the normals and depth of meshes, discarding masked fragments with
+/// `fragment_entry_point` "fragment_mask".
+fn create_prepass_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    fragment_entry_point: &str,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: PipelineCompilationOptions::default(),
+            buffers: &[vertex_buffer_layout()],
+        },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: fragment_entry_point,
+            compilation_options: PipelineCompilationOptions::default(),
+            targets: &[Some(NORMAL_FORMAT.into())],
+        }),
+        primitive: PrimitiveState::default(),
+        depth_stencil: Some(DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: CompareFunction::LessEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+/// Screen sized textures, recreated when the target is resized.
+struct SsaoTargets {
+    normal_view: TextureView,
+    depth_view: TextureView,
+    raw_occlusion_view: TextureView,
+    occlusion_view: TextureView,
+    occlusion_bind_group: BindGroup,
+    blur_bind_group: BindGroup,
+}
+
+/// Renders a normal and depth prepass, computes the ambient occlusion from it and blurs
+/// the result, which the scene shader samples at binding 6.
+pub(crate) struct Ssao {
+    config: SsaoConfig,
+    kernel: Vec<Vec4>,
+    uniform: Buffer,
+    noise_view: TextureView,
+    layout: BindGroupLayout,
+    prepass_pipeline: RenderPipeline,
+    // For meshes with `AlphaMode::Mask`, discarding the fragments the scene shader does.
+    prepass_mask_pipeline: RenderPipeline,
+    occlusion_pipeline: RenderPipeline,
+    blur_pipeline: RenderPipeline,
+    targets: SsaoTargets,
+}
+
+impl Ssao {
+    pub fn new(
+        device: &Device,
+        queue: &Queue,
+        scene_layout: &BindGroupLayout,
+        mesh_layout: &BindGroupLayout,
+        material_layout: &BindGroupLayout,
+        dim: UVec2,
+        config: SsaoConfig,
+    ) -> Self {
+        let uniform = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: 144 + 16 * MAX_SSAO_SAMPLES as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // Random rotations around the normal, tiled over the screen.
+        let noise = (0..NOISE_SIZE * NOISE_SIZE)
+            .flat_map(|i| {
+                let angle = hash(i + 0x2000) * std::f32::consts::TAU;
+                [angle.cos(), angle.sin(), 0., 0.].map(|v| (v * i8::MAX as f32) as i8 as u8)
+            })
+            .collect::<Vec<_>>();
+        let noise_view = device
+            .create_texture_with_data(
+                queue,
+                &TextureDescriptor {
+                    label: None,
+                    size: Extent3d {
+                        width: NOISE_SIZE,
+                        height: NOISE_SIZE,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: TextureFormat::Rgba8Snorm,
+                    usage: TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                },
+                TextureDataOrder::LayerMajor,
+                &noise,
+            )
+            .create_view(&TextureViewDescriptor::default());
+
+        let texture_entry = |binding, sample_type| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type,
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture_entry(1, TextureSampleType::Depth),
+                texture_entry(2, TextureSampleType::Float { filterable: false }),
+                texture_entry(3, TextureSampleType::Float { filterable: false }),
+            ],
+        });
+
+        let prepass_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: with_alpha_mask(include_str!("ssao_prepass.wgsl")),
+        });
+        let prepass_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[scene_layout, mesh_layout],
+            ..Default::default()
+        });
+        // Only the masked meshes bind their material.
+        let prepass_mask_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[scene_layout, mesh_layout, material_layout],
+            ..Default::default()
+        });
+        let prepass_pipeline =
+            create_prepass_pipeline(device, &prepass_layout, &prepass_module, "fragment");
+        let prepass_mask_pipeline = create_prepass_pipeline(
+            device,
+            &prepass_mask_layout,
+            &prepass_module,
+            "fragment_mask",
+        );
+
+        let shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("ssao.wgsl"))),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            ..Default::default()
+        });
+        let occlusion_pipeline = create_fullscreen_pipeline(
+            device,
+            &pipeline_layout,
+            &shader_module,
+            "fragment_occlusion",
+        );
+        let blur_pipeline =
+            create_fullscreen_pipeline(device, &pipeline_layout, &shader_module, "fragment_blur");
+
+        let targets = Self::create_targets(device, &layout, &uniform, &noise_view, dim);
+
+        Self {
+            kernel: create_kernel(config.samples.clamp(1, MAX_SSAO_SAMPLES)),
+            config,
+            uniform,
+            noise_view,
+            layout,
+            prepass_pipeline,
+            prepass_mask_pipeline,
+            occlusion_pipeline,
+            blur_pipeline,
+            targets,
+        }
+    }
+
+    fn create_targets(
+        device: &Device,
+        layout: &BindGroupLayout,
+        uniform: &Buffer,
+        noise_view: &TextureView,
+        dim: UVec2,
+    ) -> SsaoTargets {
+        let dim = dim.max(UVec2::ONE);
+        let normal_view = create_target(device, dim, NORMAL_FORMAT);
+        let depth_view = create_target(device, dim, DEPTH_FORMAT);
+        let raw_occlusion_view = create_target(device, dim, OCCLUSION_FORMAT);
+        let occlusion_view = create_target(device, dim, OCCLUSION_FORMAT);
+
+        let create_bind_group = |input: &TextureView| {
+            device.create_bind_group(&BindGroupDescriptor {
+                label: None,
+                layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: uniform.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::TextureView(&depth_view),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: BindingResource::TextureView(input),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: BindingResource::TextureView(noise_view),
+                    },
+                ],
+            })
+        };
+        let occlusion_bind_group = create_bind_group(&normal_view);
+        let blur_bind_group = create_bind_group(&raw_occlusion_view);
+
+        SsaoTargets {
+            normal_view,
+            depth_view,
+            raw_occlusion_view,
+            occlusion_view,
+            occlusion_bind_group,
+            blur_bind_group,
+        }
+    }
+
+    pub fn resize(&mut self, device: &Device, dim: UVec2) {
+        self.targets =
+            Self::create_targets(device, &self.layout, &self.uniform, &self.noise_view, dim);
+    }
+
+    #[inline]
+    pub fn occlusion_view(&self) -> &TextureView {
+        &self.targets.occlusion_view
+    }
+
+    pub fn set_config(&mut self, config: SsaoConfig) {
+        self.kernel = create_kernel(config.samples.clamp(1, MAX_SSAO_SAMPLES));
+        self.config = config;
+    }
+
+    pub fn update(&self, queue: &Queue, proj: Mat4) {
+        let uniform = SsaoUniform {
+            proj,
+            config: &self.config,
+            kernel: &self.kernel,
+        };
+        queue.write_buffer(&self.uniform, 0, &uniform.as_raw());
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn encode(
+        &self,
+        command_encoder: &mut CommandEncoder,
+        assets: &AssetRegistry,
+        scene_bind_group: &BindGroup,
+        mesh_groups: &[(&BindGroup, &[MeshHandle])],
+        materials: &Materials,
+        stats: &mut FrameStats,
+        mut gpu_timer: Option<&mut GpuTimer>,
+    ) {
+        {
+            let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &self.targets.normal_view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Color::TRANSPARENT),
+                        store: StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                    view: &self.targets.depth_view,
+                    depth_ops: Some(Operations {
+                        load: LoadOp::Clear(1.),
+                        store: StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: gpu_timer
+                    .as_mut()
+                    .and_then(|timer| timer.pass_writes("ssao prepass")),
+                ..Default::default()
+            });
+
+            pass.set_bind_group(0, scene_bind_group, &[]);
+            // Masked meshes last, so the pipeline only changes once.
+            for masked in [false, true] {
+                let pipeline = match masked {
+                    true => &self.prepass_mask_pipeline,
+                    false => &self.prepass_pipeline,
+                };
+                pass.set_pipeline(pipeline);
+                for (mesh_bind_group, meshes) in mesh_groups {
+                    pass.set_bind_group(1, mesh_bind_group, &[]);
+                    for &handle in meshes.iter() {
+                        let Some(mesh) = assets.mesh(handle) else {
+                            continue;
+                        };
+                        if matches!(mesh.material.alpha_mode, AlphaMode::Mask { .. }) != masked {
+                            continue;
+                        }
+                        if masked {
+                            materials.bind(&mut pass, 2, handle, mesh);
+                        }
+                        pass.set_vertex_buffer(0, mesh.vertex_buf.slice(..));
+                        pass.draw(0..mesh.vertex_count, 0..1);
+                        stats.draw_calls += 1;
+                        stats.triangles += mesh.vertex_count / 3;
+                    }
+                }
+            }
+        }
+
+        let passes = [
+            (
+                "ssao",
+                &self.targets.raw_occlusion_view,
+                &self.occlusion_pipeline,
+                &self.targets.occlusion_bind_group,
+            ),
+            (
+                "ssao blur",
+                &self.targets.occlusion_view,
+                &self.blur_pipeline,
+                &self.targets.blur_bind_group,
+            ),
+        ];
+        for (name, target, pipeline, bind_group) in passes {
+            let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Load,
+                        store: StoreOp::Store,
+                    },
+                })],
+                timestamp_writes: gpu_timer.as_mut().and_then(|timer| timer.pass_writes(name)),
+                ..Default::default()
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+// Hemisphere sampled ambient occlusion from the prepass depth and normals, followed by a
+// depth aware blur over the size of the noise texture.
+
+struct Ssao {
+    proj: mat4x4f,
+    inv_proj: mat4x4f,
+    radius: f32,
+    intensity: f32,
+    bias: f32,
+    sample_count: u32,
+    kernel: array<vec4f, 64>,
+}
+
+@group(0) @binding(0) var<uniform> ssao: Ssao;
+@group(0) @binding(1) var depth_map: texture_depth_2d;
+// Normals for the occlusion pass, the raw occlusion for the blur.
+@group(0) @binding(2) var input_map: texture_2d<f32>;
+@group(0) @binding(3) var noise_map: texture_2d<f32>;
+
+const NOISE_SIZE: i32 = 4;
+// How quickly the blur stops mixing in neighbours at different view depths.
+const BLUR_DEPTH_FALLOFF: f32 = 4.;
+
+// A triangle covering the whole target.
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> @builtin(position) vec4f {
+    let uv = vec2f(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    return vec4f(uv * 2. - 1., 0., 1.);
+}
+
+fn load_depth(texel: vec2i) -> f32 {
+    let size = vec2i(textureDimensions(depth_map));
+    return textureLoad(depth_map, clamp(texel, vec2i(0), size - 1), 0);
+}
+
+fn view_position(texel: vec2i) -> vec3f {
+    let size = vec2f(textureDimensions(depth_map));
+    let uv = (vec2f(texel) + 0.5) / size;
+    let ndc = vec3f(uv.x * 2. - 1., 1. - uv.y * 2., load_depth(texel));
+    let position = ssao.inv_proj * vec4f(ndc, 1.);
+    return position.xyz / position.w;
+}
+
+@fragment
+fn fragment_occlusion(@builtin(position) position_fs: vec4f) -> @location(0) vec4f {
+    let texel = vec2i(position_fs.xy);
+    if load_depth(texel) >= 1. {
+        return vec4f(1.);
+    }
+
+    let position = view_position(texel);
+    let normal = textureLoad(input_map, texel, 0).xyz;
+    let random = vec3f(textureLoad(noise_map, texel % NOISE_SIZE, 0).xy, 0.);
+    let tangent = normalize(random - normal * dot(random, normal));
+    let tbn = mat3x3f(tangent, cross(normal, tangent), normal);
+
+    let size = vec2f(textureDimensions(depth_map));
+    var occlusion = 0.;
+    for (var i = 0u; i < ssao.sample_count; i += 1u) {
+        let sample_position = position + tbn * ssao.kernel[i].xyz * ssao.radius;
+        let sample_cs = ssao.proj * vec4f(sample_position, 1.);
+        let sample_uv = sample_cs.xy / sample_cs.w * vec2f(0.5, -0.5) + 0.5;
+        let sample_depth = view_position(vec2i(sample_uv * size)).z;
+
+        // Fades out occluders far outside the radius, like the background behind edges.
+        let range = smoothstep(0., 1., ssao.radius / abs(position.z - sample_depth));
+        occlusion += select(0., range, sample_depth >= sample_position.z + ssao.bias);
+    }
+
+    let visibility = 1. - occlusion / f32(ssao.sample_count);
+    return vec4f(pow(visibility, ssao.intensity));
+}
+
+@fragment
+fn fragment_blur(@builtin(position) position_fs: vec4f) -> @location(0) vec4f {
+    let texel = vec2i(position_fs.xy);
+    let size = vec2i(textureDimensions(input_map));
+    let depth = view_position(texel).z;
+
+    var occlusion = 0.;
+    var weights = 0.;
+    for (var y = -NOISE_SIZE / 2; y < NOISE_SIZE / 2; y += 1) {
+        for (var x = -NOISE_SIZE / 2; x < NOISE_SIZE / 2; x += 1) {
+            let neighbour = clamp(texel + vec2i(x, y), vec2i(0), size - 1);
+            let difference = abs(view_position(neighbour).z - depth);
+            let weight = exp(-difference * BLUR_DEPTH_FALLOFF);
+            occlusion += textureLoad(input_map, neighbour, 0).r * weight;
+            weights += weight;
+        }
+    }
+    return vec4f(occlusion / weights);
+}
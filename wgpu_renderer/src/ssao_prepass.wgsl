@@ -0,0 +1,46 @@
+// Writes view space normals and depth for the ambient occlusion pass, sharing the
+// bindings and vertex layout of the scene shader.
+
+struct Camera {
+    view: mat4x4f,
+    proj: mat4x4f,
+}
+
+struct VertexInput {
+    @location(0) position: vec3f,
+    @location(1) normal: vec3f,
+    @location(2) joints: vec4u,
+    @location(3) weights: vec4f,
+    @location(4) uv: vec2f,
+}
+
+struct VertexOutput {
+    @builtin(position) position_cs: vec4f,
+    @location(0) normal_vs: vec3f,
+    @location(1) uv: vec2f,
+}
+
+@group(0) @binding(0) var<uniform> camera: Camera;
+
+@vertex
+fn vertex(input: VertexInput) -> VertexOutput {
+    let skin = skin_matrix(input.joints, input.weights);
+    let position_ws = skin * vec4f(input.position, 1.);
+
+    var output: VertexOutput;
+    output.position_cs = camera.proj * camera.view * position_ws;
+    output.normal_vs = (camera.view * skin * vec4f(input.normal, 0.)).xyz;
+    output.uv = input.uv;
+    return output;
+}
+
+@fragment
+fn fragment(input: VertexOutput) -> @location(0) vec4f {
+    return vec4f(normalize(input.normal_vs), 0.);
+}
+
+@fragment
+fn fragment_mask(input: VertexOutput) -> @location(0) vec4f {
+    alpha_mask(input.uv);
+    return vec4f(normalize(input.normal_vs), 0.);
+}
@@ -0,0 +1,237 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use wgpu::*;
+
+const MAX_TIMED_PASSES: u32 = 32;
+
+#[derive(Debug, Clone)]
+pub struct PassTime {
+    pub name: &'static str,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FrameStats {
+    /// Time since the previous frame, only measured by [`crate::WgpuSurfaceRenderer`].
+    pub frame_time: Duration,
+    /// Time spent on the CPU recording and submitting this frame.
+    pub cpu_time: Duration,
+    /// Lags a few frames behind as timestamps are read back asynchronously. Empty if
+    /// `Features::TIMESTAMP_QUERY` is not supported.
+    pub gpu_pass_times: Vec<PassTime>,
+    pub draw_calls: u32,
+    pub triangles: u32,
+    pub buffer_memory: u64,
+}
+
+impl FrameStats {
+    pub fn gpu_time(&self) -> Duration {
+        self.gpu_pass_times.iter().map(|p| p.duration).sum()
+    }
+}
+
+pub struct GpuTimer {
+    query_set: QuerySet,
+    resolve_buffer: Buffer,
+    readback_buffer: Buffer,
+    period: f32,
+    passes: Vec<&'static str>,
+    in_flight: Vec<&'static str>,
+    mapped: Arc<AtomicBool>,
+    failed: Arc<AtomicBool>,
+}
+
+impl GpuTimer {
+    pub fn new(device: &Device, queue: &Queue) -> Option<Self> {
+        if !device.features().contains(Features::TIMESTAMP_QUERY) {
+            return None;
+        }
+
+        let size = (MAX_TIMED_PASSES * 2) as u64 * QUERY_SIZE as u64;
+
+        Some(Self {
+            query_set: device.create_query_set(&QuerySetDescriptor {
+                label: None,
+                ty: QueryType::Timestamp,
+                count: MAX_TIMED_PASSES * 2,
+            }),
+            resolve_buffer: device.create_buffer(&BufferDescriptor {
+                label: None,
+                size,
+                usage: BufferUsages::QUERY_RESOLVE | BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            readback_buffer: device.create_buffer(&BufferDescriptor {
+                label: None,
+                size,
+                usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            }),
+            period: queue.get_timestamp_period(),
+            passes: Vec::new(),
+            in_flight: Vec::new(),
+            mapped: Default::default(),
+            failed: Default::default(),
+        })
+    }
+
+    #[inline]
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    pub fn pass_writes(&mut self, name: &'static str) -> Option<RenderPassTimestampWrites<'_>> {
+        let index = self.passes.len() as u32;
+        if !self.is_idle() || index >= MAX_TIMED_PASSES {
+            return None;
+        }
+
+        self.passes.push(name);
+        Some(RenderPassTimestampWrites {
+            query_set: &self.query_set,
+            beginning_of_pass_write_index: Some(index * 2),
+            end_of_pass_write_index: Some(index * 2 + 1),
+        })
+    }
+
+    pub fn compute_pass_writes(
+        &mut self,
+        name: &'static str,
+    ) -> Option<ComputePassTimestampWrites<'_>> {
+        let index = self.passes.len() as u32;
+        if !self.is_idle() || index >= MAX_TIMED_PASSES {
+            return None;
+        }
+
+        self.passes.push(name);
+        Some(ComputePassTimestampWrites {
+            query_set: &self.query_set,
+            beginning_of_pass_write_index: Some(index * 2),
+            end_of_pass_write_index: Some(index * 2 + 1),
+        })
+    }
+
+    pub fn resolve(&self, command_encoder: &mut CommandEncoder) {
+        if self.passes.is_empty() {
+            return;
+        }
+
+        let count = self.passes.len() as u32 * 2;
+        command_encoder.resolve_query_set(&self.query_set, 0..count, &self.resolve_buffer, 0);
+        command_encoder.copy_buffer_to_buffer(
+            &self.resolve_buffer,
+            0,
+            &self.readback_buffer,
+            0,
+            count as u64 * QUERY_SIZE as u64,
+        );
+    }
+
+    /// Must be called after the command buffer containing [`GpuTimer::resolve`] is submitted.
+    pub fn map(&mut self) {
+        if self.passes.is_empty() {
+            return;
+        }
+
+        self.in_flight = std::mem::take(&mut self.passes);
+        let mapped = self.mapped.clone();
+        let failed = self.failed.clone();
+        self.readback_buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |r| match r {
+                Ok(()) => mapped.store(true, Ordering::Release),
+                Err(err) => {
+                    log::error!("Failed to map pass timestamps: {}", err);
+                    failed.store(true, Ordering::Release);
+                }
+            });
+    }
+
+    /// Returns the pass durations of the last mapped frame once they are available.
+    pub fn collect(&mut self, device: &Device) -> Option<Vec<PassTime>> {
+        if self.is_idle() {
+            return None;
+        }
+
+        device.poll(Maintain::Poll);
+        // Drop the frame so that the following ones can be timed again.
+        if self.failed.swap(false, Ordering::Acquire) {
+            self.in_flight.clear();
+            return None;
+        }
+        if !self.mapped.swap(false, Ordering::Acquire) {
+            return None;
+        }
+
+        let times = {
+            let view = self.readback_buffer.slice(..).get_mapped_range();
+            let timestamps: &[u64] = bytemuck::cast_slice(&view[..self.in_flight.len() * 16]);
+            self.in_flight
+                .iter()
+                .enumerate()
+                .map(|(i, &name)| {
+                    let ticks = timestamps[i * 2 + 1].saturating_sub(timestamps[i * 2]);
+                    PassTime {
+                        name,
+                        duration: Duration::from_nanos((ticks as f64 * self.period as f64) as u64),
+                    }
+                })
+                .collect()
+        };
+        self.readback_buffer.unmap();
+        self.in_flight.clear();
+
+        Some(times)
+    }
+}
+
+pub struct StatsRecorder {
+    writer: BufWriter<File>,
+    frame: u64,
+}
+
+impl StatsRecorder {
+    pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(
+            writer,
+            "frame,frame_time_ms,cpu_time_ms,gpu_time_ms,draw_calls,triangles,buffer_memory,gpu_passes"
+        )?;
+
+        Ok(Self { writer, frame: 0 })
+    }
+
+    pub fn record(&mut self, stats: &FrameStats) {
+        let passes = stats
+            .gpu_pass_times
+            .iter()
+            .map(|p| format!("{}:{:.4}", p.name, p.duration.as_secs_f64() * 1000.))
+            .collect::<Vec<_>>()
+            .join("|");
+
+        if let Err(err) = writeln!(
+            self.writer,
+            "{},{:.4},{:.4},{:.4},{},{},{},{}",
+            self.frame,
+            stats.frame_time.as_secs_f64() * 1000.,
+            stats.cpu_time.as_secs_f64() * 1000.,
+            stats.gpu_time().as_secs_f64() * 1000.,
+            stats.draw_calls,
+            stats.triangles,
+            stats.buffer_memory,
+            passes,
+        ) {
+            log::error!("Failed to record frame stats: {}", err);
+        }
+        self.frame += 1;
+    }
+}
@@ -0,0 +1,374 @@
+use std::borrow::Cow;
+
+use glam::{Mat4, UVec2, Vec2};
+use wgpu::*;
+
+use crate::{bloom::HDR_FORMAT, render::ShaderData, stats::GpuTimer};
+
+/// Format of the motion vectors written by the scene pass: the offset to the previous
+/// frame in UV units, with alpha set where a surface was drawn.
+pub const MOTION_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+/// Length of the Halton sequence the jitter cycles through.
+const JITTER_SAMPLES: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TaaConfig {
+    /// Weight of the current frame against the history, lower values are smoother but
+    /// ghost longer.
+    pub blend_factor: f32,
+    /// Camera movement within a frame beyond which the history is discarded.
+    pub cut_distance: f32,
+    /// Camera rotation within a frame beyond which the history is discarded, in radians.
+    pub cut_angle: f32,
+}
+
+impl Default for TaaConfig {
+    fn default() -> Self {
+        Self {
+            blend_factor: 0.1,
+            cut_distance: 2.,
+            cut_angle: 0.5,
+        }
+    }
+}
+
+/// Element `index` of the Halton sequence in `base`, in [0, 1).
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut fraction = 1.;
+    let mut result = 0.;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+/// The motion vectors as a target of the pipelines in the scene pass, which all have
+/// to declare it while only those drawing surfaces `write` it.
+pub(crate) fn motion_target(write: bool) -> ColorTargetState {
+    ColorTargetState {
+        format: MOTION_FORMAT,
+        blend: None,
+        write_mask: match write {
+            true => ColorWrites::ALL,
+            false => ColorWrites::empty(),
+        },
+    }
+}
+
+struct TaaUniform<'a> {
+    reprojection: Mat4,
+    config: &'a TaaConfig,
+    reset: bool,
+}
+
+impl ShaderData for TaaUniform<'_> {
+    fn as_raw(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(80);
+        buf.extend_from_slice(bytemuck::cast_slice(self.reprojection.as_ref()));
+        buf.extend_from_slice(bytemuck::bytes_of(&self.config.blend_factor));
+        buf.extend_from_slice(bytemuck::bytes_of(&(self.reset as u32)));
+        buf.extend_from_slice(&[0; 8]);
+        buf
+    }
+}
+
+fn create_target(device: &Device, dim: UVec2, format: TextureFormat) -> TextureView {
+    device
+        .create_texture(&TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: dim.x,
+                height: dim.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        })
+        .create_view(&TextureViewDescriptor::default())
+}
+
+fn create_resolve_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: PipelineCompilationOptions::default(),
+            buffers: &[],
+        },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "fragment",
+            compilation_options: PipelineCompilationOptions::default(),
+            targets: &[Some(format.into()), Some(HDR_FORMAT.into())],
+        }),
+        primitive: PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+/// Screen sized textures, recreated when the target is resized.
+struct TaaTargets {
+    scene_view: TextureView,
+    motion_view: TextureView,
+    // Written in turns, one frame reading what the previous one wrote.
+    history_views: [TextureView; 2],
+    // Reading the history at the same index.
+    bind_groups: [BindGroup; 2],
+}
+
+/// Renders the scene with a projection jittered over a Halton sequence, along with
+/// motion vectors, and accumulates the frames into a history reprojected with them.
+/// The result is written to the target following the scene, the bloom or the color
+/// target passed to [`crate::WgpuRenderer::draw`].
+pub(crate) struct Taa {
+    config: TaaConfig,
+    uniform: Buffer,
+    sampler: Sampler,
+    layout: BindGroupLayout,
+    pipeline_layout: PipelineLayout,
+    shader_module: ShaderModule,
+    pipeline: RenderPipeline,
+    targets: TaaTargets,
+    jitter_index: u32,
+    // Index of the history written by the last frame.
+    history: usize,
+    reset: bool,
+}
+
+impl Taa {
+    pub fn new(device: &Device, format: TextureFormat, dim: UVec2, config: TaaConfig) -> Self {
+        let uniform = device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: 80,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: None,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let texture_entry = |binding, filterable| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture_entry(1, false),
+                texture_entry(2, false),
+                texture_entry(3, true),
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            ..Default::default()
+        });
+        let shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("taa.wgsl"))),
+        });
+        let pipeline = create_resolve_pipeline(device, &pipeline_layout, &shader_module, format);
+
+        let targets = Self::create_targets(device, &layout, &uniform, &sampler, dim);
+
+        Self {
+            config,
+            uniform,
+            sampler,
+            layout,
+            pipeline_layout,
+            shader_module,
+            pipeline,
+            targets,
+            jitter_index: 0,
+            history: 0,
+            reset: true,
+        }
+    }
+
+    fn create_targets(
+        device: &Device,
+        layout: &BindGroupLayout,
+        uniform: &Buffer,
+        sampler: &Sampler,
+        dim: UVec2,
+    ) -> TaaTargets {
+        let dim = dim.max(UVec2::ONE);
+        let scene_view = create_target(device, dim, HDR_FORMAT);
+        let motion_view = create_target(device, dim, MOTION_FORMAT);
+        let history_views = [(); 2].map(|_| create_target(device, dim, HDR_FORMAT));
+
+        let bind_groups = [0, 1].map(|i| {
+            device.create_bind_group(&BindGroupDescriptor {
+                label: None,
+                layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: uniform.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::TextureView(&scene_view),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: BindingResource::TextureView(&motion_view),
+                    },
+                    BindGroupEntry {
+                        binding: 3,
+                        resource: BindingResource::TextureView(&history_views[i]),
+                    },
+                    BindGroupEntry {
+                        binding: 4,
+                        resource: BindingResource::Sampler(sampler),
+                    },
+                ],
+            })
+        });
+
+        TaaTargets {
+            scene_view,
+            motion_view,
+            history_views,
+            bind_groups,
+        }
+    }
+
+    pub fn resize(&mut self, device: &Device, dim: UVec2) {
+        self.targets =
+            Self::create_targets(device, &self.layout, &self.uniform, &self.sampler, dim);
+        self.reset = true;
+    }
+
+    /// The color target of the scene passes.
+    #[inline]
+    pub fn scene_view(&self) -> &TextureView {
+        &self.targets.scene_view
+    }
+
+    /// The second color target of the scene passes.
+    #[inline]
+    pub fn motion_view(&self) -> &TextureView {
+        &self.targets.motion_view
+    }
+
+    #[inline]
+    pub fn config(&self) -> &TaaConfig {
+        &self.config
+    }
+
+    #[inline]
+    pub fn set_config(&mut self, config: TaaConfig) {
+        self.config = config;
+    }
+
+    /// For a new format of the target following the scene.
+    pub fn rebuild_pipeline(&mut self, device: &Device, format: TextureFormat) {
+        self.pipeline =
+            create_resolve_pipeline(device, &self.pipeline_layout, &self.shader_module, format);
+    }
+
+    /// Advances the Halton sequence, returning the offset of the projection in NDC for a
+    /// target of size `dim`, within half a pixel.
+    pub fn next_jitter(&mut self, dim: UVec2) -> Vec2 {
+        self.jitter_index = self.jitter_index % JITTER_SAMPLES + 1;
+        let sample = Vec2::new(halton(self.jitter_index, 2), halton(self.jitter_index, 3));
+        (sample - 0.5) * 2. / dim.max(UVec2::ONE).as_vec2()
+    }
+
+    /// Discards the history with the next frame, for camera cuts.
+    #[inline]
+    pub fn reset(&mut self) {
+        self.reset = true;
+    }
+
+    /// `reprojection` goes from the unjittered NDC of this frame to the clip space of the
+    /// previous one. The history is discarded unless `enabled`, for the debug view modes.
+    pub fn update(&self, queue: &Queue, reprojection: Mat4, enabled: bool) {
+        let uniform = TaaUniform {
+            reprojection,
+            config: &self.config,
+            reset: self.reset || !enabled,
+        };
+        queue.write_buffer(&self.uniform, 0, &uniform.as_raw());
+    }
+
+    /// Resolves the scene target into `output` and the next history.
+    pub fn encode(
+        &mut self,
+        command_encoder: &mut CommandEncoder,
+        output: &TextureView,
+        gpu_timer: Option<&mut GpuTimer>,
+    ) {
+        let read = self.history;
+        let write = 1 - read;
+        let attachment = |view| {
+            Some(RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Load,
+                    store: StoreOp::Store,
+                },
+            })
+        };
+
+        let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+            label: None,
+            color_attachments: &[
+                attachment(output),
+                attachment(&self.targets.history_views[write]),
+            ],
+            timestamp_writes: gpu_timer.and_then(|timer| timer.pass_writes("taa")),
+            ..Default::default()
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.targets.bind_groups[read], &[]);
+        pass.draw(0..3, 0..1);
+
+        self.history = write;
+        self.reset = false;
+    }
+}
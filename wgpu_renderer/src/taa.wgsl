@@ -0,0 +1,77 @@
+// Temporal anti-aliasing: blends the jittered scene into the history reprojected with the
+// motion vectors, clamped to the neighbourhood of every pixel to reject stale history.
+
+struct Taa {
+    // From the NDC of the current frame to the clip space of the previous one, for the
+    // background which has no motion vectors.
+    reprojection: mat4x4f,
+    blend_factor: f32,
+    // Set after camera cuts and for the debug view modes, which are copied as they are.
+    reset: u32,
+}
+
+struct ResolveOutput {
+    @location(0) color: vec4f,
+    @location(1) history: vec4f,
+}
+
+@group(0) @binding(0) var<uniform> taa: Taa;
+@group(0) @binding(1) var scene_map: texture_2d<f32>;
+@group(0) @binding(2) var motion_map: texture_2d<f32>;
+@group(0) @binding(3) var history_map: texture_2d<f32>;
+@group(0) @binding(4) var history_sampler: sampler;
+
+// A triangle covering the whole target.
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> @builtin(position) vec4f {
+    let uv = vec2f(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    return vec4f(uv * 2. - 1., 0., 1.);
+}
+
+fn load_scene(texel: vec2i) -> vec3f {
+    let size = vec2i(textureDimensions(scene_map));
+    return textureLoad(scene_map, clamp(texel, vec2i(0), size - 1), 0).rgb;
+}
+
+// Where the pixel was in the previous frame, in UV units.
+fn history_uv(texel: vec2i, uv: vec2f) -> vec2f {
+    let motion = textureLoad(motion_map, texel, 0);
+    if motion.a != 0. {
+        return uv - motion.xy;
+    }
+
+    let ndc = vec4f(uv.x * 2. - 1., 1. - uv.y * 2., 1., 1.);
+    let previous = taa.reprojection * ndc;
+    return previous.xy / previous.w * vec2f(0.5, -0.5) + 0.5;
+}
+
+@fragment
+fn fragment(@builtin(position) position_fs: vec4f) -> ResolveOutput {
+    let texel = vec2i(position_fs.xy);
+    let uv = position_fs.xy / vec2f(textureDimensions(scene_map));
+    let current = textureLoad(scene_map, texel, 0);
+
+    var output: ResolveOutput;
+    output.color = current;
+    output.history = current;
+    let previous_uv = history_uv(texel, uv);
+    if taa.reset != 0u || any(previous_uv < vec2f(0.)) || any(previous_uv > vec2f(1.)) {
+        return output;
+    }
+
+    var low = current.rgb;
+    var high = current.rgb;
+    for (var y = -1; y <= 1; y += 1) {
+        for (var x = -1; x <= 1; x += 1) {
+            let neighbour = load_scene(texel + vec2i(x, y));
+            low = min(low, neighbour);
+            high = max(high, neighbour);
+        }
+    }
+    let history = textureSampleLevel(history_map, history_sampler, previous_uv, 0.).rgb;
+    let color = mix(clamp(history, low, high), current.rgb, taa.blend_factor);
+
+    output.color = vec4f(color, current.a);
+    output.history = output.color;
+    return output;
+}
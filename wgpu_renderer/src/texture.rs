@@ -0,0 +1,524 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::{self, ErrorKind},
+    path::Path,
+    sync::Arc,
+};
+
+use glam::UVec2;
+use wgpu::{util::*, *};
+
+/// How the texels of an 8 bit texture are read by shaders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ColorSpace {
+    /// Converted to linear when sampled, for colors like the base color or emission.
+    #[default]
+    Srgb,
+    /// Read as they are, for data like metallic and roughness.
+    Linear,
+}
+
+impl ColorSpace {
+    fn rgba8_format(self) -> TextureFormat {
+        match self {
+            ColorSpace::Srgb => TextureFormat::Rgba8UnormSrgb,
+            ColorSpace::Linear => TextureFormat::Rgba8Unorm,
+        }
+    }
+}
+
+/// Decoded pixels of a 2D texture.
+#[derive(Debug, Clone)]
+pub struct TextureImage {
+    pub dim: UVec2,
+    pub format: TextureFormat,
+    /// Every mip level from the largest, each with tightly packed rows from top to
+    /// bottom.
+    pub data: Vec<u8>,
+    /// Mip levels in `data`. With a single one, the others are generated on the GPU if
+    /// the format can be rendered to.
+    pub mip_level_count: u32,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Expands grayscale, grayscale with alpha, RGB or RGBA pixels to RGBA.
+pub(crate) fn expand_to_rgba(pixels: &[u8], channels: usize) -> Vec<u8> {
+    match channels {
+        4 => pixels.to_vec(),
+        3 => pixels
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        2 => pixels
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        _ => pixels.iter().flat_map(|&l| [l, l, l, 255]).collect(),
+    }
+}
+
+impl TextureImage {
+    pub fn from_rgba8(dim: UVec2, pixels: Vec<u8>, color_space: ColorSpace) -> Self {
+        Self {
+            dim,
+            format: color_space.rgba8_format(),
+            data: pixels,
+            mip_level_count: 1,
+        }
+    }
+
+    /// Decodes PNG, JPEG or KTX2 files depending on the extension. The color space of
+    /// KTX2 files is given by their format instead.
+    pub fn load(path: impl AsRef<Path>, color_space: ColorSpace) -> io::Result<Self> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        let bytes = std::fs::read(path)?;
+        match extension.as_deref() {
+            Some("png") => Self::decode_png(&bytes, color_space),
+            Some("jpg" | "jpeg") => Self::decode_jpeg(&bytes, color_space),
+            Some("ktx2") => Self::decode_ktx2(&bytes),
+            _ => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "Only PNG, JPEG and KTX2 textures are supported",
+            )),
+        }
+    }
+
+    /// 16 bit channels and palettes are converted to 8 bit RGBA.
+    pub fn decode_png(bytes: &[u8], color_space: ColorSpace) -> io::Result<Self> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(io::Error::other)?;
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).map_err(io::Error::other)?;
+        pixels.truncate(info.buffer_size());
+
+        let dim = UVec2::new(info.width, info.height);
+        let pixels = expand_to_rgba(&pixels, info.color_type.samples());
+        Ok(Self::from_rgba8(dim, pixels, color_space))
+    }
+
+    /// 16 bit grayscale is converted to 8 bit, CMYK isn't supported.
+    pub fn decode_jpeg(bytes: &[u8], color_space: ColorSpace) -> io::Result<Self> {
+        let mut decoder = jpeg_decoder::Decoder::new(bytes);
+        let pixels = decoder.decode().map_err(io::Error::other)?;
+        let info = decoder
+            .info()
+            .ok_or_else(|| invalid_data("Missing JPEG frame"))?;
+
+        let pixels = match info.pixel_format {
+            jpeg_decoder::PixelFormat::L8 => expand_to_rgba(&pixels, 1),
+            jpeg_decoder::PixelFormat::L16 => {
+                let high_bytes = pixels
+                    .chunks_exact(2)
+                    .map(|l| (u16::from_ne_bytes([l[0], l[1]]) >> 8) as u8)
+                    .collect::<Vec<_>>();
+                expand_to_rgba(&high_bytes, 1)
+            }
+            jpeg_decoder::PixelFormat::RGB24 => expand_to_rgba(&pixels, 3),
+            jpeg_decoder::PixelFormat::CMYK32 => {
+                return Err(invalid_data("CMYK JPEG files aren't supported"))
+            }
+        };
+        let dim = UVec2::new(info.width as u32, info.height as u32);
+        Ok(Self::from_rgba8(dim, pixels, color_space))
+    }
+
+    /// Only 2D textures with 8 bit RGBA or 16 bit float RGBA texels and no
+    /// supercompression are supported, with any number of mips.
+    pub fn decode_ktx2(bytes: &[u8]) -> io::Result<Self> {
+        let reader = ktx2::Reader::new(bytes).map_err(io::Error::other)?;
+        let header = reader.header();
+        let (format, texel_size) = match header.format {
+            Some(ktx2::Format::R8G8B8A8_UNORM) => (TextureFormat::Rgba8Unorm, 4),
+            Some(ktx2::Format::R8G8B8A8_SRGB) => (TextureFormat::Rgba8UnormSrgb, 4),
+            Some(ktx2::Format::R16G16B16A16_SFLOAT) => (TextureFormat::Rgba16Float, 8),
+            _ => return Err(invalid_data("Unsupported KTX2 format")),
+        };
+        if header.supercompression_scheme.is_some() {
+            return Err(invalid_data("Supercompressed KTX2 files aren't supported"));
+        }
+        if header.pixel_depth > 1 || header.layer_count > 1 || header.face_count > 1 {
+            return Err(invalid_data("Only 2D KTX2 textures are supported"));
+        }
+
+        let dim = UVec2::new(header.pixel_width, header.pixel_height.max(1));
+        if header.level_count > dim.max_element().ilog2() + 1 {
+            return Err(invalid_data("More KTX2 levels than a full mip chain"));
+        }
+        let mut data = Vec::new();
+        for (mip, level) in reader.levels().enumerate() {
+            let mip_dim = (dim >> mip as u32).max(UVec2::ONE);
+            let size = mip_dim
+                .x
+                .checked_mul(mip_dim.y)
+                .and_then(|texels| texels.checked_mul(texel_size))
+                .ok_or_else(|| invalid_data("KTX2 level too large"))?;
+            if level.len() as u64 != size as u64 {
+                return Err(invalid_data("Mismatched KTX2 level size"));
+            }
+            data.extend_from_slice(level);
+        }
+
+        Ok(Self {
+            dim,
+            format,
+            data,
+            mip_level_count: header.level_count.max(1),
+        })
+    }
+
+    /// Mips of the uploaded texture, a full chain if they are generated.
+    fn uploaded_mip_level_count(&self) -> u32 {
+        match self.mip_level_count == 1 && MipmapGenerator::supports(self.format) {
+            true => self.dim.max_element().max(1).ilog2() + 1,
+            false => self.mip_level_count,
+        }
+    }
+
+    /// Of the uploaded texture, which can be rendered to if its mips are generated.
+    pub fn descriptor(&self) -> TextureDescriptor<'static> {
+        let mut usage = TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST;
+        let mip_level_count = self.uploaded_mip_level_count();
+        if mip_level_count > self.mip_level_count {
+            usage |= TextureUsages::RENDER_ATTACHMENT;
+        }
+
+        TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: self.dim.x,
+                height: self.dim.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: self.format,
+            usage,
+            view_formats: &[],
+        }
+    }
+}
+
+/// Sampling of a texture, used to look samplers up in the cache of the
+/// [`crate::assets::AssetRegistry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SamplerOptions {
+    pub address_mode_u: AddressMode,
+    pub address_mode_v: AddressMode,
+    pub mag_filter: FilterMode,
+    pub min_filter: FilterMode,
+    pub mipmap_filter: FilterMode,
+    /// Between 1 and 16, values above 1 need every filter to be linear.
+    pub anisotropy_clamp: u16,
+}
+
+impl Default for SamplerOptions {
+    fn default() -> Self {
+        Self {
+            address_mode_u: AddressMode::Repeat,
+            address_mode_v: AddressMode::Repeat,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Linear,
+            anisotropy_clamp: 1,
+        }
+    }
+}
+
+impl SamplerOptions {
+    pub fn descriptor(&self) -> SamplerDescriptor<'static> {
+        SamplerDescriptor {
+            label: None,
+            address_mode_u: self.address_mode_u,
+            address_mode_v: self.address_mode_v,
+            mag_filter: self.mag_filter,
+            min_filter: self.min_filter,
+            mipmap_filter: self.mipmap_filter,
+            anisotropy_clamp: self.anisotropy_clamp,
+            ..Default::default()
+        }
+    }
+}
+
+/// Samplers created so far, as there are only a few distinct ones for any number of
+/// textures.
+#[derive(Default)]
+pub(crate) struct SamplerCache {
+    samplers: HashMap<SamplerOptions, Arc<Sampler>>,
+}
+
+impl SamplerCache {
+    pub fn get(&mut self, device: &Device, options: SamplerOptions) -> Arc<Sampler> {
+        self.samplers
+            .entry(options)
+            .or_insert_with(|| Arc::new(device.create_sampler(&options.descriptor())))
+            .clone()
+    }
+}
+
+/// Renders every mip of a texture from the one above it with a bilinear filter, which
+/// averages the 2x2 texels under every pixel.
+pub(crate) struct MipmapGenerator {
+    layout: BindGroupLayout,
+    pipeline_layout: PipelineLayout,
+    shader_module: ShaderModule,
+    sampler: Sampler,
+    pipelines: HashMap<TextureFormat, RenderPipeline>,
+}
+
+impl MipmapGenerator {
+    pub fn new(device: &Device) -> Self {
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            ..Default::default()
+        });
+        let shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("mipmap.wgsl"))),
+        });
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: None,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self {
+            layout,
+            pipeline_layout,
+            shader_module,
+            sampler,
+            pipelines: HashMap::new(),
+        }
+    }
+
+    /// Whether textures of `format` can be filtered and rendered to on every device.
+    pub fn supports(format: TextureFormat) -> bool {
+        matches!(
+            format,
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb | TextureFormat::Rgba16Float
+        )
+    }
+
+    fn pipeline(&mut self, device: &Device, format: TextureFormat) -> &RenderPipeline {
+        self.pipelines.entry(format).or_insert_with(|| {
+            device.create_render_pipeline(&RenderPipelineDescriptor {
+                label: None,
+                layout: Some(&self.pipeline_layout),
+                vertex: VertexState {
+                    module: &self.shader_module,
+                    entry_point: "vertex",
+                    compilation_options: PipelineCompilationOptions::default(),
+                    buffers: &[],
+                },
+                fragment: Some(FragmentState {
+                    module: &self.shader_module,
+                    entry_point: "fragment",
+                    compilation_options: PipelineCompilationOptions::default(),
+                    targets: &[Some(format.into())],
+                }),
+                primitive: PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: MultisampleState::default(),
+                multiview: None,
+            })
+        })
+    }
+
+    /// Fills every mip of `texture` past the first, which needs to be a supported
+    /// format with the render attachment usage.
+    pub fn generate(&mut self, device: &Device, queue: &Queue, texture: &Texture) {
+        let mip_view = |mip| {
+            texture.create_view(&TextureViewDescriptor {
+                base_mip_level: mip,
+                mip_level_count: Some(1),
+                ..Default::default()
+            })
+        };
+
+        self.pipeline(device, texture.format());
+        let pipeline = &self.pipelines[&texture.format()];
+
+        let mut command_encoder =
+            device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        for mip in 1..texture.mip_level_count() {
+            let bind_group = device.create_bind_group(&BindGroupDescriptor {
+                label: None,
+                layout: &self.layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: BindingResource::TextureView(&mip_view(mip - 1)),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: BindingResource::Sampler(&self.sampler),
+                    },
+                ],
+            });
+            let target = mip_view(mip);
+            let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &target,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Color::TRANSPARENT),
+                        store: StoreOp::Store,
+                    },
+                })],
+                ..Default::default()
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        queue.submit(Some(command_encoder.finish()));
+    }
+}
+
+/// A single white texel, bound in place of missing textures.
+pub(crate) fn create_white_view(device: &Device, queue: &Queue) -> TextureView {
+    device
+        .create_texture_with_data(
+            queue,
+            &TextureDescriptor {
+                label: None,
+                size: Extent3d::default(),
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Rgba8Unorm,
+                usage: TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            TextureDataOrder::LayerMajor,
+            &[u8::MAX; 4],
+        )
+        .create_view(&TextureViewDescriptor::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ktx2_file(dim: UVec2, level_count: u32, levels: &[&[u8]]) -> Vec<u8> {
+        const HEADER_SIZE: usize = 80;
+        const R8G8B8A8_UNORM: u32 = 37;
+        let index_end = HEADER_SIZE + 24 * levels.len();
+        // An empty data format descriptor, only its total size.
+        let dfd = [4, 0, 0, 0];
+
+        let mut bytes = vec![
+            0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n',
+        ];
+        for field in [R8G8B8A8_UNORM, 1, dim.x, dim.y, 0, 0, 1, level_count, 0] {
+            bytes.extend(field.to_le_bytes());
+        }
+        for field in [index_end as u32, dfd.len() as u32, 0, 0] {
+            bytes.extend(field.to_le_bytes());
+        }
+        bytes.extend([0; 16]);
+        let mut offset = index_end + dfd.len();
+        for level in levels {
+            for field in [offset, level.len(), level.len()] {
+                bytes.extend((field as u64).to_le_bytes());
+            }
+            offset += level.len();
+        }
+        bytes.extend(dfd);
+        bytes.extend(levels.concat());
+        bytes
+    }
+
+    #[test]
+    fn decode_png() {
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, 2, 1);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&[255, 0, 0, 0, 0, 255])
+            .unwrap();
+
+        let image = TextureImage::decode_png(&bytes, ColorSpace::Linear).unwrap();
+        assert_eq!(image.dim, UVec2::new(2, 1));
+        assert_eq!(image.format, TextureFormat::Rgba8Unorm);
+        assert_eq!(image.data, [255, 0, 0, 255, 0, 0, 255, 255]);
+        assert_eq!(image.mip_level_count, 1);
+    }
+
+    #[test]
+    fn decode_jpeg() {
+        // 8x8 grayscale baseline JPEG, with a single DC coefficient of 72 quantized by 8.
+        let mut bytes = vec![0xFF, 0xD8];
+        bytes.extend([0xFF, 0xDB, 0, 67, 0, 8]);
+        bytes.extend([1; 63]);
+        bytes.extend([0xFF, 0xC0, 0, 11, 8, 0, 8, 0, 8, 1, 1, 0x11, 0]);
+        for (class, symbol) in [(0x00, 7), (0x10, 0)] {
+            bytes.extend([0xFF, 0xC4, 0, 20, class, 1]);
+            bytes.extend([0; 15]);
+            bytes.push(symbol);
+        }
+        bytes.extend([0xFF, 0xDA, 0, 8, 1, 1, 0, 0, 63, 0]);
+        bytes.extend([0x48, 0x7F, 0xFF, 0xD9]);
+
+        let image = TextureImage::decode_jpeg(&bytes, ColorSpace::Srgb).unwrap();
+        assert_eq!(image.dim, UVec2::splat(8));
+        assert_eq!(image.format, TextureFormat::Rgba8UnormSrgb);
+        assert_eq!(image.data, [200, 200, 200, 255].repeat(64));
+    }
+
+    #[test]
+    fn decode_ktx2() {
+        let mips: [&[u8]; 3] = [&[1; 64], &[2; 16], &[3; 4]];
+        let image = TextureImage::decode_ktx2(&ktx2_file(UVec2::new(4, 4), 3, &mips)).unwrap();
+        assert_eq!(image.dim, UVec2::new(4, 4));
+        assert_eq!(image.format, TextureFormat::Rgba8Unorm);
+        assert_eq!(image.data, mips.concat());
+        assert_eq!(image.mip_level_count, 3);
+    }
+
+    #[test]
+    fn decode_ktx2_rejects_invalid_levels() {
+        let mismatched = ktx2_file(UVec2::new(2, 2), 1, &[&[0; 4]]);
+        assert!(TextureImage::decode_ktx2(&mismatched).is_err());
+        let past_chain = ktx2_file(UVec2::new(1, 1), 2, &[&[0; 4], &[0; 4]]);
+        assert!(TextureImage::decode_ktx2(&past_chain).is_err());
+        let overflowing = ktx2_file(UVec2::new(u32::MAX, 2), 1, &[&[0; 4]]);
+        let error = TextureImage::decode_ktx2(&overflowing).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,406 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use glam::UVec2;
+use wgpu::*;
+
+use crate::{render::TransparencyMode, stats::GpuTimer, taa};
+
+const ACCUM_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+const REVEALAGE_FORMAT: TextureFormat = TextureFormat::R8Unorm;
+
+fn create_target(device: &Device, dim: UVec2, format: TextureFormat) -> TextureView {
+    device
+        .create_texture(&TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: dim.x,
+                height: dim.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        })
+        .create_view(&TextureViewDescriptor::default())
+}
+
+/// The pipeline drawing transparent meshes with the scene pipeline layout, into the
+/// scene target for [`TransparencyMode::Sorted`] or the accumulation targets for
+/// [`TransparencyMode::WeightedBlended`]. Neither writes depth.
+#[allow(clippy::too_many_arguments)]
+fn create_transparent_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    vertex_buffers: &[VertexBufferLayout],
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+    motion_vectors: bool,
+    shader_defs: &HashMap<String, f64>,
+    mode: TransparencyMode,
+) -> RenderPipeline {
+    let compilation_options = PipelineCompilationOptions {
+        constants: shader_defs,
+        ..Default::default()
+    };
+    let (entry_point, targets) = match mode {
+        TransparencyMode::Sorted => (
+            "fragment_blend",
+            vec![
+                Some(ColorTargetState {
+                    format,
+                    blend: Some(BlendState::ALPHA_BLENDING),
+                    write_mask: ColorWrites::ALL,
+                }),
+                Some(taa::motion_target(false)),
+            ],
+        ),
+        TransparencyMode::WeightedBlended => (
+            "fragment_oit",
+            vec![
+                Some(ColorTargetState {
+                    format: ACCUM_FORMAT,
+                    blend: Some(BlendState {
+                        color: BlendComponent {
+                            src_factor: BlendFactor::One,
+                            dst_factor: BlendFactor::One,
+                            operation: BlendOperation::Add,
+                        },
+                        alpha: BlendComponent {
+                            src_factor: BlendFactor::One,
+                            dst_factor: BlendFactor::One,
+                            operation: BlendOperation::Add,
+                        },
+                    }),
+                    write_mask: ColorWrites::ALL,
+                }),
+                Some(ColorTargetState {
+                    format: REVEALAGE_FORMAT,
+                    blend: Some(BlendState {
+                        color: BlendComponent {
+                            src_factor: BlendFactor::Zero,
+                            dst_factor: BlendFactor::OneMinusSrc,
+                            operation: BlendOperation::Add,
+                        },
+                        alpha: BlendComponent::REPLACE,
+                    }),
+                    write_mask: ColorWrites::ALL,
+                }),
+            ],
+        ),
+    };
+    // The accumulation targets are a pass of their own, without motion vectors.
+    let target_count = match mode {
+        TransparencyMode::Sorted => 1 + motion_vectors as usize,
+        TransparencyMode::WeightedBlended => 2,
+    };
+
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: compilation_options.clone(),
+            buffers: vertex_buffers,
+        },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point,
+            compilation_options,
+            targets: &targets[..target_count],
+        }),
+        primitive: PrimitiveState::default(),
+        depth_stencil: Some(DepthStencilState {
+            format: TextureFormat::Depth24Plus,
+            depth_write_enabled: false,
+            depth_compare: CompareFunction::LessEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
+        multisample: MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+fn create_composite_pipeline(
+    device: &Device,
+    layout: &PipelineLayout,
+    shader_module: &ShaderModule,
+    format: TextureFormat,
+) -> RenderPipeline {
+    device.create_render_pipeline(&RenderPipelineDescriptor {
+        label: None,
+        layout: Some(layout),
+        vertex: VertexState {
+            module: shader_module,
+            entry_point: "vertex",
+            compilation_options: PipelineCompilationOptions::default(),
+            buffers: &[],
+        },
+        fragment: Some(FragmentState {
+            module: shader_module,
+            entry_point: "fragment",
+            compilation_options: PipelineCompilationOptions::default(),
+            targets: &[Some(ColorTargetState {
+                format,
+                blend: Some(BlendState::ALPHA_BLENDING),
+                write_mask: ColorWrites::ALL,
+            })],
+        }),
+        primitive: PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+/// Screen sized textures of the weighted blended transparency, recreated when the
+/// target is resized.
+struct OitTargets {
+    accum_view: TextureView,
+    revealage_view: TextureView,
+    bind_group: BindGroup,
+}
+
+impl OitTargets {
+    fn new(device: &Device, layout: &BindGroupLayout, dim: UVec2) -> Self {
+        let dim = dim.max(UVec2::ONE);
+        let accum_view = create_target(device, dim, ACCUM_FORMAT);
+        let revealage_view = create_target(device, dim, REVEALAGE_FORMAT);
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&accum_view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&revealage_view),
+                },
+            ],
+        });
+
+        Self {
+            accum_view,
+            revealage_view,
+            bind_group,
+        }
+    }
+}
+
+/// Resolves the accumulation targets of the weighted blended transparency over the
+/// scene target.
+struct Oit {
+    layout: BindGroupLayout,
+    pipeline_layout: PipelineLayout,
+    shader_module: ShaderModule,
+    composite_pipeline: RenderPipeline,
+    targets: OitTargets,
+}
+
+impl Oit {
+    fn new(device: &Device, format: TextureFormat, dim: UVec2) -> Self {
+        let texture_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: false },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[texture_entry(0), texture_entry(1)],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            ..Default::default()
+        });
+        let shader_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("transparency.wgsl"))),
+        });
+        let composite_pipeline =
+            create_composite_pipeline(device, &pipeline_layout, &shader_module, format);
+        let targets = OitTargets::new(device, &layout, dim);
+
+        Self {
+            layout,
+            pipeline_layout,
+            shader_module,
+            composite_pipeline,
+            targets,
+        }
+    }
+}
+
+/// Draws the meshes with [`crate::render::AlphaMode::Blend`] after the opaque ones,
+/// either sorted in the scene pass or accumulated in a pass of their own and composited
+/// over the scene target.
+pub(crate) struct Transparency {
+    mode: TransparencyMode,
+    pipeline: RenderPipeline,
+    oit: Option<Oit>,
+}
+
+impl Transparency {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &Device,
+        shader_module: &ShaderModule,
+        scene_pipeline_layout: &PipelineLayout,
+        vertex_buffers: &[VertexBufferLayout],
+        format: TextureFormat,
+        motion_vectors: bool,
+        shader_defs: &HashMap<String, f64>,
+        mode: TransparencyMode,
+        dim: UVec2,
+    ) -> Self {
+        let pipeline = create_transparent_pipeline(
+            device,
+            scene_pipeline_layout,
+            vertex_buffers,
+            shader_module,
+            format,
+            motion_vectors,
+            shader_defs,
+            mode,
+        );
+        let oit =
+            (mode == TransparencyMode::WeightedBlended).then(|| Oit::new(device, format, dim));
+
+        Self {
+            mode,
+            pipeline,
+            oit,
+        }
+    }
+
+    #[inline]
+    pub fn resize(&mut self, device: &Device, dim: UVec2) {
+        if let Some(oit) = &mut self.oit {
+            oit.targets = OitTargets::new(device, &oit.layout, dim);
+        }
+    }
+
+    /// For a new target format, shader defs or motion vectors.
+    #[allow(clippy::too_many_arguments)]
+    pub fn rebuild_pipelines(
+        &mut self,
+        device: &Device,
+        shader_module: &ShaderModule,
+        scene_pipeline_layout: &PipelineLayout,
+        vertex_buffers: &[VertexBufferLayout],
+        format: TextureFormat,
+        motion_vectors: bool,
+        shader_defs: &HashMap<String, f64>,
+    ) {
+        self.pipeline = create_transparent_pipeline(
+            device,
+            scene_pipeline_layout,
+            vertex_buffers,
+            shader_module,
+            format,
+            motion_vectors,
+            shader_defs,
+            self.mode,
+        );
+        if let Some(oit) = &mut self.oit {
+            oit.composite_pipeline =
+                create_composite_pipeline(device, &oit.pipeline_layout, &oit.shader_module, format);
+        }
+    }
+
+    #[inline]
+    pub fn mode(&self) -> TransparencyMode {
+        self.mode
+    }
+
+    /// Draws the meshes sorted in the scene pass.
+    #[inline]
+    pub fn pipeline(&self) -> &RenderPipeline {
+        &self.pipeline
+    }
+
+    /// Starts the pass accumulating the transparent meshes, tested against the depth of
+    /// the opaque ones. Followed by [`Transparency::composite`] once they're drawn, only
+    /// for [`TransparencyMode::WeightedBlended`].
+    pub fn begin_accumulation<'a>(
+        &'a self,
+        command_encoder: &'a mut CommandEncoder,
+        depth_target: &'a TextureView,
+        gpu_timer: Option<&'a mut GpuTimer>,
+    ) -> RenderPass<'a> {
+        let oit = self
+            .oit
+            .as_ref()
+            .expect("Accumulating without weighted blended transparency.");
+        let attachment = |view, clear| {
+            Some(RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Clear(clear),
+                    store: StoreOp::Store,
+                },
+            })
+        };
+
+        let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+            label: None,
+            color_attachments: &[
+                attachment(&oit.targets.accum_view, Color::TRANSPARENT),
+                attachment(&oit.targets.revealage_view, Color::WHITE),
+            ],
+            depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                view: depth_target,
+                depth_ops: Some(Operations {
+                    load: LoadOp::Load,
+                    store: StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: gpu_timer.and_then(|timer| timer.pass_writes("oit accumulate")),
+            ..Default::default()
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass
+    }
+
+    /// Blends the accumulated transparent meshes over `scene_target`.
+    pub fn composite(
+        &self,
+        command_encoder: &mut CommandEncoder,
+        scene_target: &TextureView,
+        gpu_timer: Option<&mut GpuTimer>,
+    ) {
+        let Some(oit) = &self.oit else {
+            return;
+        };
+
+        let mut pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(RenderPassColorAttachment {
+                view: scene_target,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Load,
+                    store: StoreOp::Store,
+                },
+            })],
+            timestamp_writes: gpu_timer.and_then(|timer| timer.pass_writes("oit composite")),
+            ..Default::default()
+        });
+        pass.set_pipeline(&oit.composite_pipeline);
+        pass.set_bind_group(0, &oit.targets.bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
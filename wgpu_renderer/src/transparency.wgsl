@@ -0,0 +1,23 @@
+@group(0) @binding(0) var accum_map: texture_2d<f32>;
+@group(0) @binding(1) var revealage_map: texture_2d<f32>;
+
+// A triangle covering the whole target.
+@vertex
+fn vertex(@builtin(vertex_index) vertex_index: u32) -> @builtin(position) vec4f {
+    let uv = vec2f(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    return vec4f(uv * 2. - 1., 0., 1.);
+}
+
+// Averages the accumulated colors by their weights, blended over the opaque meshes by
+// how much the transparent ones cover them.
+@fragment
+fn fragment(@builtin(position) position: vec4f) -> @location(0) vec4f {
+    let texel = vec2i(position.xy);
+    let revealage = textureLoad(revealage_map, texel, 0).r;
+    if revealage >= 1. {
+        discard;
+    }
+
+    let accum = textureLoad(accum_map, texel, 0);
+    return vec4f(accum.rgb / max(accum.a, 1e-5), 1. - revealage);
+}
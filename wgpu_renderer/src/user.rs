@@ -0,0 +1,152 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use wgpu::*;
+
+use crate::{
+    assets::MeshHandle, render::GpuMesh, vertex_buffer_layout, RendererConfig, VERTEX_ATTRIBUTES,
+};
+
+/// Group of the first user bind group in the scene pipeline, following the scene, mesh
+/// and material groups.
+pub(crate) const FIRST_USER_GROUP: u32 = 3;
+
+/// Bind group layouts and vertex attributes of [`RendererConfig`] added to the scene
+/// pipeline, along with the bind groups set for them.
+pub(crate) struct UserBindings {
+    layouts: Vec<BindGroupLayout>,
+    bind_groups: Vec<Option<BindGroup>>,
+    // Replace the bind group at the same index for a single mesh.
+    mesh_bind_groups: HashMap<(MeshHandle, usize), BindGroup>,
+    attributes: Vec<VertexAttribute>,
+    stride: BufferAddress,
+    // Meshes already warned about as they couldn't be drawn, to only log it once.
+    skipped: Mutex<HashSet<MeshHandle>>,
+}
+
+impl UserBindings {
+    pub fn new(device: &Device, config: &RendererConfig) -> Self {
+        let layouts = config
+            .user_bind_group_layouts
+            .iter()
+            .map(|entries| {
+                device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                    label: None,
+                    entries,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // After the locations of the vertices, see `GpuMesh::attributes` for the layout.
+        let mut stride: BufferAddress = 0;
+        let attributes = config
+            .user_vertex_attributes
+            .iter()
+            .zip(VERTEX_ATTRIBUTES.len() as u32..)
+            .map(|(&format, shader_location)| {
+                let offset = stride.next_multiple_of(format.size().min(4));
+                stride = offset + format.size();
+                VertexAttribute {
+                    format,
+                    offset,
+                    shader_location,
+                }
+            })
+            .collect();
+
+        Self {
+            bind_groups: layouts.iter().map(|_| None).collect(),
+            layouts,
+            mesh_bind_groups: HashMap::new(),
+            attributes,
+            stride: stride.next_multiple_of(4),
+            skipped: Mutex::new(HashSet::new()),
+        }
+    }
+
+    #[inline]
+    pub fn layouts(&self) -> &[BindGroupLayout] {
+        &self.layouts
+    }
+
+    /// The vertices, followed by the user attributes if there are any.
+    pub fn vertex_buffers(&self) -> Vec<VertexBufferLayout<'_>> {
+        let mut buffers = vec![vertex_buffer_layout()];
+        if !self.attributes.is_empty() {
+            buffers.push(VertexBufferLayout {
+                array_stride: self.stride,
+                step_mode: VertexStepMode::Vertex,
+                attributes: &self.attributes,
+            });
+        }
+        buffers
+    }
+
+    pub fn set_bind_group(&mut self, index: usize, bind_group: Option<BindGroup>) {
+        assert!(index < self.layouts.len(), "No user bind group {}.", index);
+        self.bind_groups[index] = bind_group;
+    }
+
+    pub fn set_mesh_bind_group(
+        &mut self,
+        mesh: MeshHandle,
+        index: usize,
+        bind_group: Option<BindGroup>,
+    ) {
+        assert!(index < self.layouts.len(), "No user bind group {}.", index);
+        match bind_group {
+            Some(bind_group) => self.mesh_bind_groups.insert((mesh, index), bind_group),
+            None => self.mesh_bind_groups.remove(&(mesh, index)),
+        };
+    }
+
+    /// Drops the bind groups of meshes for which `keep` returns false.
+    pub fn retain_meshes(&mut self, mut keep: impl FnMut(MeshHandle) -> bool) {
+        self.mesh_bind_groups.retain(|(mesh, _), _| keep(*mesh));
+        self.skipped.get_mut().unwrap().retain(|mesh| keep(*mesh));
+    }
+
+    fn warn_skipped(&self, handle: MeshHandle, mesh: &GpuMesh, missing: &str) {
+        if self.skipped.lock().unwrap().insert(handle) {
+            log::warn!(
+                "Not drawing mesh {} without its user {}.",
+                mesh.name.as_deref().unwrap_or("unnamed"),
+                missing
+            );
+        }
+    }
+
+    /// Binds the user bind groups and attributes of `mesh`, returning false if one of
+    /// them is missing and the mesh can't be drawn.
+    pub fn bind<'a>(
+        &'a self,
+        pass: &mut RenderPass<'a>,
+        handle: MeshHandle,
+        mesh: &'a GpuMesh,
+    ) -> bool {
+        for (index, bind_group) in self.bind_groups.iter().enumerate() {
+            let Some(bind_group) = self
+                .mesh_bind_groups
+                .get(&(handle, index))
+                .or(bind_group.as_ref())
+            else {
+                self.warn_skipped(handle, mesh, "bind groups");
+                return false;
+            };
+            pass.set_bind_group(FIRST_USER_GROUP + index as u32, bind_group, &[]);
+        }
+
+        if !self.attributes.is_empty() {
+            let size = mesh.vertex_count as BufferAddress * self.stride;
+            let Some(attribute_buf) = mesh.attribute_buf.as_ref().filter(|b| b.size() >= size)
+            else {
+                self.warn_skipped(handle, mesh, "vertex attributes");
+                return false;
+            };
+            pass.set_vertex_buffer(1, attribute_buf.slice(..));
+        }
+        true
+    }
+}
@@ -0,0 +1,110 @@
+// Fragment entry points for the diagnostic view modes, sharing the bindings and vertex
+// layout of the scene shader.
+
+struct Camera {
+    view: mat4x4f,
+    proj: mat4x4f,
+}
+
+struct DirectionalLight {
+    pos: vec3f,
+    first_cascade: u32,
+    dir: vec3f,
+    cascade_count: u32,
+    col: vec3f,
+    filter_mode: u32,
+    filter_samples: u32,
+    filter_param: f32,
+    depth_bias: f32,
+    normal_bias: f32,
+}
+
+struct VertexInput {
+    @builtin(vertex_index) vertex_index: u32,
+    @location(0) position: vec3f,
+    @location(1) normal: vec3f,
+    @location(2) joints: vec4u,
+    @location(3) weights: vec4f,
+    @location(4) uv: vec2f,
+}
+
+struct VertexOutput {
+    @builtin(position) position_cs: vec4f,
+    @location(0) normal_ws: vec3f,
+    @location(1) uv: vec2f,
+    @location(2) view_distance: f32,
+    @location(3) barycentric: vec3f,
+}
+
+@group(0) @binding(0) var<uniform> camera: Camera;
+@group(0) @binding(1) var<storage> dir_lights: array<DirectionalLight>;
+
+// Distance mapped to white in the depth view.
+const MAX_VIEW_DISTANCE: f32 = 50.;
+const AMBIENT: f32 = 0.05;
+const OVERDRAW_STEP: f32 = 0.1;
+// In pixels.
+const WIREFRAME_WIDTH: f32 = 1.;
+
+@vertex
+fn vertex(input: VertexInput) -> VertexOutput {
+    let skin = skin_matrix(input.joints, input.weights);
+    let position_ws = skin * vec4f(input.position, 1.);
+    let position_vs = camera.view * position_ws;
+
+    var output: VertexOutput;
+    output.position_cs = camera.proj * position_vs;
+    output.normal_ws = normalize((skin * vec4f(input.normal, 0.)).xyz);
+    output.uv = input.uv;
+    output.view_distance = length(position_vs.xyz);
+    // Meshes aren't indexed, so every three consecutive vertices form a triangle.
+    let corner = input.vertex_index % 3u;
+    output.barycentric = vec3f(f32(corner == 0u), f32(corner == 1u), f32(corner == 2u));
+    return output;
+}
+
+@fragment
+fn fragment_normals(input: VertexOutput) -> @location(0) vec4f {
+    return vec4f(normalize(input.normal_ws) * 0.5 + 0.5, 1.);
+}
+
+@fragment
+fn fragment_uvs(input: VertexOutput) -> @location(0) vec4f {
+    return vec4f(fract(input.uv), 0., 1.);
+}
+
+@fragment
+fn fragment_depth(input: VertexOutput) -> @location(0) vec4f {
+    return vec4f(vec3f(saturate(input.view_distance / MAX_VIEW_DISTANCE)), 1.);
+}
+
+@fragment
+fn fragment_overdraw(input: VertexOutput) -> @location(0) vec4f {
+    return vec4f(vec3f(OVERDRAW_STEP), 1.);
+}
+
+@fragment
+fn fragment_light(input: VertexOutput) -> @location(0) vec4f {
+    let normal = normalize(input.normal_ws);
+    var color = vec3f(AMBIENT);
+    for (var i_light = 0u; i_light < arrayLength(&dir_lights); i_light += 1u) {
+        let light = &dir_lights[i_light];
+        color += max(dot(normal, -(*light).dir), 0.) * (*light).col;
+    }
+    return vec4f(color, 1.);
+}
+
+// Only drawn with line polygons, which need no edge detection.
+@fragment
+fn fragment_wireframe_lines(input: VertexOutput) -> @location(0) vec4f {
+    return vec4f(1.);
+}
+
+@fragment
+fn fragment_wireframe(input: VertexOutput) -> @location(0) vec4f {
+    let edge = input.barycentric / fwidth(input.barycentric);
+    if min(edge.x, min(edge.y, edge.z)) > WIREFRAME_WIDTH {
+        discard;
+    }
+    return vec4f(1.);
+}